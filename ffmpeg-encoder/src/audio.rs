@@ -6,7 +6,7 @@ use ffmpeg::{
     av_rescale_q,
 };
 
-use crate::ff;
+use crate::{Error, ff};
 
 pub struct AudioOutputStream {
     pub stream: ff::Stream,
@@ -14,6 +14,8 @@ pub struct AudioOutputStream {
     pub tmp_pkt: ff::Packet,
 
     pub frame: ff::Frame,
+    /// Samples `frame` has room for
+    frame_capacity: usize,
 
     samples_count: i64,
 }
@@ -21,25 +23,26 @@ pub struct AudioOutputStream {
 pub fn new_audio_streams(
     format_context: &ff::FormatContext,
     output_format: &ff::OutputFormat,
-) -> AudioOutputStream {
+    bit_rate: u64,
+) -> Result<AudioOutputStream, Error> {
     let codec_id = output_format.audio_codec_id();
-    assert_ne!(
-        codec_id,
-        AVCodecID::AV_CODEC_ID_NONE,
-        "The selected output container does not support audio encoding"
-    );
+    if codec_id == AVCodecID::AV_CODEC_ID_NONE {
+        return Err(Error::Unsupported(
+            "The selected output container does not support audio encoding".into(),
+        ));
+    }
 
-    let codec = output_format.audio_codec();
+    let codec = output_format.audio_codec()?;
 
     let output_format = output_format.as_ptr();
 
-    let tmp_pkt = ff::Packet::new();
+    let tmp_pkt = ff::Packet::new()?;
 
     let stream = format_context
         .new_stream()
-        .expect("Could not allocate stream");
+        .ok_or(Error::Alloc("an audio stream"))?;
 
-    let mut codec_ctx = codec.context();
+    let mut codec_ctx = codec.context()?;
 
     let codec_ptr = codec.as_ptr();
     let codec_ctx_ptr = codec_ctx.as_ptr();
@@ -54,7 +57,7 @@ pub fn new_audio_streams(
                 *(*codec_ptr).sample_fmts
             };
 
-            (*codec_ctx_ptr).bit_rate = 64000;
+            (*codec_ctx_ptr).bit_rate = bit_rate as i64;
             (*codec_ctx_ptr).sample_rate = 44100;
 
             let supported_samplerates = (*codec_ptr).supported_samplerates;
@@ -96,7 +99,7 @@ pub fn new_audio_streams(
             }
         }
 
-        codec_ctx.open();
+        codec_ctx.open()?;
 
         let nb_samples = if codec.capabilities() & AV_CODEC_CAP_VARIABLE_FRAME_SIZE as i32 != 0 {
             10000
@@ -109,29 +112,35 @@ pub fn new_audio_streams(
             codec_ctx.channel_layout(),
             codec_ctx.sample_rate(),
             nb_samples,
-        );
+        )?;
 
-        codec_ctx.copy_parameters_to_stream(&stream);
+        codec_ctx.copy_parameters_to_stream(&stream)?;
 
-        AudioOutputStream {
+        Ok(AudioOutputStream {
             stream,
             codec_ctx,
             tmp_pkt,
             frame,
+            frame_capacity: nb_samples.max(0) as usize,
             samples_count: 0,
-        }
+        })
     }
 }
 
 #[allow(unused)]
 impl AudioOutputStream {
     /// Prepare a 16-bit dummy audio frame.
-    fn next_frame(&mut self, audio_l: &[f32], audio_r: &[f32]) {
-        self.frame.make_writable();
-        let frame_ptr = self.frame.as_ptr();
+    fn next_frame(&mut self, audio_l: &[f32], audio_r: &[f32]) -> Result<(), Error> {
+        let nb_samples = audio_l.len().min(audio_r.len());
+        if nb_samples > self.frame_capacity {
+            return Err(Error::FrameSize {
+                expected: self.frame_capacity,
+                actual: nb_samples,
+            });
+        }
 
-        debug_assert_eq!(audio_l.len(), audio_r.len());
-        let nb_samples = audio_l.len();
+        self.frame.make_writable()?;
+        let frame_ptr = self.frame.as_ptr();
 
         unsafe {
             (*frame_ptr).nb_samples = nb_samples as i32;
@@ -155,6 +164,8 @@ impl AudioOutputStream {
         });
 
         self.samples_count += nb_samples as i64;
+
+        Ok(())
     }
 
     /// Encode one audio frame and send it to the muxer.
@@ -163,8 +174,8 @@ impl AudioOutputStream {
         format_ctx: &ff::FormatContext,
         audio_l: &[f32],
         audio_r: &[f32],
-    ) -> bool {
-        self.next_frame(audio_l, audio_r);
+    ) -> Result<bool, Error> {
+        self.next_frame(audio_l, audio_r)?;
 
         super::write_frame(
             &self.codec_ctx,
@@ -175,7 +186,7 @@ impl AudioOutputStream {
         )
     }
 
-    pub fn write_terminator_frame(&self, format_ctx: &ff::FormatContext) -> bool {
+    pub fn write_terminator_frame(&self, format_ctx: &ff::FormatContext) -> Result<bool, Error> {
         super::write_frame(
             &self.codec_ctx,
             &self.stream,
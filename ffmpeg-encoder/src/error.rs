@@ -0,0 +1,58 @@
+use std::{ffi::CStr, path::PathBuf};
+
+use libc::c_int;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    /// An ffmpeg call failed, `message` is the description ffmpeg gives for `code`
+    #[error("{context}: {message}")]
+    Ffmpeg {
+        context: &'static str,
+        code: i32,
+        message: String,
+    },
+    /// ffmpeg could not allocate the named object
+    #[error("Could not allocate {0}")]
+    Alloc(&'static str),
+    /// The options can't be encoded, eg. the codec is not available or has no alpha support
+    #[error("{0}")]
+    Unsupported(String),
+    /// The output path is not valid UTF-8 or contains a nul byte
+    #[error("Invalid output path: {}", .0.display())]
+    InvalidPath(PathBuf),
+    /// Video frame with a size other than `width * height * 4` bytes, or an audio frame with
+    /// more samples than the encoder takes at once
+    #[error("Frame has {actual} elements, expected {expected}")]
+    FrameSize { expected: usize, actual: usize },
+    /// A frame was sent after [`crate::Frame::Terminator`]
+    #[error("The encoder is already finished")]
+    Closed,
+}
+
+impl Error {
+    pub(crate) fn ffmpeg(context: &'static str, code: c_int) -> Self {
+        let mut buf = [0; 128];
+        let message = unsafe {
+            if ffmpeg::av_strerror(code, buf.as_mut_ptr(), buf.len()) < 0 {
+                format!("error code {code}")
+            } else {
+                CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+            }
+        };
+
+        Self::Ffmpeg {
+            context,
+            code,
+            message,
+        }
+    }
+}
+
+/// Turns a negative ffmpeg return code into an error
+pub(crate) fn check(code: c_int, context: &'static str) -> Result<c_int, Error> {
+    if code < 0 {
+        Err(Error::ffmpeg(context, code))
+    } else {
+        Ok(code)
+    }
+}
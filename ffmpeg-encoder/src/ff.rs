@@ -1,5 +1,5 @@
 use std::{
-    ffi::CStr,
+    ffi::{CStr, CString},
     ptr::{self, NonNull},
 };
 
@@ -8,6 +8,8 @@ use ffmpeg::{
     AVOutputFormat, AVPacket, AVPixelFormat, AVRational, AVSampleFormat, AVStream,
 };
 
+use crate::{Error, error::check};
+
 pub struct FormatContext(NonNull<AVFormatContext>);
 
 impl Drop for FormatContext {
@@ -20,7 +22,7 @@ impl Drop for FormatContext {
 }
 
 impl FormatContext {
-    pub fn new(path: &CStr) -> Self {
+    pub fn new(path: &CStr) -> Result<Self, Error> {
         let mut output_context = ptr::null_mut();
 
         unsafe {
@@ -31,19 +33,22 @@ impl FormatContext {
                 path.as_ptr(),
             );
 
-            Self(
-                NonNull::new(output_context)
-                    .or_else(|| {
-                        ffmpeg::avformat_alloc_output_context2(
-                            &mut output_context,
-                            ptr::null_mut(),
-                            ptr::null(),
-                            c"mpeg".as_ptr(),
-                        );
-                        NonNull::new(output_context)
-                    })
-                    .expect("Unable to create the output context."),
-            )
+            if let Some(output_context) = NonNull::new(output_context) {
+                return Ok(Self(output_context));
+            }
+
+            // Unknown extension, fall back to MPEG
+            let ret = ffmpeg::avformat_alloc_output_context2(
+                &mut output_context,
+                ptr::null_mut(),
+                c"mpeg".as_ptr(),
+                path.as_ptr(),
+            );
+            check(ret, "Could not create the output context")?;
+
+            NonNull::new(output_context)
+                .map(Self)
+                .ok_or(Error::Alloc("the output context"))
         }
     }
 
@@ -52,42 +57,38 @@ impl FormatContext {
         self.0.as_ptr()
     }
 
-    pub fn open(&self, path: impl AsRef<CStr>) {
+    pub fn open(&self, path: impl AsRef<CStr>) -> Result<(), Error> {
         unsafe {
+            // Muxers like image2 write files on their own
+            if (*(*self.0.as_ptr()).oformat).flags & ffmpeg::AVFMT_NOFILE != 0 {
+                return Ok(());
+            }
+
             // open the output file, if needed
-            if ffmpeg::avio_open(
+            let ret = ffmpeg::avio_open(
                 &mut (*self.0.as_ptr()).pb,
                 path.as_ref().as_ptr(),
                 ffmpeg::AVIO_FLAG_WRITE,
-            ) < 0
-            {
-                panic!("Failed to open the output file.");
-            }
+            );
+            check(ret, "Could not open the output file").map(|_| ())
         }
     }
 
     /// Write the compressed frame to the media file.
-    pub fn interleaved_write_frame(&self, packet: &Packet) {
-        unsafe {
-            if ffmpeg::av_interleaved_write_frame(self.0.as_ptr(), packet.as_ptr()) < 0 {
-                panic!("Error while writing output packet",);
-            }
-        }
+    pub fn interleaved_write_frame(&self, packet: &Packet) -> Result<(), Error> {
+        let ret = unsafe { ffmpeg::av_interleaved_write_frame(self.0.as_ptr(), packet.as_ptr()) };
+        check(ret, "Could not write an output packet").map(|_| ())
     }
 
-    pub fn write_trailer(&self) {
-        unsafe {
-            ffmpeg::av_write_trailer(self.0.as_ptr());
-        }
+    pub fn write_trailer(&self) -> Result<(), Error> {
+        let ret = unsafe { ffmpeg::av_write_trailer(self.0.as_ptr()) };
+        check(ret, "Could not write the trailer").map(|_| ())
     }
 
-    pub fn write_header(&self) {
-        unsafe {
-            // Write the stream header, if any.
-            if ffmpeg::avformat_write_header(self.0.as_ptr(), ptr::null_mut()) < 0 {
-                panic!("Failed to open the output file.");
-            }
-        }
+    pub fn write_header(&self) -> Result<(), Error> {
+        // Write the stream header, if any.
+        let ret = unsafe { ffmpeg::avformat_write_header(self.0.as_ptr(), ptr::null_mut()) };
+        check(ret, "Could not write the stream header").map(|_| ())
     }
 
     pub fn output_format(&self) -> OutputFormat {
@@ -147,31 +148,49 @@ impl OutputFormat {
         unsafe { (*self.0).audio_codec }
     }
 
-    pub fn video_codec(&self) -> Codec {
+    pub fn video_codec(&self) -> Result<Codec, Error> {
         let codec_id = self.video_codec_id();
-        let codec = unsafe { ffmpeg::avcodec_find_encoder(codec_id) };
-        assert!(!codec.is_null(), "Could not find video encoder");
-        Codec(codec)
+        Codec::find_encoder(codec_id)
+            .ok_or_else(|| Error::Unsupported(format!("Could not find {codec_id:?} encoder")))
     }
 
-    pub fn audio_codec(&self) -> Codec {
+    pub fn audio_codec(&self) -> Result<Codec, Error> {
         let codec_id = self.audio_codec_id();
-        let codec = unsafe { ffmpeg::avcodec_find_encoder(codec_id) };
-        assert!(!codec.is_null(), "Could not find audio encoder");
-        Codec(codec)
+        Codec::find_encoder(codec_id)
+            .ok_or_else(|| Error::Unsupported(format!("Could not find {codec_id:?} encoder")))
     }
 }
 
 pub struct Codec(*const AVCodec);
 
 impl Codec {
+    pub fn find_encoder(codec_id: AVCodecID) -> Option<Self> {
+        let codec = unsafe { ffmpeg::avcodec_find_encoder(codec_id) };
+        (!codec.is_null()).then_some(Self(codec))
+    }
+
+    pub fn find_encoder_by_name(name: &CStr) -> Option<Self> {
+        let codec = unsafe { ffmpeg::avcodec_find_encoder_by_name(name.as_ptr()) };
+        (!codec.is_null()).then_some(Self(codec))
+    }
+
+    pub fn id(&self) -> AVCodecID {
+        unsafe { (*self.0).id }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.0).name) }
+    }
+
     pub fn as_ptr(&self) -> *const AVCodec {
         self.0
     }
 
-    pub fn context(&self) -> CodecContext {
+    pub fn context(&self) -> Result<CodecContext, Error> {
         let codec_context = unsafe { ffmpeg::avcodec_alloc_context3(self.0) };
-        CodecContext(NonNull::new(codec_context).expect("Could not alloc an encoding context"))
+        NonNull::new(codec_context)
+            .map(CodecContext)
+            .ok_or(Error::Alloc("an encoding context"))
     }
 
     pub fn capabilities(&self) -> i32 {
@@ -192,54 +211,48 @@ impl CodecContext {
         self.0.as_ptr()
     }
 
-    pub fn open_video(&self) {
+    /// Opens the codec with private encoder `options`, like `crf` or `preset`
+    pub fn open_video(&self, options: &[(&CStr, CString)]) -> Result<(), Error> {
         unsafe {
             let mut opt: *mut AVDictionary = ptr::null_mut();
 
-            // The range of the CRF scale is 0–51, where 0 is lossless
-            ffmpeg::av_dict_set(&mut opt, c"crf".as_ptr(), c"0".as_ptr(), 0);
-            ffmpeg::av_dict_set(&mut opt, c"preset".as_ptr(), c"medium".as_ptr(), 0);
-
-            if ffmpeg::avcodec_open2(self.0.as_ptr(), ptr::null_mut(), &mut opt) < 0 {
-                panic!("Could not open video codec.");
+            for (key, value) in options {
+                ffmpeg::av_dict_set(&mut opt, key.as_ptr(), value.as_ptr(), 0);
             }
 
+            let ret = ffmpeg::avcodec_open2(self.0.as_ptr(), ptr::null_mut(), &mut opt);
             ffmpeg::av_dict_free(&mut opt);
+
+            check(ret, "Could not open the video codec").map(|_| ())
         }
     }
 
-    pub fn open(&self) {
-        unsafe {
-            if ffmpeg::avcodec_open2(self.0.as_ptr(), ptr::null_mut(), &mut ptr::null_mut()) < 0 {
-                panic!("Could not open video codec.");
-            }
-        }
+    pub fn open(&self) -> Result<(), Error> {
+        let ret = unsafe {
+            ffmpeg::avcodec_open2(self.0.as_ptr(), ptr::null_mut(), &mut ptr::null_mut())
+        };
+        check(ret, "Could not open the audio codec").map(|_| ())
     }
 
-    pub fn send_frame(&self, frame: Option<&Frame>) {
-        if unsafe {
+    pub fn send_frame(&self, frame: Option<&Frame>) -> Result<(), Error> {
+        let ret = unsafe {
             ffmpeg::avcodec_send_frame(
                 self.0.as_ptr(),
                 frame.map(Frame::as_const_ptr).unwrap_or(ptr::null()),
             )
-        } < 0
-        {
-            panic!("Error sending a frame to the encoder",);
-        }
+        };
+        check(ret, "Could not send a frame to the encoder").map(|_| ())
     }
 
     pub fn receive_packet(&self, packet: &Packet) -> i32 {
         unsafe { ffmpeg::avcodec_receive_packet(self.0.as_ptr(), packet.as_ptr()) }
     }
 
-    pub fn copy_parameters_to_stream(&self, stream: &Stream) {
+    pub fn copy_parameters_to_stream(&self, stream: &Stream) -> Result<(), Error> {
         let codecpar = unsafe { (*stream.as_ptr()).codecpar };
-        unsafe {
-            // copy the stream parameters to the muxer
-            if ffmpeg::avcodec_parameters_from_context(codecpar, self.0.as_ptr()) < 0 {
-                panic!("Could not copy the stream parameters");
-            }
-        }
+        // copy the stream parameters to the muxer
+        let ret = unsafe { ffmpeg::avcodec_parameters_from_context(codecpar, self.0.as_ptr()) };
+        check(ret, "Could not copy the stream parameters").map(|_| ())
     }
 
     pub fn frame_size(&self) -> i32 {
@@ -279,6 +292,13 @@ impl CodecContext {
     }
 }
 
+/// Looks up a pixel format by its ffmpeg name, eg. `yuv420p`
+pub fn pix_fmt_from_name(name: &str) -> Option<AVPixelFormat> {
+    let name = CString::new(name).ok()?;
+    let pix_fmt = unsafe { ffmpeg::av_get_pix_fmt(name.as_ptr()) };
+    (pix_fmt != AVPixelFormat::AV_PIX_FMT_NONE).then_some(pix_fmt)
+}
+
 pub struct Packet(NonNull<AVPacket>);
 
 impl Drop for Packet {
@@ -290,9 +310,11 @@ impl Drop for Packet {
 }
 
 impl Packet {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, Error> {
         let packet = unsafe { ffmpeg::av_packet_alloc() };
-        Self(NonNull::new(packet).expect("Could not allocate AVPacket"))
+        NonNull::new(packet)
+            .map(Self)
+            .ok_or(Error::Alloc("a packet"))
     }
 
     pub fn as_ptr(&self) -> *mut AVPacket {
@@ -329,14 +351,13 @@ impl Frame {
         self.0.as_ptr() as *const AVFrame
     }
 
-    pub fn new_raw() -> Self {
+    pub fn new_raw() -> Result<Self, Error> {
         let frame = unsafe { ffmpeg::av_frame_alloc() };
-        let frame = NonNull::new(frame).expect("Could not allocate frame.");
-        Self(frame)
+        NonNull::new(frame).map(Self).ok_or(Error::Alloc("a frame"))
     }
 
-    pub fn new_video(pix_fmt: AVPixelFormat, width: i32, height: i32) -> Self {
-        let frame = Frame::new_raw();
+    pub fn new_video(pix_fmt: AVPixelFormat, width: i32, height: i32) -> Result<Self, Error> {
+        let frame = Frame::new_raw()?;
 
         unsafe {
             let frame = frame.as_ptr();
@@ -345,12 +366,13 @@ impl Frame {
             (*frame).height = height;
 
             /* allocate the buffers for the frame data */
-            if ffmpeg::av_frame_get_buffer(frame, 0) < 0 {
-                panic!("Could not allocate frame data.");
-            }
+            check(
+                ffmpeg::av_frame_get_buffer(frame, 0),
+                "Could not allocate video frame data",
+            )?;
         }
 
-        frame
+        Ok(frame)
     }
 
     pub fn new_audio(
@@ -358,8 +380,8 @@ impl Frame {
         channel_layout: *const AVChannelLayout,
         sample_rate: i32,
         nb_samples: i32,
-    ) -> Self {
-        let frame = Frame::new_raw();
+    ) -> Result<Self, Error> {
+        let frame = Frame::new_raw()?;
 
         unsafe {
             let frame = frame.as_ptr();
@@ -369,24 +391,28 @@ impl Frame {
             (*frame).nb_samples = nb_samples;
 
             /* allocate the buffers for the frame data */
-            if nb_samples > 0 && ffmpeg::av_frame_get_buffer(frame, 0) < 0 {
-                panic!("Error allocating an audio buffer");
+            if nb_samples > 0 {
+                check(
+                    ffmpeg::av_frame_get_buffer(frame, 0),
+                    "Could not allocate audio frame data",
+                )?;
             }
         }
 
-        frame
+        Ok(frame)
     }
 
-    pub fn make_writable(&self) {
-        unsafe {
-            if ffmpeg::av_frame_make_writable(self.0.as_ptr()) < 0 {
-                panic!("Could not make frame writable");
-            }
-        }
+    pub fn make_writable(&self) -> Result<(), Error> {
+        let ret = unsafe { ffmpeg::av_frame_make_writable(self.0.as_ptr()) };
+        check(ret, "Could not make a frame writable").map(|_| ())
     }
 
-    pub fn image_fill_arrays(&self, frame_bytes: &[u8], pix_fmt: AVPixelFormat) {
-        unsafe {
+    pub fn image_fill_arrays(
+        &self,
+        frame_bytes: &[u8],
+        pix_fmt: AVPixelFormat,
+    ) -> Result<(), Error> {
+        let ret = unsafe {
             ffmpeg::av_image_fill_arrays(
                 (*self.0.as_ptr()).data.as_mut_ptr(),
                 (*self.0.as_ptr()).linesize.as_mut_ptr(),
@@ -395,8 +421,9 @@ impl Frame {
                 self.width(),
                 self.height(),
                 1,
-            );
-        }
+            )
+        };
+        check(ret, "Could not fill the frame").map(|_| ())
     }
 
     pub fn width(&self) -> i32 {
@@ -431,21 +458,20 @@ impl Drop for SwrContext {
 
 #[allow(unused)]
 impl SwrContext {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, Error> {
         let swr = unsafe { ffmpeg::swr_alloc() };
-        let swr = NonNull::new(swr).expect("Could not allocate resampler context");
-
-        Self(swr)
+        NonNull::new(swr)
+            .map(Self)
+            .ok_or(Error::Alloc("a resampler context"))
     }
 
     pub fn as_ptr(&self) -> *mut ffmpeg::SwrContext {
         self.0.as_ptr()
     }
 
-    pub fn init(&self) {
-        if unsafe { ffmpeg::swr_init(self.0.as_ptr()) } < 0 {
-            panic!("Failed to initialize the resampling context");
-        }
+    pub fn init(&self) -> Result<(), Error> {
+        let ret = unsafe { ffmpeg::swr_init(self.0.as_ptr()) };
+        check(ret, "Could not initialize the resampling context").map(|_| ())
     }
 }
 
@@ -465,7 +491,7 @@ impl SwsContext {
         dist_width: i32,
         dist_height: i32,
         dist_pix_fmt: AVPixelFormat,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let ctx = unsafe {
             ffmpeg::sws_getContext(
                 src_width,
@@ -484,7 +510,11 @@ impl SwsContext {
             )
         };
 
-        Self(NonNull::new(ctx).expect("Could not initialize the conversion context"))
+        NonNull::new(ctx).map(Self).ok_or_else(|| {
+            Error::Unsupported(format!(
+                "Could not convert {src_pix_fmt:?} frames to {dist_pix_fmt:?}"
+            ))
+        })
     }
 
     #[allow(unused)]
@@ -492,8 +522,8 @@ impl SwsContext {
         self.0.as_ptr()
     }
 
-    pub fn scale(&self, src_frame: &Frame, dest_frame: &Frame, height: i32) {
-        unsafe {
+    pub fn scale(&self, src_frame: &Frame, dest_frame: &Frame, height: i32) -> Result<(), Error> {
+        let ret = unsafe {
             ffmpeg::sws_scale(
                 self.0.as_ptr(),
                 (*src_frame.as_ptr()).data.as_ptr() as *const *const u8,
@@ -502,7 +532,8 @@ impl SwsContext {
                 height,
                 (*dest_frame.as_ptr()).data.as_mut_ptr(),
                 (*dest_frame.as_ptr()).linesize.as_ptr(),
-            );
-        }
+            )
+        };
+        check(ret, "Could not convert the frame").map(|_| ())
     }
 }
@@ -2,14 +2,17 @@
 
 use std::{ffi::CString, path::Path};
 
-use ffmpeg::{AVERROR, AVERROR_EOF, AVPixelFormat, EAGAIN};
+use ffmpeg::{AVCodecID, AVERROR, AVERROR_EOF, AVPixelFormat, EAGAIN};
 
 mod audio;
+mod error;
 mod ff;
+mod options;
 mod video;
 
-const FRAME_RATE: i32 = 60;
-const STREAM_PIX_FMT: AVPixelFormat = AVPixelFormat::AV_PIX_FMT_YUV420P;
+pub use error::Error;
+pub use options::{EncoderOptions, Quality, VideoCodec};
+
 const SRC_STREAM_PIX_FMT: AVPixelFormat = AVPixelFormat::AV_PIX_FMT_BGRA;
 
 /// Encode one frame and send it to the muxer.
@@ -20,29 +23,25 @@ fn write_frame(
     packet: &ff::Packet,
     format_ctx: &ff::FormatContext,
     frame: Option<&ff::Frame>,
-) -> bool {
+) -> Result<bool, Error> {
     // Send the frame to the encoder
-    codec_ctx.send_frame(frame);
+    codec_ctx.send_frame(frame)?;
 
-    let mut ret = 0;
-    while ret >= 0 {
-        ret = codec_ctx.receive_packet(packet);
+    loop {
+        let ret = codec_ctx.receive_packet(packet);
 
         if ret == AVERROR(EAGAIN) || ret == AVERROR_EOF {
-            break;
-        } else if ret < 0 {
-            panic!("Error encoding a frame",);
+            return Ok(ret == AVERROR_EOF);
         }
+        error::check(ret, "Could not encode a frame")?;
 
         // Rescale output packet timestamp values from codec to stream timebase
         packet.rescale_ts(codec_ctx.time_base(), stream.time_base());
         packet.set_stream_index(stream.index());
 
         // Write the compressed frame to the media file.
-        format_ctx.interleaved_write_frame(packet);
+        format_ctx.interleaved_write_frame(packet)?;
     }
-
-    ret == AVERROR_EOF
 }
 
 pub enum Frame<'a> {
@@ -56,47 +55,71 @@ pub struct EncoderInfo {
     pub frame_size: usize,
 }
 
-pub fn new(path: impl AsRef<Path>, width: u32, height: u32) -> (EncoderInfo, impl FnMut(Frame)) {
-    let path = path.as_ref().to_str().unwrap();
-    let path = CString::new(path).unwrap();
+/// Opens `path` for encoding, frames are passed to the returned closure and
+/// [`Frame::Terminator`] finishes the file.
+#[allow(clippy::type_complexity)]
+pub fn new(
+    path: &Path,
+    options: &EncoderOptions,
+) -> Result<(EncoderInfo, impl FnMut(Frame) -> Result<(), Error> + use<>), Error> {
+    let path = path
+        .to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or_else(|| Error::InvalidPath(path.to_owned()))?;
 
-    let format_context = ff::FormatContext::new(&path);
+    let format_context = ff::FormatContext::new(&path)?;
 
     let output_format = format_context.output_format();
 
-    let video_stream =
-        video::VideoOutputStream::new(&format_context, &output_format, width as i32, height as i32);
-    let audio_stream = audio::new_audio_streams(&format_context, &output_format);
+    let video_stream = video::VideoOutputStream::new(&format_context, &output_format, options)?;
+    // Image sequences have no audio
+    let audio_stream = if output_format.audio_codec_id() != AVCodecID::AV_CODEC_ID_NONE {
+        Some(audio::new_audio_streams(
+            &format_context,
+            &output_format,
+            options.audio_bitrate,
+        )?)
+    } else {
+        None
+    };
 
     format_context.dump_format(&path);
-    format_context.open(&path);
+    format_context.open(&path)?;
     // Write the stream header, if any.
-    format_context.write_header();
+    format_context.write_header()?;
 
-    let frame_size = audio_stream.codec_ctx.frame_size() as usize;
+    let frame_size = audio_stream
+        .as_ref()
+        .map(|audio| audio.codec_ctx.frame_size() as usize)
+        .unwrap_or(1024);
     let info = EncoderInfo { frame_size };
 
     let mut ctx = Some((video_stream, audio_stream, format_context));
 
-    (info, move |input_frame| match input_frame {
+    Ok((info, move |input_frame: Frame| match input_frame {
         Frame::Vide(input_frame) => {
             let (video_stream, _audio_stream, format_context) =
-                ctx.as_mut().expect("Encoder should not be closed");
-            video_stream.write_frame(format_context, input_frame);
+                ctx.as_mut().ok_or(Error::Closed)?;
+            video_stream.write_frame(format_context, input_frame)?;
+            Ok(())
         }
         Frame::Audio(l, r) => {
             let (_video_stream, audio_stream, format_context) =
-                ctx.as_mut().expect("Encoder should not be closed");
-            audio_stream.write_frame(format_context, l, r);
+                ctx.as_mut().ok_or(Error::Closed)?;
+            if let Some(audio_stream) = audio_stream {
+                audio_stream.write_frame(format_context, l, r)?;
+            }
+            Ok(())
         }
         Frame::Terminator => {
-            let (video_stream, audio_stream, format_ctx) =
-                ctx.take().expect("Encoder should not be closed");
+            let (video_stream, audio_stream, format_ctx) = ctx.take().ok_or(Error::Closed)?;
 
-            video_stream.write_terminator_frame(&format_ctx);
-            audio_stream.write_terminator_frame(&format_ctx);
+            video_stream.write_terminator_frame(&format_ctx)?;
+            if let Some(audio_stream) = audio_stream {
+                audio_stream.write_terminator_frame(&format_ctx)?;
+            }
 
-            format_ctx.write_trailer();
+            format_ctx.write_trailer()
         }
-    })
+    }))
 }
@@ -0,0 +1,110 @@
+use std::ffi::CStr;
+
+use ffmpeg::AVCodecID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+    ProRes,
+    /// Image sequence, the output path needs a frame number pattern, eg. `frames/%05d.png`
+    Png,
+}
+
+impl VideoCodec {
+    pub const ALL: [Self; 6] = [
+        Self::H264,
+        Self::H265,
+        Self::Vp9,
+        Self::Av1,
+        Self::ProRes,
+        Self::Png,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::H265 => "h265",
+            Self::Vp9 => "vp9",
+            Self::Av1 => "av1",
+            Self::ProRes => "prores",
+            Self::Png => "png",
+        }
+    }
+
+    /// Whether the codec can keep the alpha channel, see [`EncoderOptions::alpha`]
+    pub fn supports_alpha(&self) -> bool {
+        matches!(self, Self::Vp9 | Self::ProRes | Self::Png)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.name() == name)
+    }
+
+    pub(crate) fn codec_id(&self) -> AVCodecID {
+        match self {
+            Self::H264 => AVCodecID::AV_CODEC_ID_H264,
+            Self::H265 => AVCodecID::AV_CODEC_ID_HEVC,
+            Self::Vp9 => AVCodecID::AV_CODEC_ID_VP9,
+            Self::Av1 => AVCodecID::AV_CODEC_ID_AV1,
+            Self::ProRes => AVCodecID::AV_CODEC_ID_PRORES,
+            Self::Png => AVCodecID::AV_CODEC_ID_PNG,
+        }
+    }
+
+    /// Encoder preferred over whatever ffmpeg picks for the codec id first
+    pub(crate) fn encoder_name(&self) -> &'static CStr {
+        match self {
+            Self::H264 => c"libx264",
+            Self::H265 => c"libx265",
+            Self::Vp9 => c"libvpx-vp9",
+            Self::Av1 => c"libsvtav1",
+            Self::ProRes => c"prores_ks",
+            Self::Png => c"png",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// Constant rate factor, lower is better, 0 is lossless for h264/h265. Up to 51 for h264/h265
+    /// and 63 for VP9/AV1. Ignored by codecs without a CRF mode, like ProRes.
+    Crf(u32),
+    /// Target bitrate in bits per second
+    Bitrate(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// `None` uses the default codec of the output container
+    pub codec: Option<VideoCodec>,
+    /// `None` uses a visually lossless CRF picked for the codec
+    pub quality: Option<Quality>,
+    /// Pixel format name as understood by ffmpeg (eg. `yuv420p`), `None` picks one for the codec
+    pub pix_fmt: Option<String>,
+    /// Keep the alpha channel of the frames, with a pixel format picked for ProRes 4444, VP9 with
+    /// alpha or PNG. Frames are expected to be straight, not premultiplied.
+    pub alpha: bool,
+    /// Audio bitrate in bits per second
+    pub audio_bitrate: u64,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            codec: None,
+            quality: None,
+            pix_fmt: None,
+            alpha: false,
+            audio_bitrate: 64_000,
+        }
+    }
+}
@@ -1,8 +1,45 @@
-use std::cell::OnceCell;
+use std::{cell::OnceCell, ffi::CString};
+
+use ffmpeg::{AVCodecID, AVPixelFormat, AVRational};
+
+use crate::{EncoderOptions, Error, Quality, SRC_STREAM_PIX_FMT, ff};
+
+/// Pixel format used when none was requested
+fn default_pix_fmt(codec_id: AVCodecID, alpha: bool) -> Result<AVPixelFormat, Error> {
+    Ok(match (codec_id, alpha) {
+        (AVCodecID::AV_CODEC_ID_PRORES, false) => AVPixelFormat::AV_PIX_FMT_YUV422P10LE,
+        // prores_ks picks the 4444 profile for 4:4:4 formats with alpha
+        (AVCodecID::AV_CODEC_ID_PRORES, true) => AVPixelFormat::AV_PIX_FMT_YUVA444P10LE,
+        (AVCodecID::AV_CODEC_ID_VP9, true) => AVPixelFormat::AV_PIX_FMT_YUVA420P,
+        (AVCodecID::AV_CODEC_ID_PNG, false) => AVPixelFormat::AV_PIX_FMT_RGB24,
+        (AVCodecID::AV_CODEC_ID_PNG, true) => AVPixelFormat::AV_PIX_FMT_RGBA,
+        (_, true) => {
+            return Err(Error::Unsupported(format!(
+                "{codec_id:?} does not support alpha"
+            )));
+        }
+        (_, false) => AVPixelFormat::AV_PIX_FMT_YUV420P,
+    })
+}
 
-use ffmpeg::{AVCodecID, AVRational};
+/// CRF used when no quality was requested, `None` for codecs without a CRF mode
+fn default_crf(codec_id: AVCodecID) -> Option<u32> {
+    match codec_id {
+        AVCodecID::AV_CODEC_ID_H264 => Some(18),
+        AVCodecID::AV_CODEC_ID_HEVC => Some(20),
+        AVCodecID::AV_CODEC_ID_VP9 => Some(23),
+        AVCodecID::AV_CODEC_ID_AV1 => Some(23),
+        _ => None,
+    }
+}
 
-use crate::{FRAME_RATE, SRC_STREAM_PIX_FMT, STREAM_PIX_FMT, ff};
+/// Highest CRF the encoder accepts
+fn max_crf(codec_id: AVCodecID) -> u32 {
+    match codec_id {
+        AVCodecID::AV_CODEC_ID_H264 | AVCodecID::AV_CODEC_ID_HEVC => 51,
+        _ => 63,
+    }
+}
 
 pub struct VideoOutputStream {
     pub stream: ff::Stream,
@@ -10,7 +47,7 @@ pub struct VideoOutputStream {
     pub tmp_pkt: ff::Packet,
 
     pub frame: ff::Frame,
-    pub tmp_frame: Option<ff::Frame>,
+    pub tmp_frame: ff::Frame,
 
     pub next_pts: i64,
 
@@ -21,27 +58,42 @@ impl VideoOutputStream {
     pub fn new(
         format_context: &ff::FormatContext,
         output_format: &ff::OutputFormat,
-        width: i32,
-        height: i32,
-    ) -> Self {
-        let codec_id = output_format.video_codec_id();
-        assert_ne!(
-            codec_id,
-            AVCodecID::AV_CODEC_ID_NONE,
-            "The selected output container does not support video encoding"
-        );
-
-        let codec = output_format.video_codec();
+        options: &EncoderOptions,
+    ) -> Result<Self, Error> {
+        let codec = match options.codec {
+            Some(video_codec) => ff::Codec::find_encoder_by_name(video_codec.encoder_name())
+                .or_else(|| ff::Codec::find_encoder(video_codec.codec_id()))
+                .ok_or_else(|| {
+                    Error::Unsupported(format!("Could not find {} encoder", video_codec.name()))
+                })?,
+            None => {
+                if output_format.video_codec_id() == AVCodecID::AV_CODEC_ID_NONE {
+                    return Err(Error::Unsupported(
+                        "The selected output container does not support video encoding".into(),
+                    ));
+                }
+                output_format.video_codec()?
+            }
+        };
+        let codec_id = codec.id();
+
+        let pix_fmt = match options.pix_fmt.as_deref() {
+            Some(name) => ff::pix_fmt_from_name(name)
+                .ok_or_else(|| Error::Unsupported(format!("Unknown pixel format: {name}")))?,
+            None => default_pix_fmt(codec_id, options.alpha)?,
+        };
+
+        let fps = options.fps as i32;
 
         let output_format = output_format.as_ptr();
 
-        let tmp_pkt = ff::Packet::new();
+        let tmp_pkt = ff::Packet::new()?;
 
         let stream = format_context
             .new_stream()
-            .expect("Could not allocate stream");
+            .ok_or(Error::Alloc("a video stream"))?;
 
-        let codec_ctx = codec.context();
+        let codec_ctx = codec.context()?;
 
         unsafe {
             let codec_ctx = codec_ctx.as_ptr();
@@ -50,22 +102,20 @@ impl VideoOutputStream {
             (*codec_ctx).bit_rate = 400000;
 
             // Resolution must be a multiple of two.
-            (*codec_ctx).width = width;
-            (*codec_ctx).height = height;
+            (*codec_ctx).width = options.width as i32;
+            (*codec_ctx).height = options.height as i32;
 
             // timebase: This is the fundamental unit of time (in seconds) in terms
             // of which frame timestamps are represented. For fixed-fps content,
             // timebase should be 1/framerate and timestamp increments should be
             // identical to 1.
-            let time_base = AVRational {
-                num: 1,
-                den: FRAME_RATE,
-            };
+            let time_base = AVRational { num: 1, den: fps };
             (*stream.as_ptr()).time_base = time_base;
             (*codec_ctx).time_base = time_base;
+            (*codec_ctx).framerate = AVRational { num: fps, den: 1 };
 
             (*codec_ctx).gop_size = 12; // emit one intra frame every twelve frames at most
-            (*codec_ctx).pix_fmt = STREAM_PIX_FMT;
+            (*codec_ctx).pix_fmt = pix_fmt;
 
             if (*codec_ctx).codec_id == AVCodecID::AV_CODEC_ID_MPEG2VIDEO {
                 // just for testing, we also add B-frames
@@ -85,30 +135,51 @@ impl VideoOutputStream {
             }
         }
 
-        codec_ctx.open_video();
+        let mut private_options = Vec::new();
+
+        let quality = options
+            .quality
+            .or_else(|| default_crf(codec_id).map(Quality::Crf));
+        match quality {
+            Some(Quality::Crf(crf)) if crf > max_crf(codec_id) => {
+                return Err(Error::Unsupported(format!(
+                    "CRF {crf} is out of range for {codec_id:?}, the highest is {}",
+                    max_crf(codec_id)
+                )));
+            }
+            Some(Quality::Crf(crf)) => {
+                // libvpx only uses constant quality mode with the bitrate unset
+                if codec_id == AVCodecID::AV_CODEC_ID_VP9 {
+                    unsafe { (*codec_ctx.as_ptr()).bit_rate = 0 };
+                }
+                private_options.push((c"crf", CString::new(crf.to_string()).unwrap()));
+            }
+            Some(Quality::Bitrate(bit_rate)) => unsafe {
+                (*codec_ctx.as_ptr()).bit_rate = bit_rate as i64;
+            },
+            None => {}
+        }
+
+        if matches!(codec.name().to_bytes(), b"libx264" | b"libx265") {
+            private_options.push((c"preset", c"medium".into()));
+        }
+
+        codec_ctx.open_video(&private_options)?;
 
         let video_frame =
-            ff::Frame::new_video(codec_ctx.pix_fmt(), codec_ctx.width(), codec_ctx.height());
+            ff::Frame::new_video(codec_ctx.pix_fmt(), codec_ctx.width(), codec_ctx.height())?;
 
         video_frame.set_presentation_timestamp(0);
 
-        // If the output format is not YUV420P, then a temporary YUV420P
-        // picture is needed too. It is then converted to the required
-        // output format.
-        let tmp_frame = if codec_ctx.pix_fmt() != SRC_STREAM_PIX_FMT {
-            Some(ff::Frame::new_video(
-                SRC_STREAM_PIX_FMT,
-                codec_ctx.width(),
-                codec_ctx.height(),
-            ))
-        } else {
-            None
-        };
+        // Rendered frames are BGRA, they get converted to the codec format through a temporary
+        // frame
+        let tmp_frame =
+            ff::Frame::new_video(SRC_STREAM_PIX_FMT, codec_ctx.width(), codec_ctx.height())?;
 
         // copy the stream parameters to the muxer
-        codec_ctx.copy_parameters_to_stream(&stream);
+        codec_ctx.copy_parameters_to_stream(&stream)?;
 
-        Self {
+        Ok(Self {
             stream,
             codec_ctx,
             tmp_pkt,
@@ -116,55 +187,58 @@ impl VideoOutputStream {
             tmp_frame,
             sws_ctx: OnceCell::new(),
             next_pts: 0,
-        }
+        })
     }
 
-    fn next_frame(&mut self, frame_bytes: &[u8]) {
+    fn next_frame(&mut self, frame_bytes: &[u8]) -> Result<(), Error> {
         let video = self;
         let codec_ctx = &video.codec_ctx;
 
-        video.frame.make_writable();
+        let expected = codec_ctx.width() as usize * codec_ctx.height() as usize * 4;
+        if frame_bytes.len() != expected {
+            return Err(Error::FrameSize {
+                expected,
+                actual: frame_bytes.len(),
+            });
+        }
+
+        video.frame.make_writable()?;
 
-        if codec_ctx.pix_fmt() == SRC_STREAM_PIX_FMT {
-            todo!()
-        } else {
-            let sws_ctx = video.sws_ctx.get_or_init(|| {
-                ff::SwsContext::new(
+        let sws_ctx = match video.sws_ctx.get() {
+            Some(sws_ctx) => sws_ctx,
+            None => {
+                let sws_ctx = ff::SwsContext::new(
                     codec_ctx.width(),
                     codec_ctx.height(),
                     SRC_STREAM_PIX_FMT,
                     codec_ctx.width(),
                     codec_ctx.height(),
                     codec_ctx.pix_fmt(),
-                )
-            });
+                )?;
+                video.sws_ctx.get_or_init(|| sws_ctx)
+            }
+        };
 
-            assert_eq!(
-                frame_bytes.len(),
-                codec_ctx.width() as usize * codec_ctx.height() as usize * 4
-            );
-
-            video
-                .tmp_frame
-                .as_ref()
-                .unwrap()
-                .image_fill_arrays(frame_bytes, SRC_STREAM_PIX_FMT);
-
-            sws_ctx.scale(
-                video.tmp_frame.as_ref().unwrap(),
-                &video.frame,
-                codec_ctx.height(),
-            );
-        }
+        video
+            .tmp_frame
+            .image_fill_arrays(frame_bytes, SRC_STREAM_PIX_FMT)?;
+
+        sws_ctx.scale(&video.tmp_frame, &video.frame, codec_ctx.height())?;
 
         video.frame.set_presentation_timestamp(video.next_pts);
         video.next_pts += 1;
+
+        Ok(())
     }
 
     /// Encode one frame and send it to the muxer.
     /// Returns true when encoding is finished, false otherwise.
-    pub fn write_frame(&mut self, format_ctx: &ff::FormatContext, frame_bytes: &[u8]) -> bool {
-        self.next_frame(frame_bytes);
+    pub fn write_frame(
+        &mut self,
+        format_ctx: &ff::FormatContext,
+        frame_bytes: &[u8],
+    ) -> Result<bool, Error> {
+        self.next_frame(frame_bytes)?;
 
         super::write_frame(
             &self.codec_ctx,
@@ -175,7 +249,7 @@ impl VideoOutputStream {
         )
     }
 
-    pub fn write_terminator_frame(&self, format_ctx: &ff::FormatContext) -> bool {
+    pub fn write_terminator_frame(&self, format_ctx: &ff::FormatContext) -> Result<bool, Error> {
         super::write_frame(
             &self.codec_ctx,
             &self.stream,
@@ -0,0 +1,134 @@
+//! Seeking and playback throughput on a large generated ("black MIDI") file.
+//!
+//! Run with `cargo run --release -p midi-file --example playback_bench`
+
+use std::time::{Duration, Instant};
+
+use midi_file::{MidiFile, MidiTrack, PlaybackState};
+use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+const TRACKS: usize = 16;
+const NOTES_PER_TRACK: usize = 50_000;
+const SEEKS: usize = 200;
+
+fn generate() -> MidiFile {
+    let tracks = (0..TRACKS)
+        .map(|track| {
+            let mut events = Vec::with_capacity(NOTES_PER_TRACK * 2);
+            for note in 0..NOTES_PER_TRACK {
+                let key = (21 + (note * 7 + track) % 88) as u8;
+                let channel = (track % 16) as u8;
+
+                events.push(TrackEvent {
+                    delta: 10.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: channel.into(),
+                        message: MidiMessage::NoteOn {
+                            key: key.into(),
+                            vel: 100.into(),
+                        },
+                    },
+                });
+                events.push(TrackEvent {
+                    delta: 20.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: channel.into(),
+                        message: MidiMessage::NoteOff {
+                            key: key.into(),
+                            vel: 0.into(),
+                        },
+                    },
+                });
+            }
+            events
+        })
+        .collect();
+
+    let smf = Smf {
+        header: Header::new(Format::Parallel, Timing::Metrical(480.into())),
+        tracks,
+    };
+
+    MidiFile::from_smf("generated", &smf).unwrap()
+}
+
+/// What seeking used to cost: every track rescanned from its first event
+fn linear_seek(tracks: &[MidiTrack], leed_in: Duration, time: Duration) -> usize {
+    tracks
+        .iter()
+        .map(|track| {
+            track
+                .events
+                .iter()
+                .take_while(|event| event.timestamp + leed_in <= time)
+                .count()
+        })
+        .sum()
+}
+
+/// Deterministic spread of seek targets over the whole song
+fn seek_targets(length: Duration) -> Vec<Duration> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..SEEKS)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            length.mul_f64((seed % 10_000) as f64 / 10_000.0)
+        })
+        .collect()
+}
+
+fn main() {
+    let midi = generate();
+    let events: usize = midi.tracks.iter().map(|track| track.events.len()).sum();
+    let leed_in = Duration::from_secs(3);
+    let mut playback = PlaybackState::new(leed_in, midi.tracks.clone());
+    let targets = seek_targets(playback.length());
+
+    println!("{events} events in {} tracks", midi.tracks.len());
+
+    let start = Instant::now();
+    let mut skipped = 0;
+    for time in targets.iter() {
+        skipped += linear_seek(&midi.tracks, leed_in, *time);
+    }
+    let linear = start.elapsed();
+    std::hint::black_box(skipped);
+
+    let start = Instant::now();
+    for time in targets.iter() {
+        playback.set_time(*time);
+        std::hint::black_box(playback.update(Duration::ZERO).count());
+    }
+    let binary = start.elapsed();
+
+    println!(
+        "seek: linear rescan {:?}/seek, binary search {:?}/seek",
+        linear / SEEKS as u32,
+        binary / SEEKS as u32
+    );
+
+    let frame = Duration::from_secs(1) / 60;
+
+    playback.reset();
+    let start = Instant::now();
+    let mut played = 0;
+    while !playback.is_finished() {
+        let frame_events: Vec<_> = playback.update(frame).collect();
+        played += frame_events.len();
+    }
+    let collected = start.elapsed();
+    std::hint::black_box(played);
+
+    playback.reset();
+    let start = Instant::now();
+    let mut played = 0;
+    while !playback.is_finished() {
+        played += playback.update(frame).count();
+    }
+    let iterated = start.elapsed();
+    std::hint::black_box(played);
+
+    println!("playback at 60fps: collected into Vec {collected:?}, iterated {iterated:?}");
+}
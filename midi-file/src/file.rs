@@ -1,4 +1,4 @@
-use crate::{MidiTrack, program_track::ProgramTrack, tempo_track::TempoTrack};
+use crate::{MidiTrack, SongMeta, program_track::ProgramTrack, tempo_track::TempoTrack};
 use midly::{Format, Smf, Timing};
 use std::{fs, path::Path, sync::Arc};
 
@@ -10,6 +10,7 @@ pub struct MidiFile {
     pub program_track: ProgramTrack,
     pub tempo_track: TempoTrack,
     pub measures: Arc<[std::time::Duration]>,
+    pub meta: SongMeta,
 }
 
 impl MidiFile {
@@ -68,30 +69,16 @@ impl MidiFile {
             })
             .collect();
 
-        let measures = {
-            let last_note_end = tracks
-                .iter()
-                .fold(std::time::Duration::ZERO, |last, track| {
-                    if let Some(note) = track.notes.last() {
-                        last.max(note.start + note.duration)
-                    } else {
-                        last
-                    }
-                });
-
-            let mut masures = Vec::new();
-            let mut time = std::time::Duration::ZERO;
-            let mut id = 0;
-            while time <= last_note_end {
-                time = tempo_track.pulses_to_duration(id * u_per_quarter_note as u64 * 4);
-                masures.push(time);
-                id += 1;
-            }
-
-            masures
-        };
-
         let program_track = ProgramTrack::new(&tracks);
+        let meta = SongMeta::build(&smf.tracks, &tempo_track);
+
+        let last_note_end = tracks
+            .iter()
+            .flat_map(|track| track.notes.iter())
+            .map(|note| note.end)
+            .max()
+            .unwrap_or_default();
+        let measures = meta.bars(&tempo_track, last_note_end);
 
         Ok(Self {
             name,
@@ -100,6 +87,69 @@ impl MidiFile {
             program_track,
             tempo_track,
             measures: measures.into(),
+            meta,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{
+        Header, MetaMessage, MidiMessage, TrackEvent, TrackEventKind,
+        num::{u4, u7, u24, u28},
+    };
+    use std::time::Duration;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        }
+    }
+
+    #[test]
+    fn measures_follow_time_signature() {
+        // A note spanning 2 bars of 3/4, at 120 BPM
+        let note = |delta, vel| {
+            event(
+                delta,
+                TrackEventKind::Midi {
+                    channel: u4::new(0),
+                    message: MidiMessage::NoteOn {
+                        key: u7::new(60),
+                        vel: u7::new(vel),
+                    },
+                },
+            )
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(96.into()),
+        ));
+        smf.tracks.push(vec![
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8)),
+            ),
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+            ),
+            note(0, 100),
+            note(96 * 6, 0),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+
+        let midi = MidiFile::from_smf("waltz.mid", &smf).unwrap();
+        assert_eq!(
+            *midi.measures,
+            [
+                Duration::ZERO,
+                Duration::from_millis(1500),
+                Duration::from_secs(3),
+                Duration::from_millis(4500),
+            ]
+        );
+    }
+}
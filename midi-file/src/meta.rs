@@ -0,0 +1,259 @@
+use midly::{MetaMessage, TrackEvent, TrackEventKind};
+use std::{sync::Arc, time::Duration};
+
+use crate::tempo_track::TempoTrack;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeSignature {
+    pub absolute_pulses: u64,
+    pub timestamp: Duration,
+    pub numerator: u8,
+    /// Note value of a beat, eg. 4 for quarter notes
+    pub denominator: u8,
+}
+
+/// Beat of the song, counted from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beat {
+    pub timestamp: Duration,
+    pub bar: usize,
+    pub beat: u8,
+    pub beats_in_bar: u8,
+}
+
+/// Song information from the meta events of the file
+#[derive(Debug, Clone, Default)]
+pub struct SongMeta {
+    /// Sequence name, the name of the first track
+    pub title: Option<String>,
+    pub copyright: Option<String>,
+    /// Text events of all tracks, in file order
+    pub texts: Vec<String>,
+    /// Sorted by time, 4/4 is assumed until the first one
+    pub time_signatures: Arc<[TimeSignature]>,
+}
+
+impl SongMeta {
+    pub fn build(track_events: &[Vec<TrackEvent>], tempo_track: &TempoTrack) -> Self {
+        let text = |bytes: &[u8]| {
+            Some(String::from_utf8_lossy(bytes).trim().to_string()).filter(|text| !text.is_empty())
+        };
+
+        let mut meta = Self::default();
+        let mut time_signatures: Vec<TimeSignature> = Vec::new();
+
+        for (id, events) in track_events.iter().enumerate() {
+            let mut pulses: u64 = 0;
+            for event in events.iter() {
+                pulses += event.delta.as_int() as u64;
+
+                let TrackEventKind::Meta(message) = event.kind else {
+                    continue;
+                };
+
+                match message {
+                    MetaMessage::TrackName(name) if id == 0 && meta.title.is_none() => {
+                        meta.title = text(name);
+                    }
+                    MetaMessage::Copyright(copyright) if meta.copyright.is_none() => {
+                        meta.copyright = text(copyright);
+                    }
+                    MetaMessage::Text(bytes) => meta.texts.extend(text(bytes)),
+                    MetaMessage::TimeSignature(numerator, denominator_pow, ..) => {
+                        // Every track may repeat the time signature, like tempo
+                        if time_signatures
+                            .iter()
+                            .any(|sig| sig.absolute_pulses == pulses)
+                        {
+                            continue;
+                        }
+
+                        time_signatures.push(TimeSignature {
+                            absolute_pulses: pulses,
+                            timestamp: tempo_track.pulses_to_duration(pulses),
+                            numerator: numerator.max(1),
+                            denominator: 1u8.checked_shl(denominator_pow as u32).unwrap_or(4),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        time_signatures.sort_by_key(|sig| sig.absolute_pulses);
+        meta.time_signatures = time_signatures.into();
+        meta
+    }
+
+    /// Composer named by a text event like "Composer: ..." or "by ..."
+    pub fn composer(&self) -> Option<&str> {
+        self.texts
+            .iter()
+            .find_map(|text| {
+                ["composed by ", "composer:", "composer ", "music by ", "by "]
+                    .iter()
+                    .find(|prefix| {
+                        text.get(..prefix.len())
+                            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                    })
+                    .map(|prefix| text[prefix.len()..].trim())
+            })
+            .filter(|composer| !composer.is_empty())
+    }
+
+    /// Every beat from the start of the song until `end`
+    pub fn beats(&self, tempo_track: &TempoTrack, end: Duration) -> Vec<Beat> {
+        self.beat_iter(tempo_track)
+            .take_while(|beat| beat.timestamp <= end)
+            .collect()
+    }
+
+    /// Start of every bar, up to and including the first one after `end`
+    pub fn bars(&self, tempo_track: &TempoTrack, end: Duration) -> Vec<Duration> {
+        let mut bars = Vec::new();
+        for beat in self.beat_iter(tempo_track).filter(|beat| beat.beat == 1) {
+            bars.push(beat.timestamp);
+            if beat.timestamp > end {
+                break;
+            }
+        }
+        bars
+    }
+
+    fn beat_iter<'a>(&'a self, tempo_track: &'a TempoTrack) -> impl Iterator<Item = Beat> + 'a {
+        let ppq = tempo_track.pulses_per_quarter_note() as u64;
+
+        let mut signatures = self.time_signatures.iter().peekable();
+        let (mut numerator, mut denominator) = (4, 4);
+        let mut pulses = 0;
+        let (mut bar, mut beat) = (1, 0);
+
+        std::iter::from_fn(move || {
+            while let Some(sig) = signatures.next_if(|sig| sig.absolute_pulses <= pulses) {
+                numerator = sig.numerator;
+                denominator = sig.denominator;
+                // A new time signature starts a new bar
+                if beat != 0 {
+                    bar += 1;
+                    beat = 0;
+                }
+            }
+
+            let current = Beat {
+                timestamp: tempo_track.pulses_to_duration(pulses),
+                bar,
+                beat: beat + 1,
+                beats_in_bar: numerator,
+            };
+
+            let next = pulses + (ppq * 4 / denominator as u64).max(1);
+            // The next time signature may not fall on a beat
+            pulses = match signatures.peek() {
+                Some(sig) if sig.absolute_pulses < next => sig.absolute_pulses,
+                _ => next,
+            };
+
+            beat += 1;
+            if beat == numerator {
+                bar += 1;
+                beat = 0;
+            }
+
+            Some(current)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u4, u7, u24, u28};
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        }
+    }
+
+    #[test]
+    fn beats() {
+        // 2 bars of 3/4 then 6/8, at 120 BPM
+        let tracks = vec![vec![
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8)),
+            ),
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+            ),
+            event(
+                96 * 6,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(6, 3, 24, 8)),
+            ),
+            event(
+                96 * 3,
+                TrackEventKind::Midi {
+                    channel: u4::new(0),
+                    message: midly::MidiMessage::NoteOn {
+                        key: u7::new(60),
+                        vel: u7::new(0),
+                    },
+                },
+            ),
+        ]];
+        let tempo_track = TempoTrack::build(&tracks, 96);
+        let meta = SongMeta::build(&tracks, &tempo_track);
+
+        let beats = meta.beats(&tempo_track, Duration::from_millis(4500));
+        let counts: Vec<_> = beats.iter().map(|beat| (beat.bar, beat.beat)).collect();
+        assert_eq!(
+            counts,
+            [
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (2, 1),
+                (2, 2),
+                (2, 3),
+                (3, 1),
+                (3, 2),
+                (3, 3),
+                (3, 4),
+                (3, 5),
+                (3, 6),
+                (4, 1),
+            ]
+        );
+        assert_eq!(beats[6].timestamp, Duration::from_secs(3));
+        assert_eq!(beats[7].timestamp, Duration::from_millis(3250));
+        assert_eq!(beats[7].beats_in_bar, 6);
+
+        let bar_starts: Vec<_> = beats
+            .iter()
+            .filter(|beat| beat.beat == 1)
+            .map(|beat| beat.timestamp)
+            .collect();
+        let bars = meta.bars(&tempo_track, Duration::from_millis(4500));
+        assert_eq!(bars[..bar_starts.len()], bar_starts);
+        assert_eq!(bars[bar_starts.len()..], [Duration::from_secs(6)]);
+    }
+
+    #[test]
+    fn composer() {
+        let meta = SongMeta {
+            copyright: Some("Public domain".into()),
+            texts: vec!["Arranged for piano".into(), "Composed by F. Chopin".into()],
+            ..Default::default()
+        };
+        assert_eq!(meta.composer(), Some("F. Chopin"));
+
+        let meta = SongMeta {
+            texts: vec!["Arranged for piano".into()],
+            ..meta
+        };
+        // The copyright notice doesn't name the composer
+        assert_eq!(meta.composer(), None);
+    }
+}
@@ -1,10 +1,13 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::{MidiEvent, MidiTrack};
 
 #[derive(Debug, Clone)]
 struct TrackState {
+    /// Events that were already played
     seen_events: usize,
+    /// Value of `seen_events` before the last `update`
+    prev_seen_events: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -29,12 +32,18 @@ impl PlaybackState {
                 first_note_start = first_note_start.min(note.start);
             }
 
-            if let Some(note) = track.notes.last() {
-                last_note_end = last_note_end.max(note.start + note.duration);
+            for note in track.notes.iter() {
+                last_note_end = last_note_end.max(note.end);
             }
         }
 
-        let track_states = vec![TrackState { seen_events: 0 }; tracks.len()];
+        let track_states = vec![
+            TrackState {
+                seen_events: 0,
+                prev_seen_events: 0,
+            };
+            tracks.len()
+        ];
 
         Self {
             tracks,
@@ -48,24 +57,44 @@ impl PlaybackState {
         }
     }
 
-    pub fn update(&mut self, delta: Duration) -> Vec<&MidiEvent> {
+    /// Advances the playback and returns events that became due, track by track.
+    ///
+    /// Nothing is allocated, the returned events borrow the tracks directly.
+    pub fn update(&mut self, delta: Duration) -> impl Iterator<Item = &MidiEvent> + '_ {
         if !self.is_paused {
             self.running += delta;
         }
 
-        let events: Vec<_> = self
-            .tracks
+        for (track, state) in self.tracks.iter().zip(self.track_states.iter_mut()) {
+            state.prev_seen_events = state.seen_events;
+            state.seen_events += Self::due_events_count(
+                &track.events[state.seen_events..],
+                self.leed_in,
+                self.running,
+            );
+        }
+
+        self.tracks
             .iter()
-            .zip(self.track_states.iter_mut())
-            .flat_map(|(track, state)| {
-                track.events[state.seen_events..]
-                    .iter()
-                    .take_while(|event| event.timestamp + self.leed_in <= self.running)
-                    .inspect(|_| state.seen_events += 1)
-            })
-            .collect();
+            .zip(self.track_states.iter())
+            .flat_map(|(track, state)| &track.events[state.prev_seen_events..state.seen_events])
+    }
 
-        events
+    /// Number of leading `events` that are due at `time`, events are sorted by timestamp.
+    ///
+    /// Exponential search, so the usual handful of events per frame costs only a few comparisons
+    /// while seeking across the whole track is still logarithmic.
+    fn due_events_count(events: &[MidiEvent], leed_in: Duration, time: Duration) -> usize {
+        let is_due = |event: &MidiEvent| event.timestamp + leed_in <= time;
+
+        let mut bound = 1;
+        while bound <= events.len() && is_due(&events[bound - 1]) {
+            bound *= 2;
+        }
+
+        let start = bound / 2;
+        let end = bound.min(events.len());
+        start + events[start..end].partition_point(is_due)
     }
 
     pub fn is_paused(&self) -> bool {
@@ -84,9 +113,47 @@ impl PlaybackState {
         self.running
     }
 
+    /// Seeks to `time`, events due before it are considered already played
     pub fn set_time(&mut self, time: Duration) {
-        self.reset();
         self.running = time;
+
+        for (track, state) in self.tracks.iter().zip(self.track_states.iter_mut()) {
+            state.seen_events = Self::due_events_count(&track.events, self.leed_in, time);
+            state.prev_seen_events = state.seen_events;
+        }
+    }
+
+    /// Channel setup events that were already played: the last program, controller values,
+    /// pitch bend and channel pressure of every channel, in timestamp order.
+    ///
+    /// Replaying those after [`Self::set_time`] puts an output in the same state as playing
+    /// the song from the start would.
+    pub fn channel_state(&self) -> Vec<&MidiEvent> {
+        use midly::MidiMessage;
+
+        // (channel, message kind, controller number) -> last event
+        let mut state: HashMap<(u8, u8, u8), &MidiEvent> = HashMap::new();
+
+        for (track, track_state) in self.tracks.iter().zip(self.track_states.iter()) {
+            for event in &track.events[..track_state.seen_events] {
+                let key = match event.message {
+                    MidiMessage::ProgramChange { .. } => (0, 0),
+                    MidiMessage::Controller { controller, .. } => (1, controller.as_int()),
+                    MidiMessage::PitchBend { .. } => (2, 0),
+                    MidiMessage::ChannelAftertouch { .. } => (3, 0),
+                    _ => continue,
+                };
+
+                let entry = state.entry((event.channel, key.0, key.1)).or_insert(event);
+                if entry.timestamp <= event.timestamp {
+                    *entry = event;
+                }
+            }
+        }
+
+        let mut events: Vec<_> = state.into_values().collect();
+        events.sort_by_key(|event| event.timestamp);
+        events
     }
 
     pub fn is_finished(&self) -> bool {
@@ -118,6 +185,96 @@ impl PlaybackState {
 
         for state in self.track_states.iter_mut() {
             state.seen_events = 0;
+            state.prev_seen_events = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MidiFile;
+
+    fn due_between(tracks: &[MidiTrack], from: Duration, to: Duration) -> Vec<Duration> {
+        tracks
+            .iter()
+            .flat_map(|track| track.events.iter())
+            .filter(|event| event.timestamp > from && event.timestamp <= to)
+            .map(|event| event.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn update_returns_every_event_once() {
+        let midi = MidiFile::new("../test.mid").unwrap();
+        let mut playback = PlaybackState::new(Duration::ZERO, midi.tracks.clone());
+
+        let mut count = playback.update(Duration::ZERO).count();
+        while !playback.is_finished() {
+            count += playback.update(Duration::from_millis(16)).count();
+        }
+        count += playback.update(Duration::from_secs(60)).count();
+
+        let total: usize = midi.tracks.iter().map(|track| track.events.len()).sum();
+        assert_eq!(count, total);
+    }
+
+    #[test]
+    fn set_time_skips_past_events() {
+        let midi = MidiFile::new("../test.mid").unwrap();
+        let leed_in = Duration::from_secs(1);
+        let mut playback = PlaybackState::new(leed_in, midi.tracks.clone());
+        let step = Duration::from_millis(250);
+
+        for time in [10, 2, 7, 0, 5].map(Duration::from_secs) {
+            playback.set_time(time);
+
+            let mut events: Vec<_> = playback.update(step).map(|event| event.timestamp).collect();
+            let mut expected = due_between(
+                &midi.tracks,
+                time.saturating_sub(leed_in),
+                (time + step).saturating_sub(leed_in),
+            );
+
+            events.sort();
+            expected.sort();
+            assert_eq!(events, expected);
+        }
+    }
+
+    #[test]
+    fn channel_state_is_latest_per_channel() {
+        use midly::MidiMessage;
+
+        let midi = MidiFile::new("../test.mid").unwrap();
+        let mut playback = PlaybackState::new(Duration::ZERO, midi.tracks.clone());
+        let time = Duration::from_secs(10);
+        playback.set_time(time);
+
+        let kind = |event: &MidiEvent| match event.message {
+            MidiMessage::ProgramChange { .. } => Some((event.channel, 0, 0)),
+            MidiMessage::Controller { controller, .. } => {
+                Some((event.channel, 1, controller.as_int()))
+            }
+            MidiMessage::PitchBend { .. } => Some((event.channel, 2, 0)),
+            MidiMessage::ChannelAftertouch { .. } => Some((event.channel, 3, 0)),
+            _ => None,
+        };
+
+        let played: Vec<_> = midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.events.iter())
+            .filter(|event| event.timestamp <= time && kind(event).is_some())
+            .collect();
+        let state = playback.channel_state();
+
+        assert!(!state.is_empty());
+        assert!(state.is_sorted_by_key(|event| event.timestamp));
+        for event in played.iter() {
+            let latest = state.iter().find(|e| kind(e) == kind(event)).unwrap();
+            assert!(latest.timestamp >= event.timestamp);
         }
+        assert!(state.iter().all(|event| event.timestamp <= time));
     }
 }
@@ -1,4 +1,4 @@
-use midly::{MidiMessage, TrackEvent, TrackEventKind, num::u4};
+use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind, num::u4};
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::tempo_track::TempoTrack;
@@ -33,13 +33,15 @@ pub struct MidiNote {
 
 #[derive(Debug, Clone)]
 pub struct MidiTrack {
-    // Translated notes with calculated timings
+    // Translated notes with calculated timings, sorted by start
     pub notes: Arc<[MidiNote]>,
 
     pub events: Arc<[MidiEvent]>,
 
     pub track_id: usize,
     pub track_color_id: usize,
+    /// Name from the track name meta event, if there is one
+    pub name: Option<String>,
 
     pub programs: Arc<[ProgramEvent]>,
     pub has_drums: bool,
@@ -57,16 +59,21 @@ impl MidiTrack {
             events,
             EventsBuilder {
                 programs,
-                notes,
+                mut notes,
+                name,
                 has_drums,
                 has_other_than_drums,
                 ..
             },
         ) = build(track_id, track_color_id, tempo_track, track_events);
 
+        // Notes are finished in NoteOff order, lookups by time need them ordered by start
+        notes.sort_by_key(|note| note.start);
+
         Self {
             track_id,
             track_color_id,
+            name,
             notes: notes.into(),
             events: events.into(),
             programs: programs.into(),
@@ -85,6 +92,7 @@ struct NoteInfo {
 #[derive(Default)]
 struct EventsBuilder {
     programs: Vec<ProgramEvent>,
+    name: Option<String>,
     has_drums: bool,
     has_other_than_drums: bool,
 
@@ -205,6 +213,13 @@ fn build(
                     let timestamp = tempo_track.pulses_to_duration(pulses);
                     Some(builder.on_event(channel, message, timestamp, track_id, track_color_id))
                 }
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    if builder.name.is_none() {
+                        let name = String::from_utf8_lossy(name).trim().to_string();
+                        builder.name = Some(name).filter(|name| !name.is_empty());
+                    }
+                    None
+                }
                 _ => None,
             }
         })
@@ -1,65 +1,302 @@
+use clap::{ArgGroup, Command, arg, value_parser};
 use std::path::PathBuf;
+use std::time::Duration;
 
-fn print_help() {
-    let help = [
-        "  -i, --input <audio-input-file>",
-        "  -o, --output <midi-output-file>",
-        "  -m, --model <rten-model-file>",
-    ];
-    println!("Options:");
-    println!("{}", help.join("\n"));
-    println!();
+use neothesia_ai::{Options, detection::Thresholds, evaluate::Tolerances};
+
+/// What to do, picked by the subcommand
+#[derive(Debug)]
+pub enum Mode {
+    Transcribe(Args),
+    Evaluate(EvaluateArgs),
+    Listen(ListenArgs),
+}
+
+impl Mode {
+    /// Parses the command line, exits on invalid arguments or `--help`
+    pub fn get() -> Self {
+        let command = Command::new("neothesia-ai")
+            .about("Transcribe piano recordings to MIDI")
+            .subcommand(EvaluateArgs::command())
+            .subcommand(ListenArgs::command())
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true);
+
+        let matches = Args::command(command).get_matches();
+
+        match matches.subcommand() {
+            Some(("evaluate", matches)) => Self::Evaluate(EvaluateArgs::from_matches(matches)),
+            Some(("listen", matches)) => Self::Listen(ListenArgs::from_matches(matches)),
+            _ => Self::Transcribe(Args::from_matches(&matches)),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Args {
-    pub input: PathBuf,
-    pub output: PathBuf,
     pub model: PathBuf,
+    pub input: Input,
+    pub options: Options,
 }
 
-impl Args {
-    pub fn get_from_env() -> anyhow::Result<Args> {
-        let mut args = std::env::args().skip(1);
-
-        let mut input = None;
-        let mut output = None;
-        let mut model = None;
-
-        while let Some(arg) = args.next() {
-            match arg.as_str() {
-                "--input" | "-i" => {
-                    input = args.next();
-                }
-                "--output" | "-o" => {
-                    output = args.next();
-                }
-                "--model" | "-m" => {
-                    model = args.next();
-                }
-                "--help" | "-h" => {
-                    print_help();
-                }
-                _ => {}
-            }
+#[derive(Debug)]
+pub enum Input {
+    File {
+        input: PathBuf,
+        /// Next to the input by default
+        output: Option<PathBuf>,
+    },
+    /// Every audio file of a directory
+    Batch {
+        dir: PathBuf,
+        /// The input directory by default
+        out_dir: Option<PathBuf>,
+    },
+}
+
+/// Arguments of the `evaluate` subcommand
+#[derive(Debug)]
+pub struct EvaluateArgs {
+    pub reference: PathBuf,
+    pub transcription: PathBuf,
+    pub tolerances: Tolerances,
+    /// Print the report as JSON
+    pub json: bool,
+    /// Fail when the onset F1 score is lower
+    pub min_f1: Option<f32>,
+}
+
+impl EvaluateArgs {
+    fn command() -> Command {
+        Command::new("evaluate")
+            .about("Compare a transcription with a reference MIDI file, note by note")
+            .arg(
+                arg!([REFERENCE_MIDI])
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!([TRANSCRIBED_MIDI])
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"onset-tolerance" <SECONDS> "Onsets this far apart still match, 0.05 by default")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(arg!(--json "Print the scores as JSON"))
+            .arg(
+                arg!(--"min-f1" <F1> "Exit with an error when the onset F1 score is lower")
+                    .required(false)
+                    .value_parser(parse_score),
+            )
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        let defaults = Tolerances::default();
+
+        Self {
+            reference: matches
+                .get_one::<PathBuf>("REFERENCE_MIDI")
+                .unwrap()
+                .clone(),
+            transcription: matches
+                .get_one::<PathBuf>("TRANSCRIBED_MIDI")
+                .unwrap()
+                .clone(),
+            tolerances: Tolerances {
+                onset: matches
+                    .get_one::<f32>("onset-tolerance")
+                    .map(|secs| Duration::from_secs_f32(*secs))
+                    .unwrap_or(defaults.onset),
+                ..defaults
+            },
+            json: matches.get_flag("json"),
+            min_f1: matches.get_one::<f32>("min-f1").copied(),
         }
+    }
+}
 
-        let Some(input) = input else {
-            anyhow::bail!("`--input` audio file missing");
-        };
+/// Arguments of the `listen` subcommand
+#[derive(Debug)]
+pub struct ListenArgs {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub sample_rate: u32,
+}
 
-        let Some(output) = output else {
-            anyhow::bail!("`--output` midi file missing");
-        };
+impl ListenArgs {
+    fn command() -> Command {
+        Command::new("listen")
+            .about("Detect notes in a recording the way Neothesia listens to a microphone")
+            .arg(
+                arg!([AUDIO_FILE])
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(-o --output <MIDI_FILE> "Write the notes to a MIDI file, to compare them with evaluate")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"sample-rate" <HZ> "Sample rate of the input device to try")
+                    .required(false)
+                    .default_value("48000")
+                    .value_parser(value_parser!(u32).range(8000..)),
+            )
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        Self {
+            input: matches.get_one::<PathBuf>("AUDIO_FILE").unwrap().clone(),
+            output: matches.get_one::<PathBuf>("output").cloned(),
+            sample_rate: *matches.get_one::<u32>("sample-rate").unwrap(),
+        }
+    }
+}
+
+impl Args {
+    fn command(command: Command) -> Command {
+        command
+            .arg(
+                arg!(-m --model <RTEN_FILE> "Transcription model")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(-i --input <AUDIO_FILE> "Recording to transcribe, WAV, FLAC, MP3 or OGG")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(-o --output <MIDI_FILE> "Where to write the MIDI file, next to the input by default")
+                    .required(false)
+                    .conflicts_with("batch")
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--batch <DIR> "Transcribe every recording of a directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"out-dir" <DIR> "Where to write the MIDI files of a batch, DIR by default")
+                    .required(false)
+                    .conflicts_with("input")
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .group(
+                ArgGroup::new("inputs")
+                    .args(["input", "batch"])
+                    .required(true),
+            )
+            .arg(
+                arg!(--"onset-threshold" <THRESHOLD> "Onset detection threshold, 0.3 by default")
+                    .required(false)
+                    .value_parser(parse_threshold),
+            )
+            .arg(
+                arg!(--"offset-threshold" <THRESHOLD> "Offset detection threshold, 0.2 by default")
+                    .required(false)
+                    .value_parser(parse_threshold),
+            )
+            .arg(
+                arg!(--"frame-threshold" <THRESHOLD> "Below this a note is released, 0.1 by default")
+                    .required(false)
+                    .value_parser(parse_threshold),
+            )
+            .arg(
+                arg!(--tempo <BPM> "Tempo of the MIDI file, or the expected tempo with --beats, 120 by default")
+                    .required(false)
+                    .value_parser(parse_tempo),
+            )
+            .arg(arg!(--beats "Find the beats of the music and write them as the tempo and time signature"))
+            .arg(
+                arg!(--quantize <DIVISION> "Snap notes to a grid, eg. 16 for sixteenth notes")
+                    .required(false)
+                    .value_parser(["4", "8", "12", "16", "24", "32"]),
+            )
+            .arg(
+                arg!(--"min-note-length" <SECONDS> "Drop shorter notes, eg. 0.03")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(arg!(--"extend-notes" "Extend note offsets to the sustain pedal release"))
+    }
 
-        let Some(model) = model else {
-            anyhow::bail!("`--model` rten model file missing");
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        let defaults = Thresholds::default();
+
+        let input = match matches.get_one::<PathBuf>("batch") {
+            Some(dir) => Input::Batch {
+                dir: dir.clone(),
+                out_dir: matches.get_one::<PathBuf>("out-dir").cloned(),
+            },
+            None => Input::File {
+                input: matches.get_one::<PathBuf>("input").unwrap().clone(),
+                output: matches.get_one::<PathBuf>("output").cloned(),
+            },
         };
 
-        Ok(Args {
-            input: PathBuf::from(input),
-            output: PathBuf::from(output),
-            model: PathBuf::from(model),
-        })
+        let threshold =
+            |name: &str, default: f32| matches.get_one::<f32>(name).copied().unwrap_or(default);
+
+        Self {
+            model: matches.get_one::<PathBuf>("model").unwrap().clone(),
+            input,
+            options: Options {
+                thresholds: Thresholds {
+                    onset: threshold("onset-threshold", defaults.onset),
+                    offset: threshold("offset-threshold", defaults.offset),
+                    frame: threshold("frame-threshold", defaults.frame),
+                },
+                tempo: matches.get_one::<f32>("tempo").copied().unwrap_or(120.0),
+                track_beats: matches.get_flag("beats"),
+                quantize: matches
+                    .get_one::<String>("quantize")
+                    .map(|division| division.parse().unwrap()),
+                min_note_length: matches
+                    .get_one::<f32>("min-note-length")
+                    .copied()
+                    .unwrap_or(0.0),
+                extend_notes: matches.get_flag("extend-notes"),
+            },
+        }
     }
 }
+
+fn parse_threshold(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|threshold| (0.0..1.0).contains(threshold))
+        .ok_or_else(|| format!("invalid threshold: {value}, expected 0 to 1"))
+}
+
+fn parse_tempo(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|bpm| (20.0..=400.0).contains(bpm))
+        .ok_or_else(|| format!("invalid tempo: {value}, expected 20 to 400 BPM"))
+}
+
+fn parse_score(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|score| (0.0..=1.0).contains(score))
+        .ok_or_else(|| format!("invalid score: {value}, expected 0 to 1"))
+}
+
+fn parse_seconds(value: &str) -> Result<f32, String> {
+    value
+        .strip_suffix('s')
+        .unwrap_or(value)
+        .parse::<f32>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .ok_or_else(|| format!("invalid time: {value}"))
+}
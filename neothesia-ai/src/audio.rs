@@ -1,32 +1,458 @@
-use std::num::NonZeroU32;
+//! Audio files decoded packet by packet and resampled on the fly, for the transcription and for
+//! playing recordings along with songs.
+
 use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use symphonium::resample::fixed_resample::{
+    self, LastPacketInfo, PacketResampler, ResamplerConfig, Sequential,
+    audioadapter_buffers::direct::SequentialSliceOfSlices,
+};
+use symphonium::symphonia::core::{
+    codecs::audio::{AudioDecoder, AudioDecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatReader, SeekMode, SeekTo, TrackType},
+    units::{Time, TimeBase},
+};
+
+use crate::SAMPLE_RATE;
+
+/// Channels of the decoded audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    /// Every channel averaged
+    Mono,
+    /// The first two channels, mono audio on both
+    Stereo,
+}
+
+impl Channels {
+    pub fn count(self) -> usize {
+        match self {
+            Channels::Mono => 1,
+            Channels::Stereo => 2,
+        }
+    }
+}
+
+/// Audio file decoded packet by packet. Only a few packets are held in memory, however long the
+/// file is.
+pub struct AudioStream {
+    format_reader: Box<dyn FormatReader>,
+    decoder: Box<dyn AudioDecoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    source_channels: usize,
+    source_rate: u32,
+    channels: Channels,
+    resampler: PacketResampler<f32, Sequential<f32>>,
+    duration: Option<Duration>,
+
+    /// Decoded packet, interleaved
+    interleaved: Vec<f32>,
+    /// Decoded packet, one buffer per output channel
+    planar: Vec<Vec<f32>>,
+    /// Resampled samples not read yet, one buffer per output channel
+    pending: Vec<Vec<f32>>,
+    /// Decoded frames to drop after a seek, the packet seeked to starts before the seek time
+    skip: usize,
+    input_frames: u64,
+    output_frames: u64,
+    finished: bool,
+}
+
+impl AudioStream {
+    /// Mono at [`SAMPLE_RATE`], as the transcription model expects it
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::open_with(path, SAMPLE_RATE, Channels::Mono)
+    }
+
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        sample_rate: u32,
+        channels: Channels,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Self::open_inner(path, sample_rate, channels)
+            .with_context(|| format!("Could not open {}", path.display()))
+    }
+
+    fn open_inner(path: &Path, sample_rate: u32, channels: Channels) -> anyhow::Result<Self> {
+        let probed = symphonium::probe_from_file(path, None)?;
+        let source_rate = probed
+            .sample_rate()
+            .context("The audio has an unknown sample rate")?
+            .get();
+        let source_channels = probed.num_channels().get();
+
+        let format_reader: Box<dyn FormatReader> = probed.into();
+        let track = format_reader
+            .default_track(TrackType::Audio)
+            .context("The file has no audio track")?;
+        let track_id = track.id;
+        let time_base = track.time_base;
+        let duration = track
+            .num_frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / source_rate as f64));
+
+        let params = track
+            .codec_params
+            .as_ref()
+            .and_then(|params| params.audio())
+            .context("The audio track has no codec parameters")?;
+        let decoder = symphonium::symphonia::default::get_codecs()
+            .make_audio_decoder(params, &AudioDecoderOptions::default())?;
+
+        let resampler = PacketResampler::new(
+            channels.count(),
+            source_rate,
+            sample_rate,
+            ResamplerConfig::default(),
+        );
+
+        Ok(Self {
+            format_reader,
+            decoder,
+            track_id,
+            time_base,
+            source_channels,
+            source_rate,
+            channels,
+            resampler,
+            duration,
+
+            interleaved: Vec::new(),
+            planar: vec![Vec::new(); channels.count()],
+            pending: vec![Vec::new(); channels.count()],
+            skip: 0,
+            input_frames: 0,
+            output_frames: 0,
+            finished: false,
+        })
+    }
+
+    /// Length of the audio, when the file tells it
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    pub fn channels(&self) -> Channels {
+        self.channels
+    }
+
+    /// Fills `buf` with the next frames, interleaved. Returns how many samples were read, less
+    /// than `buf.len()` only at the end of the audio.
+    pub fn read(&mut self, buf: &mut [f32]) -> anyhow::Result<usize> {
+        let count = self.channels.count();
+        let frames = buf.len() / count;
+
+        while self.pending[0].len() < frames && !self.finished {
+            self.decode_packet().context("Could not decode the audio")?;
+        }
+
+        let frames = self.pending[0].len().min(frames);
+        for (channel, pending) in self.pending.iter_mut().enumerate() {
+            for (frame, sample) in pending.drain(..frames).enumerate() {
+                buf[frame * count + channel] = sample;
+            }
+        }
+        Ok(frames * count)
+    }
+
+    /// Moves to `time`, the next read starts there
+    pub fn seek(&mut self, time: Duration) -> anyhow::Result<()> {
+        let seeked = self
+            .format_reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::try_from_secs_f64(time.as_secs_f64())
+                        .context("Invalid seek time")?,
+                    track_id: Some(self.track_id),
+                },
+            )
+            .context("Could not seek in the audio")?;
+
+        let nanos = |ts| {
+            self.time_base
+                .and_then(|time_base| time_base.calc_time(ts))
+                .map_or(0, |time| time.as_nanos())
+        };
+        let early = (nanos(seeked.required_ts) - nanos(seeked.actual_ts)).max(0);
+        self.skip = (early * self.source_rate as i128 / 1_000_000_000) as usize;
+
+        self.decoder.reset();
+        self.resampler.reset();
+        for pending in &mut self.pending {
+            pending.clear();
+        }
+        self.input_frames = 0;
+        self.output_frames = 0;
+        self.finished = false;
+
+        Ok(())
+    }
+
+    fn decode_packet(&mut self) -> anyhow::Result<()> {
+        let Some(packet) = self.format_reader.next_packet()? else {
+            self.flush();
+            return Ok(());
+        };
+        if packet.track_id != self.track_id {
+            return Ok(());
+        }
+
+        let decoded = match self.decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet is skipped, like players do
+            Err(SymphoniaError::DecodeError(_) | SymphoniaError::IoError(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        decoded.copy_to_vec_interleaved(&mut self.interleaved);
+        match self.channels {
+            Channels::Mono => downmix(&self.interleaved, self.source_channels, &mut self.planar[0]),
+            Channels::Stereo => {
+                to_stereo(&self.interleaved, self.source_channels, &mut self.planar)
+            }
+        }
+
+        let skip = self.skip.min(self.planar[0].len());
+        self.skip -= skip;
+        for channel in &mut self.planar {
+            channel.drain(..skip);
+        }
+
+        let frames = self.planar[0].len();
+        if frames == 0 {
+            return Ok(());
+        }
+        self.input_frames += frames as u64;
+
+        let pending = &mut self.pending;
+        let output_frames = &mut self.output_frames;
+        let planar: Vec<&[f32]> = self.planar.iter().map(Vec::as_slice).collect();
+        self.resampler.process(
+            &SequentialSliceOfSlices::new(&planar, planar.len(), frames).unwrap(),
+            None,
+            None,
+            |out, frames| {
+                for (channel, pending) in pending.iter_mut().enumerate() {
+                    fixed_resample::extend_from_adapter_channel(pending, out, 0, channel, frames);
+                }
+                *output_frames += frames as u64;
+            },
+            None,
+            true,
+        );
+
+        Ok(())
+    }
+
+    /// Takes the samples left in the resampler, the audio has ended
+    fn flush(&mut self) {
+        self.finished = true;
+
+        let expected = self.resampler.out_alloc_frames(self.input_frames);
+        let Some(desired_output_frames) = expected
+            .checked_sub(self.output_frames)
+            .filter(|frames| *frames > 0)
+        else {
+            return;
+        };
+
+        let pending = &mut self.pending;
+        let planar: Vec<&[f32]> = self.planar.iter().map(|_| [].as_slice()).collect();
+        self.resampler.process(
+            &SequentialSliceOfSlices::new(&planar, planar.len(), 0).unwrap(),
+            Some(0..0),
+            None,
+            |out, frames| {
+                for (channel, pending) in pending.iter_mut().enumerate() {
+                    fixed_resample::extend_from_adapter_channel(pending, out, 0, channel, frames);
+                }
+            },
+            Some(LastPacketInfo {
+                desired_output_frames: Some(desired_output_frames),
+            }),
+            true,
+        );
+    }
+}
+
+/// Averages interleaved frames into `mono`. The LFE channel of 5.1 and 7.1 audio is left out, it
+/// has no pitched content.
+fn downmix(interleaved: &[f32], channels: usize, mono: &mut Vec<f32>) {
+    mono.clear();
+
+    if channels == 1 {
+        mono.extend_from_slice(interleaved);
+        return;
+    }
+
+    // WAV and FLAC order the channels front left, front right, center, LFE, then surround
+    let lfe = matches!(channels, 6 | 8).then_some(3);
+    let scale = 1.0 / (channels - lfe.is_some() as usize) as f32;
+
+    mono.extend(interleaved.chunks_exact(channels).map(|frame| {
+        frame
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| Some(*id) != lfe)
+            .map(|(_, sample)| sample * scale)
+            .sum::<f32>()
+    }));
+}
+
+/// Splits interleaved frames into the front left and right channels
+fn to_stereo(interleaved: &[f32], channels: usize, stereo: &mut [Vec<f32>]) {
+    let [left, right] = stereo else {
+        unreachable!("stereo has two channels");
+    };
+    left.clear();
+    right.clear();
+
+    for frame in interleaved.chunks_exact(channels) {
+        left.push(frame[0]);
+        right.push(frame[channels.min(2) - 1]);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 16 bit PCM WAV with the given channels
+    pub(crate) fn write_wav(
+        name: &str,
+        sample_rate: u32,
+        channels: &[Vec<f32>],
+    ) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("neothesia-ai-{}-{name}", std::process::id()));
+
+        let count = channels.len() as u16;
+        let frames = channels[0].len() as u32;
+        let data_len = frames * count as u32 * 2;
+
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(count.to_le_bytes());
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * count as u32 * 2).to_le_bytes());
+        wav.extend((count * 2).to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for frame in 0..frames as usize {
+            for channel in channels {
+                wav.extend(((channel[frame] * i16::MAX as f32) as i16).to_le_bytes());
+            }
+        }
+
+        std::fs::write(&path, wav).unwrap();
+        path
+    }
+
+    fn sine(freq: f32, sample_rate: u32, secs: f32) -> Vec<f32> {
+        let len = (sample_rate as f32 * secs) as usize;
+        (0..len)
+            .map(|i| (i as f32 / sample_rate as f32 * freq * std::f32::consts::TAU).sin() * 0.5)
+            .collect()
+    }
+
+    fn read_all(path: &Path) -> anyhow::Result<Vec<f32>> {
+        let mut stream = AudioStream::open(path)?;
+        let mut samples = Vec::new();
+        let mut buf = [0.0; 4096];
+        loop {
+            let len = stream.read(&mut buf)?;
+            samples.extend_from_slice(&buf[..len]);
+            if len < buf.len() {
+                return Ok(samples);
+            }
+        }
+    }
+
+    #[test]
+    fn downmix_channels() {
+        let mut mono = Vec::new();
+
+        downmix(&[0.5, -0.5], 1, &mut mono);
+        assert_eq!(mono, [0.5, -0.5]);
+
+        downmix(&[1.0, 0.0, 0.0, 1.0], 2, &mut mono);
+        assert_eq!(mono, [0.5, 0.5]);
+
+        // The LFE channel is left out
+        let surround = [0.5, 0.5, 0.5, 1.0, 0.5, 0.5].repeat(4);
+        downmix(&surround, 6, &mut mono);
+        assert_eq!(mono, [0.5; 4]);
+    }
+
+    #[test]
+    fn read_files() {
+        let tone = |sample_rate| sine(440.0, sample_rate, 1.5);
+
+        for (name, sample_rate, channels) in [
+            ("mono.wav", 16000, vec![tone(16000)]),
+            ("stereo.wav", 44100, vec![tone(44100), tone(44100)]),
+            ("surround.wav", 48000, vec![tone(48000); 6]),
+        ] {
+            let path = write_wav(name, sample_rate, &channels);
+            let duration = AudioStream::open(&path).unwrap().duration();
+            let mono = read_all(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
 
-use crate::{SAMPLE_RATE, SEGMENT_SAMPLES};
+            assert_eq!(duration, Some(Duration::from_millis(1500)), "{name}");
+            assert_eq!(mono.len(), SAMPLE_RATE as usize * 3 / 2, "{name}");
 
-pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Vec<f32>> {
-    let probed = symphonium::probe_from_file(path.as_ref(), None)?;
+            // The tone keeps its level
+            let peak = mono[1000..mono.len() - 1000]
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!((peak - 0.5).abs() < 0.02, "{name}");
+        }
+    }
 
-    let mut audio_data_f32 = symphonium::decode_f32(
-        probed,
-        &Default::default(),
-        NonZeroU32::new(SAMPLE_RATE),
-        None,
-        None,
-    )?;
+    #[test]
+    fn read_stereo_and_seek() {
+        // A ramp, so the position of every sample is known
+        let ramp: Vec<f32> = (0..44100).map(|i| i as f32 / 44100.0 * 0.5).collect();
+        let path = write_wav(
+            "seek.wav",
+            44100,
+            &[ramp.clone(), ramp.iter().map(|s| -s).collect()],
+        );
 
-    let left = audio_data_f32.data.remove(0);
-    let right = audio_data_f32.data.remove(0);
+        let mut stream = AudioStream::open_with(&path, 48000, Channels::Stereo).unwrap();
+        let mut buf = [0.0; 512];
+        assert_eq!(stream.read(&mut buf).unwrap(), 512);
 
-    let mut mono: Vec<f32> = left
-        .into_iter()
-        .zip(right)
-        .map(|(l, r)| (l + r) / 2.0)
-        .collect();
+        stream.seek(Duration::from_millis(600)).unwrap();
+        let len = stream.read(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-    let pad_len =
-        (mono.len() as f32 / SEGMENT_SAMPLES as f32).ceil() as usize * SEGMENT_SAMPLES - mono.len();
+        assert_eq!(len, 512);
+        // Left and right keep their own signal
+        assert!((buf[200] + buf[201]).abs() < 1e-3);
+        // The ramp is at 0.6s after the seek
+        assert!((buf[200] - 0.3).abs() < 0.01, "{}", buf[200]);
+    }
 
-    mono.resize(mono.len() + pad_len, 0.0);
+    #[test]
+    fn read_errors() {
+        assert!(AudioStream::open("does-not-exist.wav").is_err());
 
-    Ok(mono)
+        let path =
+            std::env::temp_dir().join(format!("neothesia-ai-{}-bad.wav", std::process::id()));
+        std::fs::write(&path, b"RIFF0000WAVEnot really").unwrap();
+        let err = read_all(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.is_err());
+    }
 }
@@ -0,0 +1,387 @@
+//! Beat tracking on the transcribed notes, after D. Ellis, Beat Tracking by Dynamic Programming,
+//! 2007. The beats give the MIDI file a tempo map, so its bars line up with the music.
+
+use crate::{FRAMES_PER_SECOND, detection::Note};
+
+/// Tempos the tracker considers, in beats per minute
+const MIN_TEMPO: f32 = 40.0;
+const MAX_TEMPO: f32 = 220.0;
+
+/// How strongly the beats keep to the estimated tempo
+const TIGHTNESS: f32 = 100.0;
+
+/// Fewer notes than this don't tell a tempo
+const MIN_NOTES: usize = 8;
+
+/// Beats of a song, in quarter notes. Maps seconds to beats and back.
+#[derive(Debug, Clone)]
+pub struct BeatGrid {
+    /// Seconds of every beat, the first one is at 0
+    beats: Vec<f32>,
+    /// Length of the beats after the last one, in seconds
+    period: f32,
+    /// Beats per bar
+    pub numerator: u8,
+    /// Beats before the first full bar
+    pub pickup: u8,
+}
+
+impl BeatGrid {
+    /// Steady `tempo` in 4/4
+    pub fn constant(tempo: f32) -> Self {
+        Self {
+            beats: vec![0.0],
+            period: 60.0 / tempo,
+            numerator: 4,
+            pickup: 0,
+        }
+    }
+
+    /// Finds the beats and the meter of the notes, tempos near `tempo` are preferred. `None`
+    /// when the notes don't tell a tempo.
+    pub fn track(notes: &[Note], tempo: f32) -> Option<Self> {
+        if notes.len() < MIN_NOTES {
+            return None;
+        }
+
+        let end = notes.iter().map(|note| note.offset).fold(0.0, f32::max);
+        let envelope = onset_envelope(notes, (end * FRAMES_PER_SECOND) as usize + 1);
+
+        let period = estimate_period(&envelope, tempo)?;
+        let beats = track_beats(&envelope, period);
+        if beats.len() < MIN_NOTES / 2 {
+            return None;
+        }
+
+        let period = period / FRAMES_PER_SECOND;
+        let beats = start_at_zero(
+            beats
+                .into_iter()
+                .map(|frame| frame as f32 / FRAMES_PER_SECOND)
+                .collect(),
+            period,
+        );
+        let (numerator, pickup) = estimate_meter(notes, &beats, period);
+
+        Some(Self {
+            beats,
+            period,
+            numerator,
+            pickup,
+        })
+    }
+
+    /// Tempo in beats per minute, the typical one when it changes
+    pub fn tempo(&self) -> f32 {
+        60.0 / self.period
+    }
+
+    /// Beats with a tempo of their own, the tempo doesn't change after the last one
+    pub(crate) fn len(&self) -> usize {
+        self.beats.len()
+    }
+
+    /// Length of beat `id`, in seconds
+    pub fn beat_len(&self, id: usize) -> f32 {
+        match self.beats.get(id..id + 2) {
+            Some([start, end]) => end - start,
+            _ => self.period,
+        }
+    }
+
+    /// Position of `time` in beats
+    pub fn beat_at(&self, time: f32) -> f32 {
+        let id = self
+            .beats
+            .partition_point(|beat| *beat <= time)
+            .saturating_sub(1);
+        id as f32 + (time - self.beats[id]) / self.beat_len(id)
+    }
+
+    /// Time of a position in beats
+    pub fn time_at(&self, beat: f32) -> f32 {
+        let id = (beat.max(0.0) as usize).min(self.beats.len() - 1);
+        self.beats[id] + (beat - id as f32) * self.beat_len(id)
+    }
+
+    /// Snaps the note onsets and offsets to `1 / division` notes, eg. 16 for sixteenths
+    pub fn quantize(&self, notes: &mut [Note], division: u32) {
+        let steps_per_beat = division as f32 / 4.0;
+
+        for note in notes {
+            let onset = (self.beat_at(note.onset) * steps_per_beat).round();
+            let offset = (self.beat_at(note.offset) * steps_per_beat)
+                .round()
+                .max(onset + 1.0);
+
+            note.onset = self.time_at(onset / steps_per_beat);
+            note.offset = self.time_at(offset / steps_per_beat);
+        }
+    }
+}
+
+/// Note onsets as impulses weighted by velocity, blurred by a few frames
+fn onset_envelope(notes: &[Note], frames: usize) -> Vec<f32> {
+    let mut impulses = vec![0.0; frames];
+    for note in notes {
+        let frame = (note.onset * FRAMES_PER_SECOND).round().max(0.0) as usize;
+        if let Some(impulse) = impulses.get_mut(frame) {
+            *impulse += note.velocity as f32 / 127.0;
+        }
+    }
+
+    gaussian_blur(&impulses, 2.0)
+}
+
+fn gaussian_blur(x: &[f32], sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|k| (-0.5 * (k as f32 / sigma).powi(2)).exp())
+        .collect();
+
+    (0..x.len() as isize)
+        .map(|i| {
+            kernel
+                .iter()
+                .zip(-radius..)
+                .filter_map(|(weight, k)| x.get(usize::try_from(i + k).ok()?).map(|v| v * weight))
+                .sum()
+        })
+        .collect()
+}
+
+/// Beat length in frames, the autocorrelation peak of the envelope
+fn estimate_period(envelope: &[f32], tempo: f32) -> Option<f32> {
+    let min_lag = (60.0 * FRAMES_PER_SECOND / MAX_TEMPO) as usize;
+    let max_lag = (60.0 * FRAMES_PER_SECOND / MIN_TEMPO).ceil() as usize;
+    if envelope.len() <= max_lag * 2 {
+        return None;
+    }
+
+    let autocorrelation: Vec<f32> = (0..=max_lag + 1)
+        .map(|lag| {
+            envelope
+                .iter()
+                .zip(&envelope[lag..])
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+
+    // Half and double the tempo fit the music about as well, prefer the one near `tempo`
+    let weighted = |lag: usize| {
+        let bpm = 60.0 * FRAMES_PER_SECOND / lag as f32;
+        autocorrelation[lag] * (-0.5 * (bpm / tempo).log2().powi(2)).exp()
+    };
+    let lag = (min_lag..=max_lag).max_by(|a, b| weighted(*a).total_cmp(&weighted(*b)))?;
+    if autocorrelation[lag] <= 0.0 {
+        return None;
+    }
+
+    // Parabolic interpolation between the lags around the peak
+    let (before, peak, after) = (
+        autocorrelation[lag - 1],
+        autocorrelation[lag],
+        autocorrelation[lag + 1],
+    );
+    let curvature = before - 2.0 * peak + after;
+    let shift = if curvature < 0.0 {
+        (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+
+    Some(lag as f32 + shift)
+}
+
+/// Frames of the beats: the chain of frames with the strongest onsets whose spacing stays close
+/// to `period`
+fn track_beats(envelope: &[f32], period: f32) -> Vec<usize> {
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    let std =
+        (envelope.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / envelope.len() as f32).sqrt();
+    if std <= 0.0 {
+        return Vec::new();
+    }
+    let normalized: Vec<f32> = envelope.iter().map(|v| v / std).collect();
+    let local_score = gaussian_blur(&normalized, period / 32.0);
+
+    let mut score = vec![0.0; local_score.len()];
+    let mut backlink: Vec<Option<usize>> = vec![None; local_score.len()];
+
+    for i in 0..local_score.len() {
+        let first = (i as f32 - 2.0 * period).ceil().max(0.0) as usize;
+        let last = i as f32 - period / 2.0;
+
+        let best = (last >= 0.0)
+            .then(|| {
+                (first..=last as usize)
+                    .map(|prev| {
+                        let penalty = TIGHTNESS * ((i - prev) as f32 / period).ln().powi(2);
+                        (prev, score[prev] - penalty)
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+            })
+            .flatten();
+
+        score[i] = local_score[i] + best.map_or(0.0, |(_, best)| best);
+        backlink[i] = best.map(|(prev, _)| prev);
+    }
+
+    // The last beat is the last strong enough local maximum of the score
+    let maxima: Vec<usize> = (1..score.len().saturating_sub(1))
+        .filter(|i| score[*i] > score[i - 1] && score[*i] >= score[i + 1])
+        .collect();
+    let mut sorted: Vec<f32> = maxima.iter().map(|i| score[*i]).collect();
+    sorted.sort_by(f32::total_cmp);
+    let Some(median) = sorted.get(sorted.len() / 2) else {
+        return Vec::new();
+    };
+    let Some(mut beat) = maxima
+        .iter()
+        .rev()
+        .find(|i| score[**i] >= median * 0.5)
+        .copied()
+    else {
+        return Vec::new();
+    };
+
+    let mut beats = vec![beat];
+    while let Some(prev) = backlink[beat] {
+        beats.push(prev);
+        beat = prev;
+    }
+    beats.reverse();
+    beats
+}
+
+/// Extends the beats back to the start of the song, the first beat lands on 0
+fn start_at_zero(beats: Vec<f32>, period: f32) -> Vec<f32> {
+    let mut first = beats[0];
+    let mut before = Vec::new();
+    while first - period > 0.0 {
+        first -= period;
+        before.push(first);
+    }
+    before.reverse();
+    before.extend(beats);
+
+    // The time before the first beat is a beat of its own, or joins the first one
+    if before[0] >= period / 2.0 {
+        before.insert(0, 0.0);
+    } else {
+        before[0] = 0.0;
+    }
+    before
+}
+
+/// Beats per bar and the beats before the first bar. Downbeats are the accented beats, loud and
+/// with bass notes.
+fn estimate_meter(notes: &[Note], beats: &[f32], period: f32) -> (u8, u8) {
+    let mut accents = vec![0.0; beats.len()];
+    for note in notes {
+        let id = beats.partition_point(|beat| *beat < note.onset);
+        let nearest = [id.checked_sub(1), Some(id)]
+            .into_iter()
+            .flatten()
+            .filter(|id| *id < beats.len())
+            .min_by(|a, b| {
+                (beats[*a] - note.onset)
+                    .abs()
+                    .total_cmp(&(beats[*b] - note.onset).abs())
+            });
+
+        if let Some(id) = nearest
+            && (beats[id] - note.onset).abs() < period / 4.0
+        {
+            let bass = if note.key < 55 { 2.0 } else { 1.0 };
+            accents[id] += note.velocity as f32 * bass;
+        }
+    }
+
+    // The accents of the strongest phase, for bars of `numerator` beats
+    let strongest = |numerator: usize| {
+        (0..numerator)
+            .map(|phase| {
+                let bar_starts: Vec<f32> = accents
+                    .iter()
+                    .skip(phase)
+                    .step_by(numerator)
+                    .copied()
+                    .collect();
+                let mean = bar_starts.iter().sum::<f32>() / bar_starts.len().max(1) as f32;
+                (mean, phase as u8)
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap()
+    };
+
+    let (triple, triple_phase) = strongest(3);
+    let (quadruple, quadruple_phase) = strongest(4);
+
+    // Most music is in 4, 3 has to be clearly stronger
+    if triple > quadruple * 1.1 {
+        (3, triple_phase)
+    } else {
+        (4, quadruple_phase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(key: u8, onset: f32, velocity: u8) -> Note {
+        Note {
+            key,
+            onset,
+            offset: onset + 0.3,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn track_waltz() {
+        // 100 BPM in 3/4, the first downbeat at 0.5s, a melody note on every beat and a bass
+        // note on the downbeats
+        let notes: Vec<Note> = (0..60)
+            .flat_map(|beat| {
+                let onset = 0.5 + beat as f32 * 0.6;
+                let bass = (beat % 3 == 0).then(|| note(40, onset, 100));
+                std::iter::once(note(72, onset, 60)).chain(bass)
+            })
+            .collect();
+
+        let grid = BeatGrid::track(&notes, 120.0).unwrap();
+
+        assert_eq!(grid.numerator, 3);
+        assert!((grid.beat_len(10) - 0.6).abs() < 0.02, "{grid:?}");
+
+        // Every note lands on a beat, the bass notes on the first beat of a bar
+        for note in notes.iter() {
+            let beat = grid.beat_at(note.onset);
+            assert!((beat - beat.round()).abs() < 0.1, "{beat}");
+
+            let bar_beat = (beat.round() as i32 - grid.pickup as i32).rem_euclid(3);
+            if note.key == 40 {
+                assert_eq!(bar_beat, 0, "{beat}");
+            }
+        }
+
+        assert!(BeatGrid::track(&notes[..4], 120.0).is_none());
+    }
+
+    #[test]
+    fn quantize() {
+        let grid = BeatGrid::constant(120.0);
+
+        let mut notes = [note(60, 0.51, 80), note(62, 1.02, 80)];
+        notes[0].offset = 0.74;
+        notes[1].offset = 1.04;
+        grid.quantize(&mut notes, 16);
+
+        assert_eq!((notes[0].onset, notes[0].offset), (0.5, 0.75));
+        // Notes keep at least one step
+        assert_eq!((notes[1].onset, notes[1].offset), (1.0, 1.125));
+    }
+}
@@ -0,0 +1,643 @@
+//! Notes and pedals are detected frame by frame, as the model outputs come in. A frame is only
+//! looked at once the frames around it are known.
+
+use ndarray::ArrayView1;
+
+use crate::{FRAMES_PER_SECOND, model::Frames};
+
+const ONSET_NEIGHBOUR: usize = 2;
+const OFFSET_NEIGHBOUR: usize = 4;
+
+const PEDAL_ONSET_THRESHOLD: f32 = 0.3;
+const PEDAL_OFFSET_THRESHOLD: f32 = 0.2;
+const PEDAL_FRAME_THRESHOLD: f32 = 0.5;
+
+/// Frames needed on each side of a frame before it is looked at
+const CONTEXT: usize = OFFSET_NEIGHBOUR;
+
+/// Notes longer than this many frames are cut
+const MAX_NOTE_FRAMES: usize = 600;
+
+/// Note detection thresholds, on model outputs from 0 to 1
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub onset: f32,
+    pub offset: f32,
+    /// A note is released once its frame output drops to this
+    pub frame: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            onset: 0.3,
+            offset: 0.2,
+            frame: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub key: u8,
+    /// Seconds
+    pub onset: f32,
+    pub offset: f32,
+    pub velocity: u8,
+}
+
+/// Sustain pedal press, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pedal {
+    pub onset: f32,
+    pub offset: f32,
+}
+
+/// Notes and pedals found in the frames pushed so far
+#[derive(Debug, Default)]
+pub struct Detected {
+    pub notes: Vec<Note>,
+    pub pedals: Vec<Pedal>,
+}
+
+impl Detected {
+    /// Drops the notes shorter than `min_length` seconds
+    pub fn drop_short_notes(mut self, min_length: f32) -> Self {
+        self.notes
+            .retain(|note| note.offset - note.onset >= min_length);
+        self
+    }
+}
+
+/// Onset and offset frames, with their regressed shifts
+struct Span {
+    bgn: usize,
+    fin: usize,
+    bgn_shift: f32,
+    fin_shift: f32,
+}
+
+impl Span {
+    fn onset(&self) -> f32 {
+        (self.bgn as f32 + self.bgn_shift) / FRAMES_PER_SECOND
+    }
+
+    fn offset(&self) -> f32 {
+        (self.fin as f32 + self.fin_shift) / FRAMES_PER_SECOND
+    }
+}
+
+pub struct Detector {
+    thresholds: Thresholds,
+    /// Frames not looked at yet, and the few before them
+    buffer: Option<Frames>,
+    /// Frame id of the first buffered frame
+    buffer_start: usize,
+    /// Frame id of the next frame to look at
+    next: usize,
+    keys: Vec<NoteTracker>,
+    pedal: PedalTracker,
+}
+
+impl Detector {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self {
+            thresholds,
+            buffer: None,
+            buffer_start: 0,
+            next: 0,
+            keys: Vec::new(),
+            pedal: PedalTracker::default(),
+        }
+    }
+
+    /// Adds the frames that follow, returns what they complete
+    pub fn push(&mut self, frames: Frames) -> Detected {
+        match &mut self.buffer {
+            Some(buffer) => buffer.append(frames),
+            None => self.buffer = Some(frames),
+        }
+
+        let mut detected = Detected::default();
+        while self.next + CONTEXT < self.buffer_end() {
+            self.step(None, &mut detected);
+        }
+
+        // Keep the frames that are still context
+        let unused = self.next.saturating_sub(CONTEXT) - self.buffer_start;
+        if let Some(buffer) = &mut self.buffer {
+            buffer.drop_front(unused);
+        }
+        self.buffer_start += unused;
+
+        detected
+    }
+
+    /// The audio is done, returns the rest
+    pub fn finish(mut self) -> Detected {
+        let mut detected = Detected::default();
+
+        let total = self.buffer_end();
+        while self.next < total {
+            self.step(Some(total), &mut detected);
+        }
+
+        if let Some(span) = self.pedal.finish(total) {
+            detected.pedals.push(Pedal {
+                onset: span.onset(),
+                offset: span.offset(),
+            });
+        }
+
+        detected
+    }
+
+    fn buffer_end(&self) -> usize {
+        self.buffer_start + self.buffer.as_ref().map_or(0, Frames::len)
+    }
+
+    /// Looks at the next frame, `total` is the frame count once the audio is done
+    fn step(&mut self, total: Option<usize>, detected: &mut Detected) {
+        let Some(buffer) = &self.buffer else {
+            return;
+        };
+
+        let n = self.next;
+        let id = n - self.buffer_start;
+        let is_last = total.is_some_and(|total| n + 1 == total);
+
+        // Peaks are only searched where all the neighbours are known
+        let peak = |x: ArrayView1<f32>, threshold: f32, neighbour: usize| {
+            let known = n >= neighbour && total.is_none_or(|total| n + neighbour < total);
+            known
+                .then(|| regression_peak(&x, id, threshold, neighbour))
+                .flatten()
+        };
+
+        let thresholds = self.thresholds;
+        let classes = buffer.frame.ncols();
+        self.keys.resize_with(classes, NoteTracker::default);
+
+        for (key, tracker) in self.keys.iter_mut().enumerate() {
+            let values = FrameValues {
+                frame: buffer.frame[[id, key]],
+                onset: peak(
+                    buffer.reg_onset.column(key),
+                    thresholds.onset,
+                    ONSET_NEIGHBOUR,
+                ),
+                offset: peak(
+                    buffer.reg_offset.column(key),
+                    thresholds.offset,
+                    OFFSET_NEIGHBOUR,
+                ),
+                velocity: buffer.velocity[[id, key]],
+            };
+
+            if let Some((span, velocity)) = tracker.step(n, &values, thresholds.frame, is_last) {
+                detected.notes.push(Note {
+                    // 21 is the first note in 88 keys layout
+                    key: key as u8 + 21,
+                    onset: span.onset(),
+                    offset: span.offset(),
+                    velocity: midi_velocity(velocity),
+                });
+            }
+        }
+
+        // The pedal outputs have a single class
+        let values = FrameValues {
+            frame: buffer.pedal_frame[[id, 0]],
+            onset: peak(
+                buffer.reg_pedal_onset.column(0),
+                PEDAL_ONSET_THRESHOLD,
+                ONSET_NEIGHBOUR,
+            ),
+            offset: peak(
+                buffer.reg_pedal_offset.column(0),
+                PEDAL_OFFSET_THRESHOLD,
+                OFFSET_NEIGHBOUR,
+            ),
+            velocity: 0.0,
+        };
+        if let Some(span) = self.pedal.step(n, &values) {
+            detected.pedals.push(Pedal {
+                onset: span.onset(),
+                offset: span.offset(),
+            });
+        }
+
+        self.next += 1;
+    }
+}
+
+/// The model outputs velocities scaled to 0..1
+fn midi_velocity(velocity: f32) -> u8 {
+    (velocity * 128.0).clamp(1.0, 127.0) as u8
+}
+
+/// Shift of the onset or offset regressed at frame `n`, if there is one
+fn regression_peak(x: &ArrayView1<f32>, n: usize, threshold: f32, neighbour: usize) -> Option<f32> {
+    if x[n] <= threshold || !is_monotonic_neighbour(x, n, neighbour) {
+        return None;
+    }
+
+    // See Section III-D in [1] for deduction.
+    // [1] Q. Kong, et al., High-resolution Piano Transcription
+    // with Pedals by Regressing Onsets and Offsets Times, 2020.
+    let denominator = if x[n - 1] > x[n + 1] {
+        x[n] - x[n + 1]
+    } else {
+        x[n] - x[n - 1]
+    };
+    // A flat peak, eg. saturated at 1.0, is centered on its frame
+    if denominator == 0.0 {
+        return Some(0.0);
+    }
+    Some((x[n + 1] - x[n - 1]) / denominator / 2.0)
+}
+
+fn is_monotonic_neighbour(x: &ArrayView1<f32>, n: usize, neighbour: usize) -> bool {
+    debug_assert!(n >= neighbour && n + neighbour < x.len());
+
+    for i in 0..neighbour {
+        if x[n - i] < x[n - i - 1] {
+            return false;
+        }
+        if x[n + i] < x[n + i + 1] {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Outputs of one class at one frame
+struct FrameValues {
+    frame: f32,
+    /// Shift of the onset, when one is regressed at this frame
+    onset: Option<f32>,
+    offset: Option<f32>,
+    velocity: f32,
+}
+
+/// Finds the notes of one key
+#[derive(Default)]
+struct NoteTracker {
+    /// Onset frame, its shift and the velocity at the onset
+    bgn: Option<(usize, f32, f32)>,
+    frame_disappear: Option<(usize, f32)>,
+    offset_occur: Option<(usize, f32)>,
+}
+
+impl NoteTracker {
+    /// Returns the note ended by frame `i`, with its velocity
+    fn step(
+        &mut self,
+        i: usize,
+        values: &FrameValues,
+        frame_threshold: f32,
+        is_last: bool,
+    ) -> Option<(Span, f32)> {
+        let mut note = None;
+
+        if let Some(onset_shift) = values.onset {
+            // Onset detected
+            if let Some((bgn, bgn_shift, velocity)) = self.bgn {
+                // Consecutive onsets. E.g., pedal is not released, but two
+                // consecutive notes being played.
+                let span = Span {
+                    bgn,
+                    fin: i.saturating_sub(1),
+                    bgn_shift,
+                    fin_shift: 0.0,
+                };
+                note = Some((span, velocity));
+
+                self.frame_disappear = None;
+                self.offset_occur = None;
+            }
+
+            self.bgn = Some((i, onset_shift, values.velocity));
+        }
+
+        let (bgn, bgn_shift, velocity) = self.bgn?;
+        if i == bgn {
+            return note;
+        }
+
+        // If onset found, then search offset
+        let offset_shift = values.offset.unwrap_or(0.0);
+
+        if values.frame <= frame_threshold && self.frame_disappear.is_none() {
+            // Frame disappear detected
+            self.frame_disappear = Some((i, offset_shift));
+        }
+
+        if values.offset.is_some() && self.offset_occur.is_none() {
+            // Offset detected
+            self.offset_occur = Some((i, offset_shift));
+        }
+
+        let fin = if let Some((frame_disappear, frame_disappear_shift)) = self.frame_disappear {
+            match self.offset_occur {
+                Some((offset_occur, shift))
+                    if offset_occur - bgn > frame_disappear - offset_occur =>
+                {
+                    // bgn --------- offset_occur --- frame_disappear
+                    (offset_occur, shift)
+                }
+                _ => {
+                    // bgn --- offset_occur --------- frame_disappear
+                    (frame_disappear, frame_disappear_shift)
+                }
+            }
+        } else if i - bgn >= MAX_NOTE_FRAMES || is_last {
+            // Offset not detected
+            (i, offset_shift)
+        } else {
+            return note;
+        };
+
+        self.bgn = None;
+        self.frame_disappear = None;
+        self.offset_occur = None;
+
+        let span = Span {
+            bgn,
+            fin: fin.0,
+            bgn_shift,
+            fin_shift: fin.1,
+        };
+        Some((span, velocity))
+    }
+}
+
+/// Finds the sustain pedal presses
+#[derive(Default)]
+struct PedalTracker {
+    previous_frame: Option<f32>,
+    bgn: Option<(usize, f32)>,
+    frame_disappear: Option<(usize, f32)>,
+    offset_occur: Option<(usize, f32)>,
+}
+
+impl PedalTracker {
+    /// Returns the press ended by frame `i`
+    fn step(&mut self, i: usize, values: &FrameValues) -> Option<Span> {
+        let previous_frame = self.previous_frame.replace(values.frame)?;
+
+        if self.bgn.is_none() {
+            if let Some(onset_shift) = values.onset {
+                // Pedal onset regressed
+                self.bgn = Some((i, onset_shift));
+            } else if values.frame >= PEDAL_FRAME_THRESHOLD && values.frame > previous_frame {
+                // Pedal onset detected by the frame output rising
+                self.bgn = Some((i, 0.0));
+            }
+        }
+
+        let (bgn, bgn_shift) = self.bgn?;
+        if i == bgn {
+            return None;
+        }
+
+        let offset_shift = values.offset.unwrap_or(0.0);
+
+        if values.frame <= PEDAL_FRAME_THRESHOLD && self.frame_disappear.is_none() {
+            self.frame_disappear = Some((i, offset_shift));
+        }
+
+        if values.offset.is_some() && self.offset_occur.is_none() {
+            self.offset_occur = Some((i, offset_shift));
+        }
+
+        let (fin, fin_shift) = match (self.offset_occur, self.frame_disappear) {
+            (Some(offset_occur), _) => offset_occur,
+            // Offset not detected, but the frame is gone for long enough
+            (None, Some(frame_disappear)) if i - frame_disappear.0 >= 10 => frame_disappear,
+            _ => return None,
+        };
+
+        self.bgn = None;
+        self.frame_disappear = None;
+        self.offset_occur = None;
+
+        Some(Span {
+            bgn,
+            fin,
+            bgn_shift,
+            fin_shift,
+        })
+    }
+
+    /// Pedal held until the end
+    fn finish(&mut self, total: usize) -> Option<Span> {
+        let (bgn, bgn_shift) = self.bgn.take()?;
+        Some(Span {
+            bgn,
+            fin: total - 1,
+            bgn_shift,
+            fin_shift: 0.0,
+        })
+    }
+}
+
+/// Notes released while the pedal is down keep sounding until the pedal is released, or the
+/// same key is struck again
+pub fn extend_notes_under_pedal(notes: &mut [Note], pedals: &[Pedal]) {
+    notes.sort_by(|a, b| a.key.cmp(&b.key).then(a.onset.total_cmp(&b.onset)));
+
+    for i in 0..notes.len() {
+        let Note { key, offset, .. } = notes[i];
+
+        let Some(pedal) = pedals
+            .iter()
+            .find(|pedal| (pedal.onset..pedal.offset).contains(&offset))
+        else {
+            continue;
+        };
+
+        let next_onset = notes
+            .get(i + 1)
+            .filter(|next| next.key == key)
+            .map_or(f32::MAX, |next| next.onset);
+
+        notes[i].offset = pedal.offset.min(next_onset).max(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array2, s};
+
+    /// A note on the first key from 1s to 2s, and the pedal down from 0.5s to 2.5s
+    fn frames(len: usize) -> Frames {
+        let mut frames = Frames {
+            reg_onset: Array2::zeros((len, 88)),
+            reg_offset: Array2::zeros((len, 88)),
+            frame: Array2::zeros((len, 88)),
+            velocity: Array2::from_elem((len, 88), 0.5),
+            reg_pedal_onset: Array2::zeros((len, 1)),
+            reg_pedal_offset: Array2::zeros((len, 1)),
+            pedal_frame: Array2::zeros((len, 1)),
+        };
+
+        let peak = [0.2, 0.5, 1.0, 0.5, 0.2];
+        for (i, value) in peak.into_iter().enumerate() {
+            frames.reg_onset[[98 + i, 0]] = value;
+            frames.reg_offset[[198 + i, 0]] = value;
+        }
+        for i in 100..200 {
+            frames.frame[[i, 0]] = 1.0;
+        }
+        for i in 50..250 {
+            frames.pedal_frame[[i, 0]] = 1.0;
+        }
+
+        frames
+    }
+
+    fn slice(frames: &Frames, range: std::ops::Range<usize>) -> Frames {
+        let slice = |output: &Array2<f32>| output.slice(s![range.clone(), ..]).to_owned();
+        Frames {
+            reg_onset: slice(&frames.reg_onset),
+            reg_offset: slice(&frames.reg_offset),
+            frame: slice(&frames.frame),
+            velocity: slice(&frames.velocity),
+            reg_pedal_onset: slice(&frames.reg_pedal_onset),
+            reg_pedal_offset: slice(&frames.reg_pedal_offset),
+            pedal_frame: slice(&frames.pedal_frame),
+        }
+    }
+
+    #[test]
+    fn detect_in_chunks() {
+        let whole = {
+            let mut detector = Detector::new(Thresholds::default());
+            let mut detected = detector.push(frames(400));
+            let rest = detector.finish();
+            detected.notes.extend(rest.notes);
+            detected.pedals.extend(rest.pedals);
+            detected
+        };
+
+        assert_eq!(
+            whole.notes,
+            [Note {
+                key: 21,
+                onset: 1.0,
+                offset: 2.0,
+                velocity: 64,
+            }]
+        );
+        assert_eq!(whole.pedals.len(), 1);
+        assert!((whole.pedals[0].onset - 0.5).abs() < 0.01);
+        assert!((whole.pedals[0].offset - 2.5).abs() < 0.01);
+
+        // Chunks split right around the onset and the offset find the same
+        for at in [97, 100, 102, 199, 201] {
+            let mut detector = Detector::new(Thresholds::default());
+            let frames = frames(400);
+            let mut detected = detector.push(slice(&frames, 0..at));
+            let tail = detector.push(slice(&frames, at..400));
+            for more in [tail, detector.finish()] {
+                detected.notes.extend(more.notes);
+                detected.pedals.extend(more.pedals);
+            }
+
+            assert_eq!(detected.notes, whole.notes, "{at}");
+            assert_eq!(detected.pedals, whole.pedals, "{at}");
+        }
+    }
+
+    #[test]
+    fn sustain_pedal_to_midi() {
+        let mut detector = Detector::new(Thresholds::default());
+        let mut detected = detector.push(frames(400));
+        let rest = detector.finish();
+        detected.notes.extend(rest.notes);
+        detected.pedals.extend(rest.pedals);
+
+        let grid = crate::beats::BeatGrid::constant(120.0);
+        let smf = crate::midi::create_midi_file(&detected.notes, &detected.pedals, &grid);
+
+        let mut ticks = 0;
+        let mut events = Vec::new();
+        for event in &smf.tracks[1] {
+            ticks += event.delta.as_int();
+            if let midly::TrackEventKind::Midi { message, .. } = event.kind {
+                events.push((ticks, message));
+            }
+        }
+
+        let pedal: Vec<_> = events
+            .iter()
+            .filter_map(|(ticks, message)| match message {
+                midly::MidiMessage::Controller { controller, value } => {
+                    Some((*ticks, controller.as_int(), value.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        // Down at 0.5s and up at 2.5s, 384 ticks per beat at 120 BPM
+        assert_eq!(pedal.len(), 2);
+        assert_eq!((pedal[0].1, pedal[0].2), (64, 127));
+        assert_eq!((pedal[1].1, pedal[1].2), (64, 0));
+        assert!(pedal[0].0.abs_diff(384) <= 4, "{}", pedal[0].0);
+        assert!(pedal[1].0.abs_diff(1920) <= 4, "{}", pedal[1].0);
+
+        // The note sounds while the pedal is down
+        let note: Vec<_> = events
+            .iter()
+            .filter(|(_, message)| matches!(message, midly::MidiMessage::NoteOn { .. }))
+            .map(|(ticks, _)| *ticks)
+            .collect();
+        assert_eq!(note, [768, 1536]);
+    }
+
+    #[test]
+    fn notes_extended_under_pedal() {
+        let note = |key, onset, offset| Note {
+            key,
+            onset,
+            offset,
+            velocity: 64,
+        };
+        let pedals = [Pedal {
+            onset: 0.5,
+            offset: 2.5,
+        }];
+        let mut notes = [
+            // Released under the pedal, until the key is struck again
+            note(60, 2.0, 2.2),
+            note(60, 1.0, 1.5),
+            // Released under the pedal
+            note(62, 1.0, 1.2),
+            // Released before the pedal went down
+            note(64, 0.0, 0.3),
+            // Released after the pedal went up
+            note(65, 1.0, 3.0),
+        ];
+
+        extend_notes_under_pedal(&mut notes, &pedals);
+
+        assert_eq!(
+            notes,
+            [
+                note(60, 1.0, 2.0),
+                note(60, 2.0, 2.5),
+                note(62, 1.0, 2.5),
+                note(64, 0.0, 0.3),
+                note(65, 1.0, 3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn flat_peak() {
+        let saturated = ndarray::arr1(&[0.5, 1.0, 1.0, 1.0, 0.5]);
+        assert_eq!(regression_peak(&saturated.view(), 2, 0.3, 1), Some(0.0));
+    }
+}
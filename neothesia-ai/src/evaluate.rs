@@ -0,0 +1,344 @@
+//! Note-level accuracy of a transcription against a reference MIDI file, with the usual criteria
+//! of piano transcription papers (mir_eval): a transcribed note matches a reference note of the
+//! same key when their onsets are within 50ms. With offsets, the offsets also have to be within
+//! 20% of the reference note length, or 50ms for short notes. Every note matches at most once.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use midi_file::MidiNote;
+use serde::Serialize;
+
+/// Drums have no pitch, they are left out
+const DRUM_CHANNEL: u8 = 9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerances {
+    pub onset: Duration,
+    /// Of the reference note length
+    pub offset_ratio: f32,
+    /// Offsets of short notes may be this far apart
+    pub min_offset: Duration,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        Self {
+            onset: Duration::from_millis(50),
+            offset_ratio: 0.2,
+            min_offset: Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Scores {
+    pub matched: usize,
+    pub precision: f32,
+    pub recall: f32,
+    pub f1: f32,
+}
+
+impl Scores {
+    fn new(matched: usize, reference: usize, transcribed: usize) -> Self {
+        let ratio = |total: usize| {
+            if total == 0 {
+                0.0
+            } else {
+                matched as f32 / total as f32
+            }
+        };
+        let precision = ratio(transcribed);
+        let recall = ratio(reference);
+        let f1 = if precision + recall > 0.0 {
+            2.0 * precision * recall / (precision + recall)
+        } else {
+            0.0
+        };
+
+        Self {
+            matched,
+            precision,
+            recall,
+            f1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub reference_notes: usize,
+    pub transcribed_notes: usize,
+    /// Notes matched by onset
+    pub onset: Scores,
+    /// Notes matched by onset and offset
+    pub onset_offset: Scores,
+    /// Mean absolute velocity difference of the notes matched by onset, in MIDI velocity steps
+    pub velocity_error: Option<f32>,
+}
+
+/// Notes of every track, drums left out
+pub fn notes(midi: &midi_file::MidiFile) -> Vec<MidiNote> {
+    midi.tracks
+        .iter()
+        .flat_map(|track| track.notes.iter())
+        .filter(|note| note.channel != DRUM_CHANNEL)
+        .cloned()
+        .collect()
+}
+
+pub fn evaluate(
+    reference: &[MidiNote],
+    transcribed: &[MidiNote],
+    tolerances: &Tolerances,
+) -> Report {
+    let onset_matches = matches(reference, transcribed, tolerances, false);
+    let onset_offset_matches = matches(reference, transcribed, tolerances, true);
+
+    let velocity_error = (!onset_matches.is_empty()).then(|| {
+        let total: u32 = onset_matches
+            .iter()
+            .map(|(r, t)| reference[*r].velocity.abs_diff(transcribed[*t].velocity) as u32)
+            .sum();
+        total as f32 / onset_matches.len() as f32
+    });
+
+    Report {
+        reference_notes: reference.len(),
+        transcribed_notes: transcribed.len(),
+        onset: Scores::new(onset_matches.len(), reference.len(), transcribed.len()),
+        onset_offset: Scores::new(
+            onset_offset_matches.len(),
+            reference.len(),
+            transcribed.len(),
+        ),
+        velocity_error,
+    }
+}
+
+/// Pairs of reference and transcribed note ids, as many as possible. Notes are only compared with
+/// notes of the same key and close onsets.
+fn matches(
+    reference: &[MidiNote],
+    transcribed: &[MidiNote],
+    tolerances: &Tolerances,
+    with_offsets: bool,
+) -> Vec<(usize, usize)> {
+    // Note ids of every key, reference then transcribed, sorted by onset
+    let mut keys: BTreeMap<u8, (Vec<usize>, Vec<usize>)> = BTreeMap::new();
+    for (id, note) in reference.iter().enumerate() {
+        keys.entry(note.note).or_default().0.push(id);
+    }
+    for (id, note) in transcribed.iter().enumerate() {
+        keys.entry(note.note).or_default().1.push(id);
+    }
+
+    let mut pairs = Vec::new();
+    for (mut reference_ids, mut transcribed_ids) in keys.into_values() {
+        reference_ids.sort_by_key(|id| reference[*id].start);
+        transcribed_ids.sort_by_key(|id| transcribed[*id].start);
+
+        let edges: Vec<Vec<usize>> = reference_ids
+            .iter()
+            .map(|r| {
+                let reference = &reference[*r];
+                let earliest = reference.start.saturating_sub(tolerances.onset);
+                let latest = reference.start + tolerances.onset;
+                let first = transcribed_ids.partition_point(|t| transcribed[*t].start < earliest);
+                let last = transcribed_ids.partition_point(|t| transcribed[*t].start <= latest);
+
+                let offset_tolerance = reference
+                    .duration
+                    .mul_f32(tolerances.offset_ratio)
+                    .max(tolerances.min_offset);
+
+                let mut candidates: Vec<usize> = (first..last)
+                    .filter(|t| {
+                        !with_offsets
+                            || reference.end.abs_diff(transcribed[transcribed_ids[*t]].end)
+                                <= offset_tolerance
+                    })
+                    .collect();
+                // Closest onsets are tried first
+                candidates.sort_by_key(|t| {
+                    reference
+                        .start
+                        .abs_diff(transcribed[transcribed_ids[*t]].start)
+                });
+                candidates
+            })
+            .collect();
+
+        let matched = maximum_matching(&edges, transcribed_ids.len());
+        pairs.extend(
+            matched
+                .iter()
+                .enumerate()
+                .filter_map(|(r, t)| Some((reference_ids[r], transcribed_ids[(*t)?]))),
+        );
+    }
+    pairs
+}
+
+/// Maximum matching of a bipartite graph with Hopcroft-Karp. `edges` lists the right vertices of
+/// every left vertex, the right vertex of every left vertex is returned.
+fn maximum_matching(edges: &[Vec<usize>], right_len: usize) -> Vec<Option<usize>> {
+    struct Graph<'a> {
+        edges: &'a [Vec<usize>],
+        left: Vec<Option<usize>>,
+        right: Vec<Option<usize>>,
+        /// BFS layer of every left vertex, `usize::MAX` when unreachable
+        layer: Vec<usize>,
+    }
+
+    impl Graph<'_> {
+        /// Layers the left vertices by their distance to the free ones. False when no augmenting
+        /// path is left.
+        fn layer(&mut self) -> bool {
+            let mut queue = VecDeque::new();
+            for (l, matched) in self.left.iter().enumerate() {
+                if matched.is_none() {
+                    self.layer[l] = 0;
+                    queue.push_back(l);
+                } else {
+                    self.layer[l] = usize::MAX;
+                }
+            }
+
+            let mut found = false;
+            while let Some(l) = queue.pop_front() {
+                for &r in &self.edges[l] {
+                    match self.right[r] {
+                        None => found = true,
+                        Some(next) if self.layer[next] == usize::MAX => {
+                            self.layer[next] = self.layer[l] + 1;
+                            queue.push_back(next);
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+            found
+        }
+
+        /// Looks for an augmenting path from `l` along the layers and flips it
+        fn augment(&mut self, l: usize) -> bool {
+            for id in 0..self.edges[l].len() {
+                let r = self.edges[l][id];
+                let free = match self.right[r] {
+                    None => true,
+                    Some(next) => self.layer[next] == self.layer[l] + 1 && self.augment(next),
+                };
+                if free {
+                    self.left[l] = Some(r);
+                    self.right[r] = Some(l);
+                    return true;
+                }
+            }
+            self.layer[l] = usize::MAX;
+            false
+        }
+    }
+
+    let mut graph = Graph {
+        edges,
+        left: vec![None; edges.len()],
+        right: vec![None; right_len],
+        layer: vec![0; edges.len()],
+    };
+
+    while graph.layer() {
+        for l in 0..edges.len() {
+            if graph.left[l].is_none() {
+                graph.augment(l);
+            }
+        }
+    }
+    graph.left
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{beats::BeatGrid, detection::Note};
+
+    /// Notes as they come back from a MIDI file written by the transcription
+    fn midi_notes(fixture: &[Note]) -> Vec<MidiNote> {
+        let smf = crate::midi::create_midi_file(fixture, &[], &BeatGrid::constant(120.0));
+        notes(&midi_file::MidiFile::from_smf("test", &smf).unwrap())
+    }
+
+    fn note(key: u8, onset: f32, offset: f32, velocity: u8) -> Note {
+        Note {
+            key,
+            onset,
+            offset,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn perfect() {
+        let reference = midi_notes(&[note(60, 0.0, 0.5, 80), note(64, 0.5, 1.0, 90)]);
+        let report = evaluate(&reference, &reference, &Tolerances::default());
+
+        assert_eq!(report.onset.f1, 1.0);
+        assert_eq!(report.onset_offset.f1, 1.0);
+        assert_eq!(report.velocity_error, Some(0.0));
+    }
+
+    #[test]
+    fn mistakes() {
+        let reference = midi_notes(&[
+            note(60, 0.0, 1.0, 80),
+            note(62, 1.0, 2.0, 80),
+            note(64, 2.0, 3.0, 80),
+            note(65, 3.0, 4.0, 80),
+        ]);
+        let transcribed = midi_notes(&[
+            // Late within the tolerance, released early
+            note(60, 0.03, 0.5, 70),
+            // Matches
+            note(62, 1.0, 2.1, 90),
+            // Wrong key
+            note(63, 2.0, 3.0, 80),
+            // Too late
+            note(65, 3.1, 4.0, 80),
+            // Extra note
+            note(70, 3.0, 3.5, 80),
+        ]);
+
+        let report = evaluate(&reference, &transcribed, &Tolerances::default());
+
+        assert_eq!(report.onset.matched, 2);
+        assert_eq!(report.onset.precision, 0.4);
+        assert_eq!(report.onset.recall, 0.5);
+        assert!((report.onset.f1 - 0.4 / 0.9).abs() < 1e-6);
+        assert_eq!(report.onset_offset.matched, 1);
+        assert_eq!(report.velocity_error, Some(10.0));
+    }
+
+    #[test]
+    fn each_note_matches_once() {
+        let reference = midi_notes(&[note(60, 1.0, 1.2, 80)]);
+        let transcribed = midi_notes(&[note(60, 0.98, 1.0, 80), note(60, 1.01, 1.2, 80)]);
+
+        let report = evaluate(&reference, &transcribed, &Tolerances::default());
+        assert_eq!(report.onset.matched, 1);
+        assert_eq!(report.onset.precision, 0.5);
+        // Only the second offset matches
+        assert_eq!(report.onset_offset.matched, 1);
+    }
+
+    #[test]
+    fn matches_as_many_as_possible() {
+        let reference = midi_notes(&[note(60, 0.0, 0.05, 80), note(60, 0.07, 0.1, 80)]);
+        // Pairing the closest onsets first would leave both late notes unmatched
+        let transcribed = midi_notes(&[note(60, 0.04, 0.06, 80), note(60, 0.11, 0.15, 80)]);
+
+        let report = evaluate(&reference, &transcribed, &Tolerances::default());
+        assert_eq!(report.onset.matched, 2);
+    }
+}
@@ -0,0 +1,187 @@
+//! Transcription of piano recordings to MIDI, for the `neothesia-ai` command line tool and the
+//! audio import of Neothesia.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+
+pub use rten::Model;
+
+pub mod beats;
+pub mod detection;
+pub mod evaluate;
+pub mod live;
+pub mod midi;
+
+pub mod audio;
+mod model;
+
+use beats::BeatGrid;
+use detection::{Note, Pedal, Thresholds};
+
+const FRAMES_PER_SECOND: f32 = 100.0;
+const SAMPLE_RATE: u32 = 16000;
+const SEGMENT_SAMPLES: usize = SAMPLE_RATE as usize * 10;
+
+/// Extensions of the recordings that can be transcribed
+pub const AUDIO_EXTENSIONS: [&str; 4] = ["wav", "flac", "mp3", "ogg"];
+
+/// How audio is turned into MIDI
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub thresholds: Thresholds,
+    /// Tempo of the MIDI file, or the expected tempo when tracking beats, in beats per minute
+    pub tempo: f32,
+    /// Find the beats of the music for the tempo map and time signature
+    pub track_beats: bool,
+    /// Snap notes to `1 / quantize` notes
+    pub quantize: Option<u32>,
+    /// Notes shorter than this are dropped, in seconds
+    pub min_note_length: f32,
+    /// Extend notes released under the sustain pedal until the pedal is released
+    pub extend_notes: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            thresholds: Thresholds::default(),
+            tempo: 120.0,
+            track_beats: false,
+            quantize: None,
+            min_note_length: 0.0,
+            extend_notes: false,
+        }
+    }
+}
+
+/// How far a transcription got, reported after every segment
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub transcribed: Duration,
+    /// Length of the recording, when the file tells it
+    pub duration: Option<Duration>,
+    /// Notes found so far
+    pub notes: usize,
+    /// Notes found in the last segment, before the pedal extension and the quantization
+    pub new_notes: &'a [Note],
+}
+
+impl Progress<'_> {
+    /// The same progress without the new notes, to keep it past the callback
+    pub fn without_notes(&self) -> Progress<'static> {
+        Progress {
+            transcribed: self.transcribed,
+            duration: self.duration,
+            notes: self.notes,
+            new_notes: &[],
+        }
+    }
+
+    /// From 0 to 1, `None` when the length of the recording is unknown
+    pub fn ratio(&self) -> Option<f32> {
+        self.duration.map(|duration| {
+            (self.transcribed.as_secs_f32() / duration.as_secs_f32().max(f32::EPSILON)).min(1.0)
+        })
+    }
+}
+
+pub struct Transcription {
+    /// Sorted by onset
+    pub notes: Vec<Note>,
+    pub pedals: Vec<Pedal>,
+    pub grid: BeatGrid,
+    /// Whether `grid` follows the beats of the music, it has a steady tempo otherwise
+    pub beats_tracked: bool,
+}
+
+impl Transcription {
+    pub fn to_smf(&self) -> midly::Smf<'static> {
+        midi::create_midi_file(&self.notes, &self.pedals, &self.grid)
+    }
+}
+
+pub fn load_model(path: &Path) -> anyhow::Result<Model> {
+    Model::load_file(path).with_context(|| format!("Could not load the model {}", path.display()))
+}
+
+/// Transcribes one recording, `progress` is called after every segment with the notes found in
+/// it
+pub fn transcribe(
+    model: &Model,
+    input: &Path,
+    options: &Options,
+    mut progress: impl FnMut(&Progress),
+) -> anyhow::Result<Transcription> {
+    let audio = audio::AudioStream::open(input)?;
+    let duration = audio.duration();
+
+    let mut segments = model::Segments::new(model, audio);
+    let mut detector = detection::Detector::new(options.thresholds);
+
+    let mut notes = Vec::new();
+    let mut pedals = Vec::new();
+
+    let mut transcribed = Duration::ZERO;
+    let mut frames = 0;
+
+    let mut report = |detected: detection::Detected, transcribed| {
+        let first_new = notes.len();
+        notes.extend(detected.notes);
+        pedals.extend(detected.pedals);
+
+        progress(&Progress {
+            transcribed,
+            duration,
+            notes: notes.len(),
+            new_notes: &notes[first_new..],
+        });
+    };
+
+    while let Some(output) = segments.next()? {
+        frames += output.len();
+        let detected = detector
+            .push(output)
+            .drop_short_notes(options.min_note_length);
+
+        // The padding at the end makes the last segment overshoot the duration
+        transcribed = Duration::from_secs_f32(frames as f32 / FRAMES_PER_SECOND);
+        if let Some(duration) = duration {
+            transcribed = transcribed.min(duration);
+        }
+
+        report(detected, transcribed);
+    }
+
+    // Notes still held at the end of the recording
+    report(
+        detector.finish().drop_short_notes(options.min_note_length),
+        transcribed,
+    );
+
+    if options.extend_notes {
+        detection::extend_notes_under_pedal(&mut notes, &pedals);
+    }
+
+    notes.sort_by(|a, b| a.onset.total_cmp(&b.onset));
+
+    let tracked = options
+        .track_beats
+        .then(|| BeatGrid::track(&notes, options.tempo))
+        .flatten();
+    let beats_tracked = tracked.is_some();
+    let grid = tracked.unwrap_or_else(|| BeatGrid::constant(options.tempo));
+
+    if let Some(division) = options.quantize {
+        grid.quantize(&mut notes, division);
+        notes.sort_by(|a, b| a.onset.total_cmp(&b.onset));
+    }
+
+    Ok(Transcription {
+        notes,
+        pedals,
+        grid,
+        beats_tracked,
+    })
+}
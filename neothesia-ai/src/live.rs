@@ -0,0 +1,492 @@
+//! Real-time note detection for acoustic pianos, much lighter than the transcription model and
+//! with a latency of a few hops instead of a segment.
+//!
+//! Every hop, the spectrum of the last ~0.1s of audio is searched for the harmonic series of the
+//! piano keys. Notes are picked one at a time, strongest first, and the harmonics of a picked note
+//! are taken out of the spectrum so they aren't mistaken for notes an octave or a fifth above it.
+//! Two notes an octave apart are heard as the lower one only, and notes a semitone apart may
+//! bring along notes that weren't played.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use realfft::{RealFftPlanner, RealToComplex, num_complex::Complex};
+
+use crate::audio::{AudioStream, Channels};
+use crate::detection::Note;
+
+const FIRST_KEY: u8 = 21;
+const KEYS: usize = 88;
+
+/// Length of the analysis window, in seconds, rounded up to a power of two samples
+const WINDOW_SECS: f32 = 0.1;
+const HOPS_PER_WINDOW: usize = 8;
+
+const HARMONICS: usize = 6;
+/// Harmonics taken out of the spectrum with a note found, higher ones would be heard as the
+/// octaves of the note
+const REMOVED_HARMONICS: usize = 12;
+/// Weight of each harmonic relative to the one before it
+const HARMONIC_DECAY: f32 = 0.8;
+/// Weakest fundamental relative to the loudest harmonic of a note
+const MIN_FUNDAMENTAL: f32 = 0.1;
+/// Bins around a harmonic, on top of its quarter tone band, taken out of the spectrum with it.
+/// About the main lobe of the Hann window.
+const LEAKAGE_BINS: usize = 2;
+
+const MAX_POLYPHONY: usize = 8;
+/// Weakest note, harmonic amplitudes summed
+const MIN_SALIENCE: f32 = 0.01;
+/// Weakest note relative to the strongest one
+const MIN_RELATIVE_SALIENCE: f32 = 0.15;
+/// Quieter windows are silence, RMS
+const SILENCE: f32 = 0.001;
+
+/// Hops a note has to be heard before it is pressed
+const ONSET_HOPS: u8 = 3;
+/// Hops a note has to be gone before it is released
+const RELEASE_HOPS: u8 = 4;
+/// A held note this much louder than at its quietest since the last attack is pressed again
+const REATTACK_RATIO: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteEvent {
+    On { key: u8, velocity: u8 },
+    Off { key: u8 },
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyState {
+    pressed: bool,
+    heard: u8,
+    gone: u8,
+    /// Hops since the key was pressed
+    since_attack: u8,
+    /// Quietest salience since the attack
+    floor: f32,
+}
+
+/// Detects notes in mono audio pushed in chunks of any length
+pub struct LiveDetector {
+    sample_rate: f32,
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    /// Sum of `window`, to get sine amplitudes out of the spectrum
+    window_gain: f32,
+    hop: usize,
+
+    /// The last window of samples, oldest first
+    samples: Vec<f32>,
+    /// Samples since the last hop
+    pending: usize,
+    /// Samples pushed so far
+    pushed: u64,
+
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+    /// `magnitudes` without the harmonics of the notes found so far
+    residual: Vec<f32>,
+    /// Key ids heard in the current window, with their salience
+    heard: Vec<(usize, f32)>,
+
+    keys: [KeyState; KEYS],
+}
+
+impl LiveDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        let len = ((sample_rate as f32 * WINDOW_SECS) as usize).next_power_of_two();
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(len);
+
+        let window: Vec<f32> = (0..len)
+            .map(|id| 0.5 - 0.5 * (std::f32::consts::TAU * id as f32 / len as f32).cos())
+            .collect();
+
+        Self {
+            sample_rate: sample_rate as f32,
+            window_gain: window.iter().sum(),
+            window,
+            hop: len / HOPS_PER_WINDOW,
+
+            samples: vec![0.0; len],
+            pending: 0,
+            pushed: 0,
+
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            magnitudes: vec![0.0; len / 2 + 1],
+            residual: vec![0.0; len / 2 + 1],
+            heard: Vec::with_capacity(MAX_POLYPHONY),
+            fft,
+
+            keys: [KeyState::default(); KEYS],
+        }
+    }
+
+    /// Audio pushed so far
+    pub fn time(&self) -> Duration {
+        Duration::from_secs_f64(self.pushed as f64 / self.sample_rate as f64)
+    }
+
+    /// Feeds the next samples, notes pressed or released within them go to `events`
+    pub fn push(&mut self, mut samples: &[f32], events: &mut Vec<NoteEvent>) {
+        while !samples.is_empty() {
+            let len = samples.len().min(self.hop - self.pending);
+            let (chunk, rest) = samples.split_at(len);
+            samples = rest;
+
+            self.samples.copy_within(len.., 0);
+            let end = self.samples.len();
+            self.samples[end - len..].copy_from_slice(chunk);
+            self.pending += len;
+            self.pushed += len as u64;
+
+            if self.pending == self.hop {
+                self.pending = 0;
+                self.analyze(events);
+            }
+        }
+    }
+
+    fn analyze(&mut self, events: &mut Vec<NoteEvent>) {
+        self.find_notes();
+
+        for (id, state) in self.keys.iter_mut().enumerate() {
+            let key = FIRST_KEY + id as u8;
+            let salience = self
+                .heard
+                .iter()
+                .find(|(heard, _)| *heard == id)
+                .map(|(_, salience)| *salience);
+
+            match salience {
+                Some(salience) => {
+                    state.heard = state.heard.saturating_add(1);
+                    state.gone = 0;
+
+                    state.since_attack = state.since_attack.saturating_add(1);
+
+                    if !state.pressed && state.heard >= ONSET_HOPS {
+                        state.pressed = true;
+                        state.since_attack = 0;
+                        events.push(NoteEvent::On {
+                            key,
+                            velocity: velocity(salience),
+                        });
+                    } else if state.pressed
+                        && state.since_attack > HOPS_PER_WINDOW as u8
+                        && salience > state.floor * REATTACK_RATIO
+                    {
+                        state.since_attack = 0;
+                        events.push(NoteEvent::Off { key });
+                        events.push(NoteEvent::On {
+                            key,
+                            velocity: velocity(salience),
+                        });
+                    }
+
+                    // An attack fills the window over several hops, it only decays afterwards
+                    state.floor = if state.since_attack <= HOPS_PER_WINDOW as u8 {
+                        salience
+                    } else {
+                        state.floor.min(salience)
+                    };
+                }
+                None => {
+                    state.heard = 0;
+                    state.gone = state.gone.saturating_add(1);
+
+                    if state.pressed && state.gone >= RELEASE_HOPS {
+                        state.pressed = false;
+                        events.push(NoteEvent::Off { key });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fills `heard` with the notes of the current window
+    fn find_notes(&mut self) {
+        self.heard.clear();
+
+        let rms =
+            (self.samples.iter().map(|s| s * s).sum::<f32>() / self.samples.len() as f32).sqrt();
+        if rms < SILENCE {
+            return;
+        }
+
+        for ((input, sample), window) in self.input.iter_mut().zip(&self.samples).zip(&self.window)
+        {
+            *input = sample * window;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.spectrum) {
+            *magnitude = bin.norm() * 2.0 / self.window_gain;
+        }
+        self.residual.copy_from_slice(&self.magnitudes);
+
+        let mut strongest = 0.0;
+        while self.heard.len() < MAX_POLYPHONY {
+            let Some((id, salience)) = (0..KEYS)
+                .filter(|id| self.heard.iter().all(|(note, _)| note != id))
+                .map(|id| (id, self.salience(id)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+            else {
+                break;
+            };
+
+            if salience < MIN_SALIENCE || salience < strongest * MIN_RELATIVE_SALIENCE {
+                break;
+            }
+            strongest = f32::max(strongest, salience);
+
+            self.heard.push((id, salience));
+            self.remove_harmonics(id);
+        }
+    }
+
+    /// Bins of the quarter tone band around harmonic `harmonic` of key `id`, `None` above
+    /// Nyquist
+    fn harmonic_bins(&self, id: usize, harmonic: usize) -> Option<std::ops::RangeInclusive<usize>> {
+        let frequency = key_frequency(id) * harmonic as f32;
+        let bin_width = self.sample_rate / self.window.len() as f32;
+
+        let quarter_tone = 2f32.powf(1.0 / 24.0);
+        let low = (frequency / quarter_tone / bin_width).round() as usize;
+        let high = (frequency * quarter_tone / bin_width).round() as usize;
+
+        (high < self.magnitudes.len()).then_some(low..=high)
+    }
+
+    /// Weighted sum of the harmonic peaks of key `id`, zero without a fundamental
+    fn salience(&self, id: usize) -> f32 {
+        let mut peaks = [0.0; HARMONICS];
+        for (harmonic, peak) in peaks.iter_mut().enumerate() {
+            let Some(bins) = self.harmonic_bins(id, harmonic + 1) else {
+                break;
+            };
+
+            // Only peaks of the spectrum count, not the skirt of a peak in a neighbouring band or
+            // of a removed one
+            *peak = bins
+                .filter(|&bin| {
+                    let magnitude = self.magnitudes[bin];
+                    magnitude >= self.magnitudes[bin.saturating_sub(1)]
+                        && self
+                            .magnitudes
+                            .get(bin + 1)
+                            .is_none_or(|next| magnitude >= *next)
+                })
+                .map(|bin| self.residual[bin])
+                .fold(0.0, f32::max);
+        }
+
+        // Otherwise chords are heard as their missing fundamental, eg. C3 for C4 E4 G4
+        let loudest = peaks.iter().copied().fold(0.0, f32::max);
+        if peaks[0] < loudest * MIN_FUNDAMENTAL {
+            return 0.0;
+        }
+
+        let mut weight = 1.0;
+        let mut salience = 0.0;
+        for peak in peaks {
+            salience += weight * peak;
+            weight *= HARMONIC_DECAY;
+        }
+        salience
+    }
+
+    fn remove_harmonics(&mut self, id: usize) {
+        for harmonic in 1..=REMOVED_HARMONICS {
+            let Some(bins) = self.harmonic_bins(id, harmonic) else {
+                break;
+            };
+
+            let start = bins.start().saturating_sub(LEAKAGE_BINS);
+            let end = (bins.end() + LEAKAGE_BINS).min(self.residual.len() - 1);
+            self.residual[start..=end].fill(0.0);
+        }
+    }
+}
+
+/// Plays a recording into a detector as if it came from a microphone, to try the detector without
+/// a piano. The audio is resampled to `sample_rate`, the rate of the input device to try. Notes are
+/// moved back by the detection latency, notes still held at the end are released there.
+pub fn detect_file(input: &Path, sample_rate: u32) -> anyhow::Result<Vec<Note>> {
+    let mut audio = AudioStream::open_with(input, sample_rate, Channels::Mono)?;
+    let mut detector = LiveDetector::new(sample_rate);
+
+    let hop = detector.hop as f32 / sample_rate as f32;
+    let onset_latency = ONSET_HOPS as f32 * hop;
+    let release_latency = (RELEASE_HOPS as usize + HOPS_PER_WINDOW / 2) as f32 * hop;
+
+    let mut notes = Vec::new();
+    let mut held: [Option<(f32, u8)>; 128] = [None; 128];
+    let mut release = |key: u8, time: f32, held: &mut [Option<(f32, u8)>; 128]| {
+        if let Some((onset, velocity)) = held[key as usize].take() {
+            notes.push(Note {
+                key,
+                onset,
+                offset: time.max(onset),
+                velocity,
+            });
+        }
+    };
+
+    let mut buf = vec![0.0; detector.hop];
+    let mut events = Vec::new();
+    loop {
+        let len = audio.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        detector.push(&buf[..len], &mut events);
+        let time = detector.time().as_secs_f32();
+        for event in events.drain(..) {
+            match event {
+                NoteEvent::On { key, velocity } => {
+                    release(key, time - onset_latency, &mut held);
+                    held[key as usize] = Some(((time - onset_latency).max(0.0), velocity));
+                }
+                NoteEvent::Off { key } => release(key, time - release_latency, &mut held),
+            }
+        }
+    }
+
+    let end = detector.time().as_secs_f32();
+    for key in 0..128 {
+        release(key, end, &mut held);
+    }
+
+    notes.sort_by(|a, b| a.onset.total_cmp(&b.onset));
+    Ok(notes)
+}
+
+fn key_frequency(id: usize) -> f32 {
+    440.0 * 2f32.powf((id as f32 + FIRST_KEY as f32 - 69.0) / 12.0)
+}
+
+/// From -40dB for the softest notes to 0dB for the loudest
+fn velocity(salience: f32) -> u8 {
+    let db = 20.0 * salience.max(f32::MIN_POSITIVE).log10();
+    (20.0 + (db + 40.0) / 40.0 * 107.0).clamp(1.0, 127.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    /// Piano-ish tones of `keys` starting together, harmonics fading with their number
+    fn chord(keys: &[u8], secs: f32) -> Vec<f32> {
+        let len = (secs * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|id| {
+                let t = id as f32 / SAMPLE_RATE as f32;
+                let envelope = 0.2 * (-t * 2.0).exp();
+                keys.iter()
+                    .map(|&key| {
+                        let frequency = key_frequency((key - FIRST_KEY) as usize);
+                        (1..=8)
+                            .map(|harmonic| {
+                                let phase = std::f32::consts::TAU * frequency * harmonic as f32 * t;
+                                phase.sin() / harmonic as f32
+                            })
+                            .sum::<f32>()
+                    })
+                    .sum::<f32>()
+                    * envelope
+            })
+            .collect()
+    }
+
+    /// Keys pressed, and whether they were all released, pushing the audio in small chunks
+    fn detect(audio: &[f32]) -> (Vec<u8>, bool) {
+        let mut detector = LiveDetector::new(SAMPLE_RATE);
+        let mut events = Vec::new();
+        for chunk in audio.chunks(100) {
+            detector.push(chunk, &mut events);
+        }
+
+        let mut pressed: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                NoteEvent::On { key, .. } => Some(*key),
+                NoteEvent::Off { .. } => None,
+            })
+            .collect();
+        pressed.sort();
+
+        let released = events
+            .iter()
+            .filter(|event| matches!(event, NoteEvent::Off { .. }))
+            .count();
+
+        (pressed.clone(), released == pressed.len())
+    }
+
+    fn with_silence(mut audio: Vec<f32>) -> Vec<f32> {
+        audio.extend(std::iter::repeat_n(0.0, SAMPLE_RATE as usize / 2));
+        audio
+    }
+
+    #[test]
+    fn single_notes() {
+        for key in [45, 60, 72, 84] {
+            let (pressed, released) = detect(&with_silence(chord(&[key], 0.5)));
+            assert_eq!(pressed, [key]);
+            assert!(released);
+        }
+    }
+
+    #[test]
+    fn chords() {
+        let (pressed, released) = detect(&with_silence(chord(&[60, 64, 67], 0.5)));
+        assert_eq!(pressed, [60, 64, 67]);
+        assert!(released);
+
+        let (pressed, _) = detect(&chord(&[48, 55, 64], 0.5));
+        assert_eq!(pressed, [48, 55, 64]);
+    }
+
+    #[test]
+    fn repeated_note() {
+        let mut audio = chord(&[62], 0.4);
+        audio.extend(chord(&[62], 0.4));
+        let (pressed, released) = detect(&with_silence(audio));
+        assert_eq!(pressed, [62, 62]);
+        assert!(released);
+    }
+
+    #[test]
+    fn detect_wav_file() {
+        let path =
+            crate::audio::tests::write_wav("live.wav", SAMPLE_RATE, &[chord(&[60, 64, 67], 0.5)]);
+        // Resampled to a usual device rate
+        let notes = detect_file(&path, 48000);
+        std::fs::remove_file(&path).unwrap();
+
+        let notes = notes.unwrap();
+        let mut keys: Vec<u8> = notes.iter().map(|note| note.key).collect();
+        keys.sort();
+        assert_eq!(keys, [60, 64, 67]);
+
+        for note in &notes {
+            assert!(note.onset < 0.05, "{note:?}");
+            assert!((note.offset - 0.5).abs() < 0.1, "{note:?}");
+        }
+    }
+
+    #[test]
+    fn silence() {
+        let (pressed, _) = detect(&vec![0.0; SAMPLE_RATE as usize]);
+        assert!(pressed.is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    beats::BeatGrid,
+    detection::{Note, Pedal},
+};
+
+const TICKS_PER_BEAT: u16 = 384;
+
+/// The notes keep their timing whatever the beats, the beats only change how they line up with
+/// bars
+pub fn create_midi_file(notes: &[Note], pedals: &[Pedal], grid: &BeatGrid) -> midly::Smf<'static> {
+    let ticks = |time: f32| (grid.beat_at(time) * TICKS_PER_BEAT as f32).round() as i32;
+
+    let mut track1 = vec![];
+
+    let mut message_roll = vec![];
+
+    for note in notes {
+        let key = note.key.into();
+        message_roll.push((
+            note.onset,
+            midly::MidiMessage::NoteOn {
+                key,
+                vel: note.velocity.into(),
+            },
+        ));
+        message_roll.push((
+            note.offset,
+            midly::MidiMessage::NoteOn { key, vel: 0.into() },
+        ));
+    }
+
+    // Sustain pedal
+    for pedal in pedals {
+        let controller = 64.into();
+        message_roll.push((
+            pedal.onset,
+            midly::MidiMessage::Controller {
+                controller,
+                value: 127.into(),
+            },
+        ));
+        message_roll.push((
+            pedal.offset,
+            midly::MidiMessage::Controller {
+                controller,
+                value: 0.into(),
+            },
+        ));
+    }
+
+    message_roll.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut previous_ticks = 0;
+
+    let start_time = 0.0;
+    for message in message_roll {
+        let this_ticks = ticks(message.0 - start_time);
+
+        if this_ticks >= 0 {
+            let diff_ticks = this_ticks - previous_ticks;
+            previous_ticks = this_ticks;
+
+            track1.push(midly::TrackEvent {
+                delta: (diff_ticks as u32).into(),
+                kind: midly::TrackEventKind::Midi {
+                    channel: 0.into(),
+                    message: message.1,
+                },
+            });
+        }
+    }
+
+    track1.push(midly::TrackEvent {
+        delta: 1.into(),
+        kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+    });
+
+    midly::Smf {
+        header: midly::Header {
+            format: midly::Format::Parallel,
+            timing: midly::Timing::Metrical(TICKS_PER_BEAT.into()),
+        },
+        tracks: vec![conductor_track(grid), track1],
+    }
+}
+
+/// Tempo of every beat that changes it, and the time signature
+fn conductor_track(grid: &BeatGrid) -> Vec<midly::TrackEvent<'static>> {
+    let beat_ticks = |beat: usize| beat as u32 * TICKS_PER_BEAT as u32;
+    let time_signature = |numerator: u8| midly::MetaMessage::TimeSignature(numerator, 2, 24, 8);
+
+    let mut messages = Vec::new();
+
+    // A pickup is a short bar of its own
+    if grid.pickup > 0 {
+        messages.push((0, time_signature(grid.pickup)));
+    }
+    messages.push((
+        beat_ticks(grid.pickup as usize),
+        time_signature(grid.numerator),
+    ));
+
+    let mut previous = None;
+    for beat in 0..grid.len() {
+        let microseconds_per_beat = (grid.beat_len(beat) * 1_000_000.0).round() as u32;
+        if previous != Some(microseconds_per_beat) {
+            messages.push((
+                beat_ticks(beat),
+                midly::MetaMessage::Tempo(microseconds_per_beat.into()),
+            ));
+            previous = Some(microseconds_per_beat);
+        }
+    }
+
+    messages.sort_by_key(|(ticks, _)| *ticks);
+
+    let mut previous_ticks = 0;
+    let mut track: Vec<_> = messages
+        .into_iter()
+        .map(|(ticks, message)| {
+            let delta = ticks - previous_ticks;
+            previous_ticks = ticks;
+            midly::TrackEvent {
+                delta: delta.into(),
+                kind: midly::TrackEventKind::Meta(message),
+            }
+        })
+        .collect();
+
+    track.push(midly::TrackEvent {
+        delta: 1.into(),
+        kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+    });
+    track
+}
@@ -0,0 +1,174 @@
+//! The model runs on 10 second segments overlapping by half, one at a time. Of every segment only
+//! the middle half is kept, the edges lack context, except for the start of the first segment and
+//! the end of the last one.
+
+use ndarray::{Array2, Axis, s};
+use rten::{NodeId, ValueOrView};
+use rten_tensor::{prelude::*, *};
+
+use crate::{SEGMENT_SAMPLES, audio::AudioStream};
+
+const HOP_SAMPLES: usize = SEGMENT_SAMPLES / 2;
+
+/// Model outputs of consecutive frames, each of shape (frames, classes)
+pub struct Frames {
+    pub reg_onset: Array2<f32>,
+    pub reg_offset: Array2<f32>,
+    pub frame: Array2<f32>,
+    pub velocity: Array2<f32>,
+    pub reg_pedal_onset: Array2<f32>,
+    pub reg_pedal_offset: Array2<f32>,
+    pub pedal_frame: Array2<f32>,
+}
+
+impl Frames {
+    pub fn len(&self) -> usize {
+        self.frame.nrows()
+    }
+
+    fn outputs_mut(&mut self) -> [&mut Array2<f32>; 7] {
+        [
+            &mut self.reg_onset,
+            &mut self.reg_offset,
+            &mut self.frame,
+            &mut self.velocity,
+            &mut self.reg_pedal_onset,
+            &mut self.reg_pedal_offset,
+            &mut self.pedal_frame,
+        ]
+    }
+
+    /// Appends the frames that follow
+    pub fn append(&mut self, mut next: Frames) {
+        for (output, next) in self.outputs_mut().into_iter().zip(next.outputs_mut()) {
+            output.append(Axis(0), next.view()).unwrap();
+        }
+    }
+
+    /// Drops the first `count` frames
+    pub fn drop_front(&mut self, count: usize) {
+        for output in self.outputs_mut() {
+            *output = output.slice(s![count.., ..]).to_owned();
+        }
+    }
+}
+
+/// Runs the model over an audio stream, segment by segment
+pub struct Segments<'a> {
+    model: &'a rten::Model,
+    audio: AudioStream,
+    segment: Vec<f32>,
+    /// Second half of the next segment, read ahead to tell whether the current one is the last
+    next: Vec<f32>,
+    /// Samples read so far, padding included
+    read: usize,
+    id: usize,
+    done: bool,
+}
+
+impl<'a> Segments<'a> {
+    pub fn new(model: &'a rten::Model, audio: AudioStream) -> Self {
+        Self {
+            model,
+            audio,
+            segment: vec![0.0; SEGMENT_SAMPLES],
+            next: vec![0.0; HOP_SAMPLES],
+            read: 0,
+            id: 0,
+            done: false,
+        }
+    }
+
+    /// Outputs of the next frames, `None` once the audio is done
+    pub fn next(&mut self) -> anyhow::Result<Option<Frames>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        if self.id == 0 {
+            let len = read_padded(&mut self.audio, &mut self.read, &mut self.segment)?;
+            anyhow::ensure!(len > 0, "The audio has no samples");
+        }
+
+        let first = self.id == 0;
+        let last = read_padded(&mut self.audio, &mut self.read, &mut self.next)? == 0;
+
+        let input = TensorView::from_data(&[1, SEGMENT_SAMPLES], self.segment.as_slice());
+        let inputs: Vec<(NodeId, ValueOrView)> = vec![(self.model.input_ids()[0], input.into())];
+
+        let [
+            reg_onset,
+            reg_offset,
+            frame,
+            velocity,
+            reg_pedal_onset,
+            reg_pedal_offset,
+            pedal_frame,
+        ] = self
+            .model
+            .run_n::<7>(inputs, self.model.output_ids().try_into()?, None)?;
+
+        let keep = |output| keep_frames(output, first, last);
+        let frames = Frames {
+            reg_onset: keep(reg_onset),
+            reg_offset: keep(reg_offset),
+            frame: keep(frame),
+            velocity: keep(velocity),
+            reg_pedal_onset: keep(reg_pedal_onset),
+            reg_pedal_offset: keep(reg_pedal_offset),
+            pedal_frame: keep(pedal_frame),
+        };
+
+        if last {
+            self.done = true;
+        } else {
+            self.segment.copy_within(HOP_SAMPLES.., 0);
+            self.segment[HOP_SAMPLES..].copy_from_slice(&self.next);
+        }
+        self.id += 1;
+
+        Ok(Some(frames))
+    }
+}
+
+/// Fills `buf` from the audio, the end of the audio is padded with silence to whole segments.
+/// Returns less than `buf.len()` only once the padding is done.
+fn read_padded(
+    audio: &mut AudioStream,
+    read: &mut usize,
+    buf: &mut [f32],
+) -> anyhow::Result<usize> {
+    let len = audio.read(buf)?;
+    *read += len;
+    if len == buf.len() {
+        return Ok(len);
+    }
+
+    let padding = (read.next_multiple_of(SEGMENT_SAMPLES) - *read).min(buf.len() - len);
+    buf[len..len + padding].fill(0.0);
+    *read += padding;
+
+    Ok(len + padding)
+}
+
+/// Frames of a segment output of shape (1, segment_frames, classes) that make it into the
+/// transcription
+fn keep_frames(output: rten::Value, first: bool, last: bool) -> Array2<f32> {
+    let output = output.into_tensor::<f32>().unwrap();
+    let [_, segment_frames, classes]: [usize; 3] = output.shape().try_into().unwrap();
+    let output = Array2::from_shape_vec((segment_frames, classes), output.to_vec()).unwrap();
+
+    // The last frame of a segment overlaps the next one, it is dropped unless the segment is
+    // alone
+    let frames = segment_frames - 1;
+    assert!(frames.is_multiple_of(4));
+
+    let range = match (first, last) {
+        (true, true) => 0..segment_frames,
+        (true, false) => 0..frames * 3 / 4,
+        (false, false) => frames / 4..frames * 3 / 4,
+        (false, true) => frames / 4..frames,
+    };
+
+    output.slice(s![range, ..]).to_owned()
+}
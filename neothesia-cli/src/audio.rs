@@ -0,0 +1,126 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    time::Duration,
+};
+
+use neothesia_core::config::Config;
+
+use crate::{cli::AudioArgs, flac::FlacWriter, wav::WavWriter};
+
+/// Samples rendered between MIDI events, matches the internal block of oxisynth
+const BLOCK: usize = 64;
+
+enum AudioWriter {
+    Wav(WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+impl AudioWriter {
+    fn write(&mut self, l: &[f32], r: &[f32]) -> io::Result<()> {
+        match self {
+            Self::Wav(wav) => wav.write(l, r),
+            Self::Flac(flac) => flac.write(l, r),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Wav(wav) => wav.finish().map(|_| ()),
+            Self::Flac(flac) => flac.finish().map(|_| ()),
+        }
+    }
+}
+
+/// Renders the MIDI file through the synth straight into a WAV or FLAC file
+pub fn render(args: &AudioArgs) -> Result<(), String> {
+    let extension = args
+        .out
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase);
+    let is_flac = match extension.as_deref() {
+        Some("wav") => false,
+        Some("flac") => true,
+        _ => return Err("Output file has to be a .wav or .flac file".into()),
+    };
+
+    let midi = midi_file::MidiFile::new(&args.midi)
+        .map_err(|err| format!("Error loading MIDI file: {err}"))?;
+    let muted_tracks = crate::select_tracks(&args.mute_tracks, &midi.tracks)?;
+
+    let soundfont = args
+        .soundfont
+        .clone()
+        .or_else(|| Config::new().soundfont_path())
+        .ok_or("No SoundFont found, pass one with --soundfont")?;
+
+    let mut synth = oxisynth::Synth::new(oxisynth::SynthDescriptor {
+        sample_rate: args.sample_rate as f32,
+        gain: args.gain,
+        reverb_active: args.reverb,
+        chorus_active: args.chorus,
+        ..Default::default()
+    })
+    .map_err(|err| format!("Error creating synth: {err:?}"))?;
+
+    synth.add_font(crate::load_soundfont(&soundfont)?, true);
+
+    let file = File::create(&args.out)
+        .map_err(|err| format!("Error creating {}: {err}", args.out.display()))?;
+    let file = BufWriter::new(file);
+    let mut writer = if is_flac {
+        FlacWriter::new(file, args.sample_rate).map(AudioWriter::Flac)
+    } else {
+        WavWriter::new(file, args.sample_rate).map(AudioWriter::Wav)
+    }
+    .map_err(write_error)?;
+
+    let mut playback = midi_file::PlaybackState::new(Duration::ZERO, midi.tracks.clone());
+
+    let sample_rate = args.sample_rate as f64;
+    let end = *playback.last_note_end() + args.tail;
+    let total = (end.as_secs_f64() * sample_rate).ceil() as usize;
+
+    let mut l = [0.0; BLOCK];
+    let mut r = [0.0; BLOCK];
+    let mut rendered = 0;
+    let start = std::time::Instant::now();
+
+    while rendered < total {
+        // Time from the sample count, so the events don't drift from the audio
+        let time = Duration::from_secs_f64(rendered as f64 / sample_rate);
+        for event in playback.update(time - playback.time()) {
+            if !muted_tracks.contains(&event.track_id) {
+                synth
+                    .send_event(crate::libmidi_to_oxisynth_event(
+                        event.channel,
+                        event.message,
+                    ))
+                    .ok();
+            }
+        }
+
+        let len = BLOCK.min(total - rendered);
+        synth.write_f32(len, &mut l, 0, 1, &mut r, 0, 1);
+        writer.write(&l[..len], &r[..len]).map_err(write_error)?;
+        rendered += len;
+
+        if rendered % (BLOCK * 1024) == 0 || rendered == total {
+            print!(
+                "\r Rendered {}s of {}s in {:.1}s",
+                (rendered as f64 / sample_rate).round(),
+                end.as_secs_f64().round(),
+                start.elapsed().as_secs_f32()
+            );
+            io::stdout().flush().ok();
+        }
+    }
+    println!();
+
+    writer.finish().map_err(write_error)
+}
+
+fn write_error(err: io::Error) -> String {
+    format!("Error writing audio: {err}")
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::cli::{BatchArgs, OutputFormat};
+
+#[derive(Serialize)]
+struct Report {
+    jobs: usize,
+    succeeded: usize,
+    failed: usize,
+    /// Wall time of the whole batch
+    elapsed_secs: f64,
+    files: Vec<FileReport>,
+}
+
+#[derive(Serialize)]
+struct FileReport {
+    input: PathBuf,
+    /// Missing when no output name could be picked for the song
+    output: Option<PathBuf>,
+    /// Length of the rendered video
+    duration_secs: Option<f64>,
+    frames: Option<usize>,
+    /// Time it took to render
+    render_secs: f64,
+    error: Option<String>,
+}
+
+/// Renders every song of the batch, a failed song doesn't stop the others
+pub fn render(args: &BatchArgs) -> Result<(), String> {
+    let songs = songs(&args.input)?;
+    if songs.is_empty() {
+        return Err(format!("No MIDI files found in {}", args.input.display()));
+    }
+
+    std::fs::create_dir_all(&args.out_dir)
+        .map_err(|err| format!("Error creating {}: {err}", args.out_dir.display()))?;
+
+    let outputs = outputs(args, &songs);
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<FileReport>>> = Mutex::new(songs.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..args.jobs.min(songs.len()) {
+            scope.spawn(|| {
+                loop {
+                    let id = next.fetch_add(1, Ordering::Relaxed);
+                    let (Some(song), Some(output)) = (songs.get(id), outputs.get(id)) else {
+                        break;
+                    };
+
+                    let report = render_song(args, song, output);
+
+                    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    match &report.error {
+                        None => println!(
+                            "[{done}/{}] {} done in {:.1}s",
+                            songs.len(),
+                            song.display(),
+                            report.render_secs
+                        ),
+                        Some(err) => {
+                            println!("[{done}/{}] {} failed: {err}", songs.len(), song.display())
+                        }
+                    }
+
+                    reports.lock().unwrap()[id] = Some(report);
+                }
+            });
+        }
+    });
+
+    let files: Vec<FileReport> = reports
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect();
+    let failed = files.iter().filter(|file| file.error.is_some()).count();
+    let report = Report {
+        jobs: args.jobs,
+        succeeded: files.len() - failed,
+        failed,
+        elapsed_secs: start.elapsed().as_secs_f64(),
+        files,
+    };
+
+    File::create(&args.report)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &report)
+                .map_err(|err| err.to_string())
+        })
+        .map_err(|err| format!("Error writing {}: {err}", args.report.display()))?;
+
+    println!(
+        "Rendered {} of {} songs in {:.1}s, report written to {}",
+        report.succeeded,
+        songs.len(),
+        report.elapsed_secs,
+        args.report.display()
+    );
+
+    if failed > 0 {
+        return Err(format!("{failed} of {} renders failed", songs.len()));
+    }
+    Ok(())
+}
+
+fn render_song(args: &BatchArgs, song: &Path, output: &Result<PathBuf, String>) -> FileReport {
+    let start = Instant::now();
+
+    let result = output.clone().and_then(|out| {
+        let mut video = args.video.clone();
+        video.midi = song.to_owned();
+        video.out = out;
+
+        // wgpu reports validation errors by panicking, that should fail just this song
+        std::panic::catch_unwind(|| crate::render_video(&video, false))
+            .unwrap_or_else(|panic| Err(panic_message(panic.as_ref())))
+    });
+
+    let (stats, error) = match result {
+        Ok(stats) => (Some(stats), None),
+        Err(err) => (None, Some(err)),
+    };
+
+    FileReport {
+        input: song.to_owned(),
+        output: output.clone().ok(),
+        duration_secs: stats.as_ref().map(|stats| stats.duration.as_secs_f64()),
+        frames: stats.as_ref().map(|stats| stats.frames),
+        render_secs: start.elapsed().as_secs_f64(),
+        error,
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error");
+    format!("Render panicked: {message}")
+}
+
+/// MIDI files of a directory, or the ones listed in a text file
+fn songs(input: &Path) -> Result<Vec<PathBuf>, String> {
+    let read_error = |err: std::io::Error| format!("Error reading {}: {err}", input.display());
+
+    if input.is_dir() {
+        let mut songs = Vec::new();
+        for entry in std::fs::read_dir(input).map_err(read_error)? {
+            let path = entry.map_err(read_error)?.path();
+            let is_midi = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi")
+                });
+            if is_midi && path.is_file() {
+                songs.push(path);
+            }
+        }
+        songs.sort();
+        Ok(songs)
+    } else {
+        // Relative paths are relative to the list file
+        let dir = input.parent().unwrap_or(Path::new(""));
+        Ok(std::fs::read_to_string(input)
+            .map_err(read_error)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| dir.join(line))
+            .collect())
+    }
+}
+
+/// Output of every song, named after the MIDI file. Songs whose name is already taken fail.
+fn outputs(args: &BatchArgs, songs: &[PathBuf]) -> Vec<Result<PathBuf, String>> {
+    let mut taken = HashSet::new();
+
+    songs
+        .iter()
+        .map(|song| {
+            let stem = song
+                .file_stem()
+                .ok_or_else(|| format!("{} is not a file", song.display()))?;
+
+            let out = match args.video.format {
+                // A directory of frames
+                OutputFormat::Png => args.out_dir.join(stem),
+                #[cfg(feature = "ffmpeg")]
+                OutputFormat::Ffmpeg => {
+                    let mut name = stem.to_owned();
+                    name.push(".");
+                    name.push(&args.extension);
+                    args.out_dir.join(name)
+                }
+            };
+
+            if taken.insert(out.clone()) {
+                Ok(out)
+            } else {
+                Err(format!(
+                    "{} is also the output of another song",
+                    out.display()
+                ))
+            }
+        })
+        .collect()
+}
@@ -1,5 +1,10 @@
-use clap::{Command, arg, value_parser};
-use std::path::PathBuf;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Command, arg, value_parser};
+#[cfg(feature = "ffmpeg")]
+use ffmpeg_encoder::{EncoderOptions, Quality, VideoCodec};
+use midi_file::MidiTrack;
+use neothesia_core::config::{ColorSchemaV1, Config};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct Args {
@@ -8,12 +13,570 @@ pub struct Args {
     pub soundfont: Option<PathBuf>,
     pub width: u32,
     pub height: u32,
+    pub fps: u32,
+    pub format: OutputFormat,
+    #[cfg(feature = "ffmpeg")]
+    pub ffmpeg: FfmpegArgs,
+    pub transparent: bool,
+    pub start: Option<SongTime>,
+    pub end: Option<SongTime>,
+    pub lead_in: Duration,
+    pub tail: Duration,
+    pub settings: Settings,
+    pub hide_tracks: Vec<TrackSelector>,
+    pub show_tracks: Vec<TrackSelector>,
+    pub mute_tracks: Vec<TrackSelector>,
+    pub overlay: OverlayArgs,
 }
 
-impl Args {
+/// Song information and position drawn over the video
+#[derive(Debug, Clone, Default)]
+pub struct OverlayArgs {
+    /// How long the title card is shown at the start
+    pub title_card: Option<Duration>,
+    /// Replaces the song name from the MIDI file
+    pub title: Option<String>,
+    /// Replaces the composer from the MIDI file
+    pub composer: Option<String>,
+    /// Top left corner text, `{title}` and `{composer}` are replaced
+    pub caption: Option<String>,
+    pub progress_bar: bool,
+    pub bar_counter: bool,
+}
+
+impl OverlayArgs {
+    pub fn is_enabled(&self) -> bool {
+        self.title_card.is_some() || self.caption.is_some() || self.progress_bar || self.bar_counter
+    }
+}
+
+/// Overrides of the app settings for a single run
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub path: Option<PathBuf>,
+    pub range: Option<(u8, u8)>,
+    pub background: Option<(u8, u8, u8)>,
+    pub colors: Option<Vec<(u8, u8, u8)>>,
+    pub vertical_guidelines: Option<bool>,
+    pub horizontal_guidelines: Option<bool>,
+    pub glow: Option<bool>,
+    pub note_labels: Option<bool>,
+    pub animation_speed: Option<f32>,
+}
+
+impl Settings {
+    /// The app settings, or the `--config` file, with overrides applied
+    pub fn load(&self) -> Result<Config, String> {
+        let mut config = match &self.path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::new(),
+        };
+
+        if let Some((start, end)) = self.range {
+            config.set_piano_range_start(start);
+            config.set_piano_range_end(end);
+        }
+        if let Some(background) = self.background {
+            config.set_background_color(background);
+        }
+        if let Some(colors) = &self.colors {
+            // The darker shade is used for note outlines
+            let dark = |c: u8| (c as f32 * 0.6) as u8;
+            config.set_color_schema(
+                colors
+                    .iter()
+                    .map(|&(r, g, b)| ColorSchemaV1 {
+                        base: (r, g, b),
+                        dark: (dark(r), dark(g), dark(b)),
+                    })
+                    .collect(),
+            );
+        }
+        if let Some(vertical) = self.vertical_guidelines {
+            config.set_vertical_guidelines(vertical);
+        }
+        if let Some(horizontal) = self.horizontal_guidelines {
+            config.set_horizontal_guidelines(horizontal);
+        }
+        if let Some(glow) = self.glow {
+            config.set_glow(glow);
+        }
+        if let Some(note_labels) = self.note_labels {
+            config.set_note_labels(note_labels);
+        }
+        if let Some(speed) = self.animation_speed {
+            config.set_animation_speed(speed);
+        }
+
+        Ok(config)
+    }
+}
+
+/// Track picked on the command line, by its index in the file or by name
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackSelector {
+    Index(usize),
+    /// Track name or the instrument name shown in the app, case insensitive
+    Name(String),
+}
+
+impl TrackSelector {
+    pub fn matches(&self, track: &MidiTrack) -> bool {
+        match self {
+            Self::Index(id) => track.track_id == *id,
+            Self::Name(name) => track
+                .name
+                .iter()
+                .map(String::as_str)
+                .chain([instrument_name(track)])
+                .any(|track_name| track_name.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+/// Instrument name the app shows on the track card
+pub fn instrument_name(track: &MidiTrack) -> &'static str {
+    if track.has_drums && !track.has_other_than_drums {
+        "Percussion"
+    } else {
+        let instrument_id = track
+            .programs
+            .last()
+            .map(|p| p.program as usize)
+            .unwrap_or(0);
+        midi_file::INSTRUMENT_NAMES[instrument_id]
+    }
+}
+
+fn parse_track(value: &str) -> Result<TrackSelector, String> {
+    Ok(match value.parse::<usize>() {
+        Ok(id) => TrackSelector::Index(id),
+        Err(_) => TrackSelector::Name(value.to_string()),
+    })
+}
+
+/// Parses a MIDI note range like `21-108`
+fn parse_range(value: &str) -> Result<(u8, u8), String> {
+    value
+        .split_once('-')
+        .and_then(|(start, end)| Some((start.parse::<u8>().ok()?, end.parse::<u8>().ok()?)))
+        .filter(|(start, end)| start < end && *end <= 127)
+        .ok_or_else(|| format!("invalid range: {value}, expected eg. 21-108"))
+}
+
+/// Parses a hex color like `#d259de` or `d259de`
+fn parse_color(value: &str) -> Result<(u8, u8, u8), String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok((r, g, b)),
+        _ => Err(format!("invalid color: {value}")),
+    }
+}
+
+fn parse_colors(value: &str) -> Result<Vec<(u8, u8, u8)>, String> {
+    value.split(',').map(|c| parse_color(c.trim())).collect()
+}
+
+/// Position in the song given on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SongTime {
+    Seconds(Duration),
+    /// Start of a bar, counted from 1
+    Bar(usize),
+}
+
+impl SongTime {
+    /// Time from the beginning of the song, `measures` are the bar start times counted like the
+    /// bar counter of the overlay, see [`midi_file::SongMeta::bars`]
+    pub fn resolve(&self, measures: &[Duration]) -> Result<Duration, String> {
+        match *self {
+            Self::Seconds(time) => Ok(time),
+            Self::Bar(bar) => measures.get(bar - 1).copied().ok_or_else(|| {
+                format!(
+                    "bar {bar} is past the end of the song, it has {} bars",
+                    measures.len()
+                )
+            }),
+        }
+    }
+}
+
+/// Frame size in pixels, encoders need it even
+fn parse_dimension(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|pixels| *pixels > 0 && pixels.is_multiple_of(2))
+        .ok_or_else(|| format!("invalid size: {value}, expected a multiple of two"))
+}
+
+/// Parses seconds like `12.5` or `12.5s`
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .strip_suffix('s')
+        .unwrap_or(value)
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid time: {value}"))
+}
+
+/// Parses seconds (`12.5`, `12.5s`) or a bar number (`9b`)
+fn parse_song_time(value: &str) -> Result<SongTime, String> {
+    match value.strip_suffix('b') {
+        Some(bar) => bar
+            .parse::<usize>()
+            .ok()
+            .filter(|bar| *bar > 0)
+            .map(SongTime::Bar)
+            .ok_or_else(|| format!("invalid bar: {value}")),
+        None => parse_seconds(value).map(SongTime::Seconds),
+    }
+}
+
+#[cfg(feature = "ffmpeg")]
+/// Parses bitrates like `8M`, `192k` or `400000`
+fn parse_bitrate(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.as_bytes().last() {
+        Some(b'k' | b'K') => (&value[..value.len() - 1], 1_000),
+        Some(b'm' | b'M') => (&value[..value.len() - 1], 1_000_000),
+        _ => (value, 1),
+    };
+
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n > 0.0)
+        .map(|n| (n * multiplier as f64) as u64)
+        .ok_or_else(|| format!("invalid bitrate: {value}"))
+}
+
+#[cfg(feature = "ffmpeg")]
+/// Alpha capable codec matching the container of `out`
+fn alpha_codec_for(out: &std::path::Path) -> Option<VideoCodec> {
+    match out.extension()?.to_str()? {
+        "mov" => Some(VideoCodec::ProRes),
+        "webm" | "mkv" => Some(VideoCodec::Vp9),
+        "png" => Some(VideoCodec::Png),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "ffmpeg")]
+fn parse_codec(value: &str) -> Result<VideoCodec, String> {
+    VideoCodec::from_name(value).ok_or_else(|| format!("unknown codec: {value}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Video file encoded by ffmpeg
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg,
+    /// Directory of numbered PNG frames and a WAV file
+    Png,
+}
+
+impl OutputFormat {
+    #[cfg(feature = "ffmpeg")]
+    const DEFAULT: Self = Self::Ffmpeg;
+    #[cfg(not(feature = "ffmpeg"))]
+    const DEFAULT: Self = Self::Png;
+}
+
+fn parse_format(value: &str) -> Result<OutputFormat, String> {
+    match value {
+        #[cfg(feature = "ffmpeg")]
+        "ffmpeg" => Ok(OutputFormat::Ffmpeg),
+        #[cfg(not(feature = "ffmpeg"))]
+        "ffmpeg" => Err("built without ffmpeg support, use --format png".into()),
+        "png" => Ok(OutputFormat::Png),
+        _ => Err(format!("unknown format: {value}")),
+    }
+}
+
+/// Options of the ffmpeg output
+#[cfg(feature = "ffmpeg")]
+#[derive(Debug, Clone)]
+pub struct FfmpegArgs {
+    pub codec: Option<VideoCodec>,
+    pub quality: Option<Quality>,
+    pub pix_fmt: Option<String>,
+    pub audio_bitrate: u64,
+}
+
+#[cfg(feature = "ffmpeg")]
+impl FfmpegArgs {
+    fn command(command: Command) -> Command {
+        let codecs = VideoCodec::ALL.map(|codec| codec.name()).join(", ");
+
+        command
+            .arg(
+                arg!(--codec <CODEC>)
+                    .help(format!("Video codec: {codecs}"))
+                    .required(false)
+                    .value_parser(parse_codec),
+            )
+            .arg(
+                arg!(--crf <CRF>)
+                    .help(
+                        "Constant rate factor, lower is better, up to 51 for h264/h265 and 63 for \
+                         vp9/av1. Picked for the codec by default",
+                    )
+                    .required(false)
+                    .value_parser(value_parser!(u32).range(0..=63))
+                    .conflicts_with("bitrate"),
+            )
+            .arg(
+                arg!(--bitrate <BITRATE> "Video bitrate, eg. 8M")
+                    .required(false)
+                    .value_parser(parse_bitrate),
+            )
+            .arg(arg!(--"pix-fmt" <PIX_FMT> "Pixel format name, eg. yuv420p").required(false))
+            .arg(
+                arg!(--"audio-bitrate" <BITRATE> "Audio bitrate, eg. 192k")
+                    .required(false)
+                    .value_parser(parse_bitrate),
+            )
+    }
+
+    fn from_matches(
+        matches: &clap::ArgMatches,
+        out: &std::path::Path,
+        transparent: bool,
+    ) -> Result<Self, clap::Error> {
+        let defaults = EncoderOptions::default();
+
+        let quality = if let Some(bitrate) = matches.get_one::<u64>("bitrate") {
+            Some(Quality::Bitrate(*bitrate))
+        } else {
+            matches.get_one::<u32>("crf").map(|crf| Quality::Crf(*crf))
+        };
+
+        let mut codec = matches.get_one::<VideoCodec>("codec").copied();
+        if transparent {
+            codec = codec.or_else(|| alpha_codec_for(out));
+
+            if !codec.is_some_and(|codec| codec.supports_alpha()) {
+                return Err(clap::Error::raw(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--transparent needs an alpha capable codec, use --codec prores (.mov), \
+                     vp9 (.webm) or png (eg. frames/%05d.png)\n",
+                ));
+            }
+        }
+
+        Ok(Self {
+            codec,
+            quality,
+            pix_fmt: matches.get_one::<String>("pix-fmt").cloned(),
+            audio_bitrate: matches
+                .get_one::<u64>("audio-bitrate")
+                .copied()
+                .unwrap_or(defaults.audio_bitrate),
+        })
+    }
+}
+
+/// What to render, picked by the subcommand
+#[derive(Debug, Clone)]
+pub enum Mode {
+    Video(Args),
+    Audio(AudioArgs),
+    Batch(BatchArgs),
+}
+
+/// Arguments of the `audio` subcommand
+#[derive(Debug, Clone)]
+pub struct AudioArgs {
+    pub midi: PathBuf,
+    /// `.wav` or `.flac` file
+    pub out: PathBuf,
+    pub soundfont: Option<PathBuf>,
+    pub sample_rate: u32,
+    pub gain: f32,
+    pub reverb: bool,
+    pub chorus: bool,
+    pub tail: Duration,
+    pub mute_tracks: Vec<TrackSelector>,
+}
+
+impl AudioArgs {
+    fn command() -> Command {
+        Command::new("audio")
+            .about("Render the audio of a MIDI file to WAV or FLAC, without a GPU")
+            .arg(
+                arg!([MIDI_FILE])
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!([OUT_FILE] "Output file, .wav or .flac")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--soundfont <SF2_FILE> "SoundFont, the one set in the app by default")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"sample-rate" <HZ>)
+                    .required(false)
+                    .value_parser(value_parser!(u32).range(8000..=96000)),
+            )
+            .arg(
+                arg!(--gain <GAIN> "Synth gain, 0.5 by default")
+                    .required(false)
+                    .value_parser(parse_gain),
+            )
+            .arg(
+                arg!(--reverb <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--chorus <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--tail <SECONDS> "Time after the last note, while it rings out")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(
+                arg!(--"mute-track" <TRACK> "Leave a track out, by index or name")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_parser(parse_track),
+            )
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        Self {
+            midi: matches.get_one::<PathBuf>("MIDI_FILE").unwrap().clone(),
+            out: matches.get_one::<PathBuf>("OUT_FILE").unwrap().clone(),
+            soundfont: matches.get_one::<PathBuf>("soundfont").cloned(),
+            sample_rate: matches
+                .get_one::<u32>("sample-rate")
+                .copied()
+                .unwrap_or(44100),
+            gain: matches.get_one::<f32>("gain").copied().unwrap_or(0.5),
+            reverb: matches.get_one::<bool>("reverb").copied().unwrap_or(true),
+            chorus: matches.get_one::<bool>("chorus").copied().unwrap_or(true),
+            tail: matches
+                .get_one::<Duration>("tail")
+                .copied()
+                .unwrap_or(Duration::from_secs(2)),
+            mute_tracks: matches
+                .get_many::<TrackSelector>("mute-track")
+                .map(|tracks| tracks.cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Arguments of the `batch` subcommand
+#[derive(Debug, Clone)]
+pub struct BatchArgs {
+    /// Directory of MIDI files, or a text file listing one per line
+    pub input: PathBuf,
+    pub out_dir: PathBuf,
+    /// Video file extension, picks the container
+    #[cfg(feature = "ffmpeg")]
+    pub extension: String,
+    pub jobs: usize,
+    pub report: PathBuf,
+    /// Shared options, `midi` and `out` are replaced for every song
+    pub video: Args,
+}
+
+impl BatchArgs {
+    fn command() -> Command {
+        let command = Command::new("batch")
+            .about("Render every MIDI file of a directory or a list file with the same options")
+            .arg(
+                arg!([INPUT] "Directory of MIDI files, or a text file with one path per line")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!([OUT_DIR])
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(-j --jobs <JOBS> "Renders running at the same time, 2 by default")
+                    .required(false)
+                    .value_parser(value_parser!(u32).range(1..=64)),
+            )
+            .arg(
+                arg!(--report <JSON_FILE>)
+                    .help("Where to write the report, OUT_DIR/report.json by default")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            );
+
+        #[cfg(feature = "ffmpeg")]
+        let command = command.arg(
+            arg!(--ext <EXT> "Extension of the videos, mp4 by default")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        );
+
+        Args::command(command)
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let input = matches.get_one::<PathBuf>("INPUT").unwrap().clone();
+        let out_dir = matches.get_one::<PathBuf>("OUT_DIR").unwrap().clone();
+        #[cfg(feature = "ffmpeg")]
+        let extension = matches
+            .get_one::<String>("ext")
+            .map(|ext| ext.trim_start_matches('.').to_string())
+            .unwrap_or_else(|| "mp4".into());
+
+        // Stands in for the song outputs, the extension picks the alpha codec
+        let song_out = out_dir.join("song");
+        #[cfg(feature = "ffmpeg")]
+        let song_out = song_out.with_extension(&extension);
+
+        Ok(Self {
+            jobs: matches.get_one::<u32>("jobs").copied().unwrap_or(2) as usize,
+            report: matches
+                .get_one::<PathBuf>("report")
+                .cloned()
+                .unwrap_or_else(|| out_dir.join("report.json")),
+            video: Args::from_matches(matches, input.clone(), song_out)?,
+            input,
+            out_dir,
+            #[cfg(feature = "ffmpeg")]
+            extension,
+        })
+    }
+}
+
+fn parse_gain(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|gain| (0.0..=10.0).contains(gain))
+        .ok_or_else(|| format!("invalid gain: {value}, expected 0 to 10"))
+}
+
+impl Mode {
     pub fn get() -> Self {
-        let matches = Command::new("Neothesia")
+        let command = Command::new("Neothesia")
             .about("MIDI visualization to video encoder")
+            .subcommand(AudioArgs::command())
+            .subcommand(BatchArgs::command())
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
             .arg(
                 arg!([MIDI_FILE])
                     .required(true)
@@ -23,36 +586,301 @@ impl Args {
                 arg!([MP4_FILE])
                     .required(true)
                     .value_parser(value_parser!(PathBuf)),
+            );
+
+        let mut command = Args::command(command);
+        let matches = command.get_matches_mut();
+
+        match matches.subcommand() {
+            Some(("audio", matches)) => Ok(Self::Audio(AudioArgs::from_matches(matches))),
+            Some(("batch", matches)) => BatchArgs::from_matches(matches).map(Self::Batch),
+            _ => Args::from_matches(
+                &matches,
+                matches.get_one::<PathBuf>("MIDI_FILE").unwrap().clone(),
+                matches.get_one::<PathBuf>("MP4_FILE").unwrap().clone(),
             )
+            .map(Self::Video),
+        }
+        .unwrap_or_else(|err| err.format(&mut command).exit())
+    }
+}
+
+impl Args {
+    /// Rendering options, shared by single and batch renders
+    fn command(command: Command) -> Command {
+        let command = command
             .arg(
                 arg!(--soundfont <SF2_FILE>)
                     .required(false)
                     .value_parser(value_parser!(PathBuf)),
             )
-            .arg(arg!(--width <PIXELS>).required(false))
-            .arg(arg!(--height <PIXELS>).required(false))
-            .get_matches();
+            .arg(
+                arg!(--width <PIXELS>)
+                    .required(false)
+                    .value_parser(parse_dimension),
+            )
+            .arg(
+                arg!(--height <PIXELS>)
+                    .required(false)
+                    .value_parser(parse_dimension),
+            )
+            .arg(
+                arg!(--fps <FPS> "Frames per second")
+                    .required(false)
+                    .value_parser(value_parser!(u32).range(1..=240)),
+            )
+            .arg(
+                arg!(--format <FORMAT>)
+                    .help(
+                        "Output format: ffmpeg (video file) or png (directory of frames and a WAV \
+                         file)",
+                    )
+                    .required(false)
+                    .value_parser(parse_format),
+            )
+            .arg(arg!(--transparent "Render with a transparent background (prores, vp9 or png)"))
+            .arg(
+                arg!(--start <TIME> "Start of the clip, in seconds (eg. 12.5) or bars (eg. 9b)")
+                    .required(false)
+                    .value_parser(parse_song_time),
+            )
+            .arg(
+                arg!(--end <TIME> "End of the clip, in seconds (eg. 42) or bars (eg. 17b)")
+                    .required(false)
+                    .value_parser(parse_song_time),
+            )
+            .arg(
+                arg!(--"lead-in" <SECONDS> "Time before the start, while the first notes fall")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(
+                arg!(--tail <SECONDS> "Time after the end, while the last notes ring out")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(
+                arg!(--config <RON_FILE> "Settings file to use instead of the app settings")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--range <RANGE> "Keyboard range as MIDI notes, eg. 21-108")
+                    .required(false)
+                    .value_parser(parse_range),
+            )
+            .arg(
+                arg!(--background <COLOR> "Background color, eg. #000000")
+                    .required(false)
+                    .value_parser(parse_color),
+            )
+            .arg(
+                arg!(--colors <COLORS> "Track colors, eg. #d259de,#5dbcff")
+                    .required(false)
+                    .value_parser(parse_colors),
+            )
+            .arg(
+                arg!(--"vertical-guidelines" <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--"horizontal-guidelines" <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--glow <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--"note-labels" <BOOL>)
+                    .required(false)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                arg!(--"animation-speed" <SPEED> "Falling speed of the notes, pixels per second")
+                    .required(false)
+                    .value_parser(value_parser!(f32)),
+            )
+            .arg(
+                arg!(--"hide-track" <TRACK> "Hide a track from the waterfall, by index or name")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_parser(parse_track),
+            )
+            .arg(
+                arg!(--"show-track" <TRACK> "Show a track hidden by default, like percussion")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_parser(parse_track),
+            )
+            .arg(
+                arg!(--"mute-track" <TRACK> "Leave a track out of the audio, by index or name")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_parser(parse_track),
+            )
+            .arg(
+                arg!(--"title-card" <SECONDS>)
+                    .help("Show the song name and composer for this long at the start")
+                    .required(false)
+                    .value_parser(parse_seconds),
+            )
+            .arg(
+                arg!(--title <TEXT> "Song name, the MIDI sequence name or file name by default")
+                    .required(false),
+            )
+            .arg(
+                arg!(--composer <TEXT> "Composer, taken from the MIDI text events by default")
+                    .required(false),
+            )
+            .arg(
+                arg!(--caption <TEXT>)
+                    .help("Text in the top left corner, {title} and {composer} are replaced")
+                    .required(false),
+            )
+            .arg(arg!(--"progress-bar" "Show the song progress at the top"))
+            .arg(arg!(--"bar-counter" "Show the current bar and beat in the top right corner"));
 
-        let width = matches
-            .get_one::<String>("width")
-            .map(|w| w.parse::<u32>().unwrap())
-            .unwrap_or(1920);
-        let height = matches
-            .get_one::<String>("height")
-            .map(|w| w.parse::<u32>().unwrap())
-            .unwrap_or(1080);
+        #[cfg(feature = "ffmpeg")]
+        let command = FfmpegArgs::command(command);
 
-        if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
-            eprintln!("width and height must be a multiple of two");
-            std::process::exit(1);
-        }
+        command
+    }
 
-        Self {
-            midi: matches.get_one::<PathBuf>("MIDI_FILE").unwrap().clone(),
-            out: matches.get_one::<PathBuf>("MP4_FILE").unwrap().clone(),
+    fn from_matches(
+        matches: &clap::ArgMatches,
+        midi: PathBuf,
+        out: PathBuf,
+    ) -> Result<Self, clap::Error> {
+        let width = matches.get_one::<u32>("width").copied().unwrap_or(1920);
+        let height = matches.get_one::<u32>("height").copied().unwrap_or(1080);
+
+        let transparent = matches.get_flag("transparent");
+        let format = matches
+            .get_one::<OutputFormat>("format")
+            .copied()
+            .unwrap_or(OutputFormat::DEFAULT);
+
+        #[cfg(feature = "ffmpeg")]
+        let ffmpeg =
+            FfmpegArgs::from_matches(matches, &out, transparent && format == OutputFormat::Ffmpeg)?;
+
+        let tracks = |id: &str| {
+            matches
+                .get_many::<TrackSelector>(id)
+                .map(|tracks| tracks.cloned().collect())
+                .unwrap_or_default()
+        };
+
+        Ok(Self {
+            midi,
+            out,
             soundfont: matches.get_one::<PathBuf>("soundfont").cloned(),
             width,
             height,
+            fps: matches.get_one::<u32>("fps").copied().unwrap_or(60),
+            format,
+            #[cfg(feature = "ffmpeg")]
+            ffmpeg,
+            transparent,
+            start: matches.get_one::<SongTime>("start").copied(),
+            end: matches.get_one::<SongTime>("end").copied(),
+            lead_in: matches
+                .get_one::<Duration>("lead-in")
+                .copied()
+                .unwrap_or(Duration::from_secs(3)),
+            tail: matches
+                .get_one::<Duration>("tail")
+                .copied()
+                .unwrap_or(Duration::from_secs(1)),
+            settings: Settings {
+                path: matches.get_one::<PathBuf>("config").cloned(),
+                range: matches.get_one::<(u8, u8)>("range").copied(),
+                background: matches.get_one::<(u8, u8, u8)>("background").copied(),
+                colors: matches.get_one::<Vec<(u8, u8, u8)>>("colors").cloned(),
+                vertical_guidelines: matches.get_one::<bool>("vertical-guidelines").copied(),
+                horizontal_guidelines: matches.get_one::<bool>("horizontal-guidelines").copied(),
+                glow: matches.get_one::<bool>("glow").copied(),
+                note_labels: matches.get_one::<bool>("note-labels").copied(),
+                animation_speed: matches.get_one::<f32>("animation-speed").copied(),
+            },
+            hide_tracks: tracks("hide-track"),
+            show_tracks: tracks("show-track"),
+            mute_tracks: tracks("mute-track"),
+            overlay: OverlayArgs {
+                title_card: matches.get_one::<Duration>("title-card").copied(),
+                title: matches.get_one::<String>("title").cloned(),
+                composer: matches.get_one::<String>("composer").cloned(),
+                caption: matches.get_one::<String>("caption").cloned(),
+                progress_bar: matches.get_flag("progress-bar"),
+                bar_counter: matches.get_flag("bar-counter"),
+            },
+        })
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    pub fn encoder_options(&self) -> EncoderOptions {
+        EncoderOptions {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            codec: self.ffmpeg.codec,
+            quality: self.ffmpeg.quality,
+            pix_fmt: self.ffmpeg.pix_fmt.clone(),
+            alpha: self.transparent,
+            audio_bitrate: self.ffmpeg.audio_bitrate,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "ffmpeg")]
+    #[test]
+    fn bitrate() {
+        assert_eq!(parse_bitrate("8M"), Ok(8_000_000));
+        assert_eq!(parse_bitrate("192k"), Ok(192_000));
+        assert_eq!(parse_bitrate("1.5m"), Ok(1_500_000));
+        assert_eq!(parse_bitrate("400000"), Ok(400_000));
+        assert!(parse_bitrate("fast").is_err());
+        assert!(parse_bitrate("0").is_err());
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#d259de"), Ok((210, 89, 222)));
+        assert_eq!(parse_color("5DBCFF"), Ok((93, 188, 255)));
+        assert!(parse_color("#fff").is_err());
+        assert!(parse_color("#gg0000").is_err());
+        assert_eq!(
+            parse_colors("#000000, #ffffff"),
+            Ok(vec![(0, 0, 0), (255, 255, 255)])
+        );
+        assert_eq!(parse_range("21-108"), Ok((21, 108)));
+        assert!(parse_range("108-21").is_err());
+    }
+
+    #[test]
+    fn song_time() {
+        let secs = |secs| SongTime::Seconds(Duration::from_secs_f64(secs));
+        assert_eq!(parse_song_time("12.5"), Ok(secs(12.5)));
+        assert_eq!(parse_song_time("3s"), Ok(secs(3.0)));
+        assert_eq!(parse_song_time("9b"), Ok(SongTime::Bar(9)));
+        assert!(parse_song_time("0b").is_err());
+        assert!(parse_song_time("-1").is_err());
+        assert!(parse_song_time("bar").is_err());
+
+        let measures = [0, 2, 4].map(Duration::from_secs);
+        assert_eq!(SongTime::Bar(1).resolve(&measures), Ok(Duration::ZERO));
+        assert_eq!(
+            SongTime::Bar(3).resolve(&measures),
+            Ok(Duration::from_secs(4))
+        );
+        assert!(SongTime::Bar(4).resolve(&measures).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Minimal FLAC encoder: 16-bit stereo, fixed predictors and a single Rice partition per subframe.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+/// `fLaC` marker plus the STREAMINFO block header
+const STREAMINFO_OFFSET: u64 = 8;
+
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    left: Vec<i32>,
+    right: Vec<i32>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"fLaC")?;
+
+        let mut writer = Self {
+            out,
+            sample_rate,
+            left: Vec::with_capacity(BLOCK_SIZE),
+            right: Vec::with_capacity(BLOCK_SIZE),
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: u32::MAX,
+            max_frame_size: 0,
+        };
+        writer.write_streaminfo()?;
+
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, l: &[f32], r: &[f32]) -> io::Result<()> {
+        for (l, r) in l.iter().zip(r) {
+            self.left.push(to_i16(*l));
+            self.right.push(to_i16(*r));
+
+            if self.left.len() == BLOCK_SIZE {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.left.is_empty() {
+            self.write_frame()?;
+        }
+
+        self.out.seek(SeekFrom::Start(STREAMINFO_OFFSET - 4))?;
+        self.write_streaminfo()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Written twice, with zero sizes at first and with the real ones once the stream is done
+    fn write_streaminfo(&mut self) -> io::Result<()> {
+        let mut bits = BitWriter::default();
+        // Last metadata block, type 0 (STREAMINFO), 34 bytes long
+        bits.put(1, 1);
+        bits.put(0, 7);
+        bits.put(34, 24);
+
+        bits.put(BLOCK_SIZE as u64, 16);
+        bits.put(BLOCK_SIZE as u64, 16);
+        let min_frame_size = if self.min_frame_size == u32::MAX {
+            0
+        } else {
+            self.min_frame_size
+        };
+        bits.put(min_frame_size as u64, 24);
+        bits.put(self.max_frame_size as u64, 24);
+        bits.put(self.sample_rate as u64, 20);
+        bits.put(2 - 1, 3);
+        bits.put(BITS_PER_SAMPLE as u64 - 1, 5);
+        bits.put(self.total_samples, 36);
+        // MD5 of the audio is optional, zero means unknown
+        bits.put(0, 64);
+        bits.put(0, 64);
+
+        self.out.write_all(&bits.finish())
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let len = self.left.len();
+        let mut bits = BitWriter::default();
+
+        // Sync code and fixed blocksize stream
+        bits.put(0b11_1111_1111_1110, 14);
+        bits.put(0, 1);
+        bits.put(0, 1);
+        // Block size: 4096, or 16-bit value at the end of the header
+        bits.put(if len == BLOCK_SIZE { 0b1100 } else { 0b0111 }, 4);
+        // Sample rate from STREAMINFO
+        bits.put(0, 4);
+        // Left/right channels
+        bits.put(0b0001, 4);
+        // 16 bits per sample
+        bits.put(0b100, 3);
+        bits.put(0, 1);
+        bits.put_utf8(self.frame_number);
+        if len != BLOCK_SIZE {
+            bits.put(len as u64 - 1, 16);
+        }
+        let crc = crc8(&bits.bytes);
+        bits.put(crc as u64, 8);
+
+        write_subframe(&mut bits, &self.left);
+        write_subframe(&mut bits, &self.right);
+
+        let mut frame = bits.finish();
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+
+        self.out.write_all(&frame)?;
+
+        self.min_frame_size = self.min_frame_size.min(frame.len() as u32);
+        self.max_frame_size = self.max_frame_size.max(frame.len() as u32);
+        self.frame_number += 1;
+        self.total_samples += len as u64;
+        self.left.clear();
+        self.right.clear();
+
+        Ok(())
+    }
+}
+
+fn to_i16(sample: f32) -> i32 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i32
+}
+
+/// Residual of the fixed polynomial predictor of `order` for every sample after the warm-up ones
+fn fixed_residual(samples: &[i32], order: usize) -> impl Iterator<Item = i32> + '_ {
+    (order..samples.len()).map(move |i| {
+        let s = |back: usize| samples[i - back];
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    })
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// Rice parameter for the residual and the approximate size in bits of the residual coded with it
+fn rice_parameter(residual: impl Iterator<Item = i32>) -> (u32, u64) {
+    let (count, sum) = residual.fold((0u64, 0u64), |(count, sum), r| {
+        (count + 1, sum + zigzag(r) as u64)
+    });
+
+    // Optimal parameter is close to log2 of the mean value
+    let mean = sum / count.max(1);
+    let k = (u64::BITS - mean.leading_zeros()).saturating_sub(1).min(14);
+    (k, count * (k as u64 + 1) + (sum >> k))
+}
+
+fn write_subframe(bits: &mut BitWriter, samples: &[i32]) {
+    let max_order = samples.len().saturating_sub(1).min(4);
+
+    let (order, k, _) = (0..=max_order)
+        .map(|order| {
+            let (k, residual_bits) = rice_parameter(fixed_residual(samples, order));
+            let size = residual_bits + order as u64 * BITS_PER_SAMPLE as u64;
+            (order, k, size)
+        })
+        .min_by_key(|(_, _, size)| *size)
+        .unwrap();
+
+    // Zero padding bit, SUBFRAME_FIXED of `order`, no wasted bits
+    bits.put(0, 1);
+    bits.put(0b001000 | order as u64, 6);
+    bits.put(0, 1);
+
+    for sample in &samples[..order] {
+        bits.put(*sample as u64 & 0xffff, BITS_PER_SAMPLE);
+    }
+
+    // Rice coding with 4-bit parameters, a single partition
+    bits.put(0b00, 2);
+    bits.put(0, 4);
+    bits.put(k as u64, 4);
+
+    for residual in fixed_residual(samples, order) {
+        let value = zigzag(residual);
+        bits.put_unary(value >> k);
+        bits.put(value as u64 & ((1 << k) - 1), k);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    /// Appends the low `count` bits of `value`, most significant first
+    fn put(&mut self, value: u64, count: u32) {
+        if count > 32 {
+            self.put(value >> 32, count - 32);
+            self.put(value & 0xffff_ffff, 32);
+            return;
+        }
+
+        self.acc = (self.acc << count) | (value & ((1 << count) - 1));
+        self.len += count;
+
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.acc >> self.len) as u8);
+        }
+    }
+
+    /// `value` zeros followed by a one
+    fn put_unary(&mut self, mut value: u32) {
+        while value >= 32 {
+            self.put(0, 32);
+            value -= 32;
+        }
+        self.put(1, value + 1);
+    }
+
+    /// Frame number in the UTF-8 like coding of FLAC frame headers
+    fn put_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.put(value, 8);
+            return;
+        }
+
+        let continuation_bytes = match value {
+            0x80..0x800 => 1,
+            0x800..0x1_0000 => 2,
+            0x1_0000..0x20_0000 => 3,
+            0x20_0000..0x400_0000 => 4,
+            0x400_0000..0x8000_0000 => 5,
+            _ => 6,
+        };
+
+        let lead_ones = (0xff00u64 >> (continuation_bytes + 1)) & 0xff;
+        self.put(lead_ones | (value >> (6 * continuation_bytes)), 8);
+        for i in (0..continuation_bytes).rev() {
+            self.put(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+        }
+    }
+
+    /// Pads to a whole byte with zeros
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.put(0, 8 - self.len);
+        }
+        self.bytes
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_back() {
+        let sample_rate = 44100;
+        // A bit more than two blocks, so the last frame has an odd size
+        let len = BLOCK_SIZE * 2 + 1000;
+        let left: Vec<f32> = (0..len)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / sample_rate as f32).sin() * 0.5)
+            .collect();
+        let right: Vec<f32> = (0..len)
+            .map(|i| if i % 100 < 50 { 0.25 } else { -0.25 })
+            .collect();
+
+        let path = std::env::temp_dir().join(format!(
+            "neothesia-cli-{}-decodes-back.flac",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut flac = FlacWriter::new(std::io::BufWriter::new(file), sample_rate).unwrap();
+        flac.write(&left, &right).unwrap();
+        flac.finish().unwrap();
+
+        let probed = symphonium::probe_from_file(&path, None).unwrap();
+        let decoded =
+            symphonium::decode_f32(probed, &Default::default(), None, None, None).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(decoded.sample_rate.get(), sample_rate);
+        assert_eq!(decoded.data.len(), 2);
+
+        for (channel, expected) in decoded.data.iter().zip([&left, &right]) {
+            assert_eq!(channel.len(), len);
+            for (decoded, expected) in channel.iter().zip(expected.iter()) {
+                let decoded = (decoded * 32768.0).round() as i32;
+                assert_eq!(decoded, to_i16(*expected));
+            }
+        }
+    }
+}
@@ -1,22 +1,42 @@
-use std::{default::Default, time::Duration};
+use std::{
+    default::Default,
+    ops::Range,
+    time::{Duration, Instant},
+};
 
 use midi_file::midly;
 use neothesia_core::{
     config::Config,
     piano_layout,
     render::{
-        GuidelineRenderer, KeyboardRenderer, NoteLabels, QuadRenderer, QuadRendererFactory,
-        TextRenderer, TextRendererFactory, WaterfallRenderer,
+        GlowRenderer, GuidelineRenderer, KEYBOARD_HEIGHT_RATIO, KeyboardRenderer, NoteLabels,
+        QuadRenderer, QuadRendererFactory, TextRenderer, TextRendererFactory, WaterfallRenderer,
     },
 };
 use wgpu_jumpstart::{Gpu, TransformUniform, Uniform, wgpu};
 
+mod audio;
+mod batch;
 mod cli;
+mod flac;
+mod output;
+mod overlay;
+mod pipeline;
+mod wav;
+
+const SAMPLE_RATE: usize = 44100;
+/// Frames the GPU works on while earlier ones are read back
+const STAGING_BUFFERS: usize = 3;
+/// Frames waiting for the encoder thread
+const ENCODER_QUEUE_LEN: usize = 8;
 
 struct Recorder {
     gpu: Gpu,
 
     playback: midi_file::PlaybackState,
+    /// Rendered part of the song, without lead-in and tail
+    clip: Range<Duration>,
+    tail: Duration,
 
     quad_renderer_bg: QuadRenderer,
     quad_renderer_fg: QuadRenderer,
@@ -25,10 +45,14 @@ struct Recorder {
     text: TextRenderer,
     guidelines: GuidelineRenderer,
     note_labels: Option<NoteLabels>,
+    glow: Option<GlowRenderer>,
+    overlay: Option<overlay::Overlay>,
 
     config: Config,
     width: u32,
     height: u32,
+    transparent: bool,
+    muted_tracks: Vec<usize>,
 
     synth: oxisynth::Synth,
 }
@@ -40,7 +64,7 @@ fn get_layout(
 ) -> piano_layout::KeyboardLayout {
     let white_count = range.white_count();
     let neutral_width = width / white_count as f32;
-    let neutral_height = height * 0.2;
+    let neutral_height = height * KEYBOARD_HEIGHT_RATIO;
 
     piano_layout::KeyboardLayout::from_range(
         piano_layout::Sizing::new(neutral_width, neutral_height),
@@ -53,20 +77,52 @@ fn time_without_lead_in(playback: &midi_file::PlaybackState) -> f32 {
 }
 
 impl Recorder {
-    fn new(args: &cli::Args) -> Self {
+    fn new(args: &cli::Args) -> Result<Self, String> {
+        let midi = midi_file::MidiFile::new(&args.midi)
+            .map_err(|err| format!("Error loading MIDI file: {err}"))?;
+
+        let config = args
+            .settings
+            .load()
+            .map_err(|err| format!("Error loading config: {err}"))?;
+
+        let selected = |selectors: &[cli::TrackSelector]| select_tracks(selectors, &midi.tracks);
+
+        // Percussion is hidden by default, like in the app
+        let shown_tracks = selected(&args.show_tracks)?;
+        let hidden_tracks: Vec<usize> = midi
+            .tracks
+            .iter()
+            .filter(|track| {
+                let is_drums = track.has_drums && !track.has_other_than_drums;
+                is_drums && !shown_tracks.contains(&track.track_id)
+            })
+            .map(|track| track.track_id)
+            .chain(selected(&args.hide_tracks)?)
+            .collect();
+        let muted_tracks = selected(&args.mute_tracks)?;
+
+        let mut playback = midi_file::PlaybackState::new(args.lead_in, midi.tracks.clone());
+
+        let resolve = |time: Option<cli::SongTime>, default: Duration| {
+            time.map_or(Ok(default), |time| time.resolve(&midi.measures))
+        };
+        let clip =
+            resolve(args.start, Duration::ZERO)?..resolve(args.end, *playback.last_note_end())?;
+
+        if clip.is_empty() {
+            return Err("--end has to be after --start".into());
+        }
+
         let instance =
             wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
-        let gpu = pollster::block_on(Gpu::new(instance, None)).unwrap_or_else(|err| {
-            eprintln!("Failed to initialize GPU: {err}");
-            std::process::exit(1);
-        });
+        let gpu = pollster::block_on(Gpu::new(instance, None))
+            .map_err(|err| format!("Failed to initialize GPU: {err}"))?;
 
-        let midi = midi_file::MidiFile::new(&args.midi).unwrap_or_else(|err| {
-            eprintln!("Error loading MIDI file: {err}");
-            std::process::exit(1);
-        });
-
-        let config = Config::new();
+        // Replay programs and controllers from before the clip, then rewind by the lead-in
+        playback.set_time(clip.start + args.lead_in);
+        let channel_state: Vec<_> = playback.channel_state().into_iter().cloned().collect();
+        playback.set_time(clip.start);
 
         let width = args.width;
         let height = args.height;
@@ -104,31 +160,33 @@ impl Recorder {
 
         let mut waterfall = WaterfallRenderer::new(
             &gpu,
-            &midi.tracks,
-            &[],
+            &clip_tracks(&midi.tracks, &clip),
+            &hidden_tracks,
             &config,
             &transform_uniform,
             keyboard_layout,
         );
 
-        let playback = midi_file::PlaybackState::new(Duration::from_secs(3), midi.tracks.clone());
-
         waterfall.update(time_without_lead_in(&playback));
 
         let text_renderer_factory = TextRendererFactory::new(&gpu);
         let text = text_renderer_factory.new_renderer();
 
         let mut synth = oxisynth::Synth::new(oxisynth::SynthDescriptor {
-            sample_rate: 44100.0,
+            sample_rate: SAMPLE_RATE as f32,
             gain: 0.5,
             ..Default::default()
         })
-        .unwrap();
+        .map_err(|err| format!("Error creating synth: {err:?}"))?;
 
         if let Some(sf2) = args.soundfont.as_ref() {
-            let mut file = std::fs::File::open(sf2).unwrap();
-            let font = oxisynth::SoundFont::load(&mut file).unwrap();
-            synth.add_font(font, true);
+            synth.add_font(load_soundfont(sf2)?, true);
+        }
+
+        for event in channel_state.iter() {
+            synth
+                .send_event(libmidi_to_oxisynth_event(event.channel, event.message))
+                .ok();
         }
 
         let note_labels = config.note_labels().then_some(NoteLabels::new(
@@ -137,10 +195,28 @@ impl Recorder {
             text_renderer_factory.new_renderer(),
         ));
 
-        Self {
+        let glow = config
+            .glow()
+            .then(|| GlowRenderer::new(&gpu, &transform_uniform, keyboard.layout()));
+
+        let overlay = args.overlay.is_enabled().then(|| {
+            overlay::Overlay::new(
+                &args.overlay,
+                &midi,
+                &config,
+                (width, height),
+                keyboard.pos().y,
+                quad_renderer_factory.new_renderer(),
+                text_renderer_factory.new_renderer(),
+            )
+        });
+
+        Ok(Self {
             gpu,
 
             playback,
+            clip,
+            tail: args.tail,
 
             quad_renderer_bg,
             quad_renderer_fg,
@@ -149,18 +225,30 @@ impl Recorder {
             text,
             guidelines,
             note_labels,
+            glow,
+            overlay,
 
             config,
             width,
             height,
+            transparent: args.transparent,
+            muted_tracks,
 
             synth,
-        }
+        })
     }
 
     fn update(&mut self, delta: Duration) {
-        let events = self.playback.update(delta);
-        file_midi_events(&mut self.synth, &mut self.keyboard, &self.config, &events);
+        let clip = self.clip.clone();
+        file_midi_events(
+            &mut self.synth,
+            &mut self.keyboard,
+            &self.config,
+            &self.muted_tracks,
+            self.playback
+                .update(delta)
+                .filter(|event| is_in_clip(event, &clip)),
+        );
 
         let time = time_without_lead_in(&self.playback);
 
@@ -180,8 +268,13 @@ impl Recorder {
         self.keyboard
             .update(&mut self.quad_renderer_fg, &mut self.text);
 
+        self.update_glow(delta);
+
         self.quad_renderer_bg.prepare();
         self.quad_renderer_fg.prepare();
+        if let Some(glow) = self.glow.as_mut() {
+            glow.prepare();
+        }
 
         if let Some(note_labels) = self.note_labels.as_mut() {
             note_labels.update(
@@ -200,6 +293,57 @@ impl Recorder {
             neothesia_core::dpi::PhysicalSize::new(self.width, self.height),
             1.0,
         );
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            let start = self.clip.start.as_secs_f32();
+            let end = self.clip.end.as_secs_f32();
+            overlay.update(
+                self.playback.time().saturating_sub(self.clip.start),
+                time,
+                (time - start) / (end - start),
+            );
+        }
+    }
+
+    fn update_glow(&mut self, delta: Duration) {
+        let Some(glow) = &mut self.glow else {
+            return;
+        };
+
+        glow.clear();
+
+        let keys = &self.keyboard.layout().keys;
+        let states = self.keyboard.key_states();
+
+        for (key, state) in keys.iter().zip(states) {
+            let Some(color) = state.pressed_by_file() else {
+                continue;
+            };
+
+            glow.push(
+                key.id(),
+                *color,
+                key.x(),
+                self.keyboard.pos().y,
+                key.width(),
+                delta,
+            );
+        }
+    }
+
+    /// Playback time at which the tail is over
+    fn end_time(&self) -> Duration {
+        self.clip.end + *self.playback.leed_in() + self.tail
+    }
+
+    fn is_finished(&self) -> bool {
+        self.playback.time() >= self.end_time()
+    }
+
+    fn percentage(&self) -> f32 {
+        let start = self.clip.start.as_secs_f32();
+        let end = self.end_time().as_secs_f32();
+        (self.playback.time().as_secs_f32() - start) / (end - start)
     }
 
     fn render(
@@ -208,9 +352,14 @@ impl Recorder {
         view: &wgpu::TextureView,
         texture_desc: &wgpu::TextureDescriptor<'_>,
         output_buffer: &wgpu::Buffer,
-    ) {
-        let bg_color = self.config.background_color();
-        let bg_color = wgpu_jumpstart::Color::from(bg_color).into_linear_wgpu_color();
+        bytes_per_row: u32,
+    ) -> wgpu::SubmissionIndex {
+        let bg_color = if self.transparent {
+            wgpu::Color::TRANSPARENT
+        } else {
+            let bg_color = self.config.background_color();
+            wgpu_jumpstart::Color::from(bg_color).into_linear_wgpu_color()
+        };
 
         {
             let rpass = self
@@ -240,12 +389,16 @@ impl Recorder {
                 note_labels.render(&mut rpass);
             }
             self.quad_renderer_fg.render(&mut rpass);
+            if let Some(glow) = &self.glow {
+                glow.render(&mut rpass);
+            }
             self.text.render(&mut rpass);
+            if let Some(overlay) = &self.overlay {
+                overlay.render(&mut rpass);
+            }
         }
 
         {
-            let u32_size = std::mem::size_of::<u32>() as u32;
-
             self.gpu.encoder.copy_texture_to_buffer(
                 wgpu::TexelCopyTextureInfo {
                     texture,
@@ -257,25 +410,78 @@ impl Recorder {
                     buffer: output_buffer,
                     layout: wgpu::TexelCopyBufferLayout {
                         offset: 0,
-                        bytes_per_row: Some(u32_size * self.width),
+                        bytes_per_row: Some(bytes_per_row),
                         rows_per_image: Some(self.height),
                     },
                 },
                 texture_desc.size,
             );
 
-            self.gpu.submit();
+            self.gpu.submit()
+        }
+    }
+}
+
+/// Ids of the tracks matching any of `selectors`, fails when one of them matches nothing
+fn select_tracks(
+    selectors: &[cli::TrackSelector],
+    tracks: &[midi_file::MidiTrack],
+) -> Result<Vec<usize>, String> {
+    if let Some(selector) = selectors
+        .iter()
+        .find(|selector| !tracks.iter().any(|track| selector.matches(track)))
+    {
+        let mut err = format!("No track matches {selector:?}, the song has:");
+        for track in tracks.iter().filter(|track| !track.notes.is_empty()) {
+            err += &format!(
+                "\n  {}: {} ({})",
+                track.track_id,
+                track.name.as_deref().unwrap_or("-"),
+                cli::instrument_name(track)
+            );
         }
+        return Err(err);
     }
+
+    Ok(tracks
+        .iter()
+        .filter(|track| selectors.iter().any(|s| s.matches(track)))
+        .map(|track| track.track_id)
+        .collect())
+}
+
+fn load_soundfont(path: &std::path::Path) -> Result<oxisynth::SoundFont, String> {
+    std::fs::File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|mut file| oxisynth::SoundFont::load(&mut file).map_err(|err| format!("{err:?}")))
+        .map_err(|err| format!("Error loading {}: {err}", path.display()))
 }
 
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("neothesia=info"))
         .init();
 
-    let args = cli::Args::get();
+    match cli::Mode::get() {
+        cli::Mode::Video(args) => render_video(&args, true).map(|_| ()),
+        cli::Mode::Audio(args) => audio::render(&args),
+        cli::Mode::Batch(args) => batch::render(&args),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+}
 
-    let mut recorder = Recorder::new(&args);
+/// What a finished render produced
+pub struct RenderStats {
+    pub frames: usize,
+    /// Length of the rendered video
+    pub duration: Duration,
+}
+
+/// Renders the video described by `args`, progress goes to stdout when `progress` is set
+fn render_video(args: &cli::Args, progress: bool) -> Result<RenderStats, String> {
+    let mut recorder = Recorder::new(args)?;
 
     let texture_desc = wgpu::TextureDescriptor {
         size: wgpu::Extent3d {
@@ -304,107 +510,203 @@ fn main() {
         usage: None,
     });
 
-    let u32_size = std::mem::size_of::<u32>() as u32;
-    let output_buffer_size = (u32_size * recorder.width * recorder.height) as wgpu::BufferAddress;
+    let mut readback = pipeline::Readback::new(
+        &recorder.gpu.device,
+        recorder.width,
+        recorder.height,
+        STAGING_BUFFERS,
+    );
 
-    let output_buffer_desc = wgpu::BufferDescriptor {
-        size: output_buffer_size,
-        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-        label: None,
-        mapped_at_creation: false,
-    };
+    let output_error = |err: output::Error| format!("Error writing {}: {err}", args.out.display());
+    let mut encoder = pipeline::Encoder::spawn(args, ENCODER_QUEUE_LEN).map_err(output_error)?;
 
-    let (encoder_info, mut encoder) =
-        ffmpeg_encoder::new(&args.out, recorder.width, recorder.height);
+    let frame_size = encoder.audio_frame_size();
 
-    let frame_size = encoder_info.frame_size;
+    let start = Instant::now();
+    let mut render_time = Duration::ZERO;
+    let mut readback_time = Duration::ZERO;
 
-    let start = std::time::Instant::now();
-
-    let frame_time = Duration::from_secs(1) / 60;
-    const SAMPLE_TIME: usize = 44100 / 60;
+    let fps = args.fps as usize;
+    let frame_time = Duration::from_secs(1) / args.fps;
 
     let mut audio_buffer_l: Vec<f32> = Vec::with_capacity(frame_size);
     let mut audio_buffer_r: Vec<f32> = Vec::with_capacity(frame_size);
 
-    println!("Encoding started:");
-    let mut n = 1;
-    while recorder.playback.percentage() * 100.0 < 101.0 {
-        let output_buffer = recorder.gpu.device.create_buffer(&output_buffer_desc);
+    if progress {
+        println!("Encoding started:");
+    }
+    // Frames rendered and frames handed to the encoder
+    let mut n = 0;
+    let mut encoded = 0;
+    loop {
+        let finished = recorder.is_finished();
+
+        // Read back the oldest frame once its buffer is needed again, or all of them at the end
+        if readback.is_full() || finished {
+            let readback_start = Instant::now();
+            let mut frame = encoder.frame_buffer();
+            let read = readback
+                .read(&recorder.gpu.device, &mut frame)
+                .map_err(|err| format!("Error reading a frame back from the GPU: {err}"))?;
+            if !read {
+                break;
+            }
+            readback_time += readback_start.elapsed();
+
+            encoder.video(frame).map_err(output_error)?;
+            encoded += 1;
+
+            if progress {
+                let stage_fps = |time: Duration| encoded as f32 / time.as_secs_f32().max(0.001);
+                print!(
+                    "\r Encoded {} frames ({}s, {}%) in {}s, render {:.0} fps, read back {:.0} fps, encode {:.0} fps",
+                    encoded,
+                    (encoded as f32 / fps as f32).round(),
+                    (recorder.percentage() * 100.0).round().min(100.0),
+                    start.elapsed().as_secs(),
+                    stage_fps(render_time),
+                    stage_fps(readback_time),
+                    stage_fps(encoder.busy()),
+                );
+            }
+            continue;
+        }
 
+        let render_start = Instant::now();
         recorder.update(frame_time);
-        recorder.render(&texture, view, &texture_desc, &output_buffer);
+        let submission = recorder.render(
+            &texture,
+            view,
+            &texture_desc,
+            readback.next_buffer(),
+            readback.bytes_per_row(),
+        );
+        readback.submitted(submission);
+        render_time += render_start.elapsed();
+        n += 1;
 
-        for _ in 0..SAMPLE_TIME {
+        // Sample rate is not always divisible by fps, so count samples from the beginning
+        let samples = n * SAMPLE_RATE / fps - (n - 1) * SAMPLE_RATE / fps;
+        for _ in 0..samples {
             let val = recorder.synth.read_next();
             audio_buffer_l.push(val.0);
             audio_buffer_r.push(val.1);
         }
 
         if audio_buffer_l.len() >= frame_size {
-            encoder(ffmpeg_encoder::Frame::Audio(
-                &audio_buffer_l[..frame_size],
-                &audio_buffer_r[..frame_size],
-            ));
+            encoder
+                .audio(&audio_buffer_l[..frame_size], &audio_buffer_r[..frame_size])
+                .map_err(output_error)?;
             audio_buffer_l.drain(..frame_size);
             audio_buffer_r.drain(..frame_size);
         }
+    }
 
-        {
-            let slice = output_buffer.slice(..);
-
-            slice.map_async(wgpu::MapMode::Read, move |_| {});
+    for (l, r) in audio_buffer_l
+        .chunks(frame_size)
+        .zip(audio_buffer_r.chunks(frame_size))
+    {
+        encoder.audio(l, r).map_err(output_error)?;
+    }
 
-            recorder
-                .gpu
-                .device
-                .poll(wgpu::PollType::Wait {
-                    submission_index: None,
-                    timeout: None,
-                })
-                .unwrap();
+    encoder.finish().map_err(output_error)?;
+    if progress {
+        println!();
+    }
 
-            let mapping = slice.get_mapped_range().unwrap();
+    let frames = n;
+    Ok(RenderStats {
+        frames,
+        duration: frame_time * frames as u32,
+    })
+}
 
-            let data: &[u8] = &mapping;
+/// Blending leaves colors premultiplied by alpha, encoders expect straight alpha. Blending
+/// happens on linear colors, the sRGB target encodes the result, so colors are divided in linear
+/// space.
+fn unpremultiply_bgra(data: &mut [u8]) {
+    static TO_LINEAR: std::sync::LazyLock<[f32; 256]> = std::sync::LazyLock::new(|| {
+        std::array::from_fn(|c| {
+            let u = c as f32 / 255.0;
+            if u < 0.04045 {
+                u / 12.92
+            } else {
+                ((u + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
 
-            encoder(ffmpeg_encoder::Frame::Vide(data));
+    fn to_srgb(u: f32) -> u8 {
+        let u = u.clamp(0.0, 1.0);
+        let c = if u < 0.0031308 {
+            u * 12.92
+        } else {
+            1.055 * u.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    }
 
-            print!(
-                "\r Encoded {} frames ({}s, {}%) in {}s",
-                n,
-                (n as f32 / 60.0).round(),
-                (recorder.playback.percentage() * 100.0).round().min(100.0),
-                start.elapsed().as_secs()
-            );
+    for pixel in data.chunks_exact_mut(4) {
+        let a = pixel[3];
+        if a == 0 || a == 255 {
+            continue;
         }
 
-        n += 1;
+        let a = a as f32 / 255.0;
+        for c in &mut pixel[..3] {
+            *c = to_srgb(TO_LINEAR[*c as usize] / a);
+        }
     }
+}
 
-    for (l, r) in audio_buffer_l
-        .chunks(frame_size)
-        .zip(audio_buffer_r.chunks(frame_size))
-    {
-        encoder(ffmpeg_encoder::Frame::Audio(l, r));
-    }
+/// Notes of the clip only, for the waterfall
+fn clip_tracks(
+    tracks: &[midi_file::MidiTrack],
+    clip: &Range<Duration>,
+) -> Vec<midi_file::MidiTrack> {
+    tracks
+        .iter()
+        .map(|track| midi_file::MidiTrack {
+            notes: track
+                .notes
+                .iter()
+                .filter(|note| clip.contains(&note.start))
+                .cloned()
+                .collect(),
+            ..track.clone()
+        })
+        .collect()
+}
+
+/// Notes only start inside of the clip, while channel setup before it and releases after it
+/// still get through
+fn is_in_clip(event: &midi_file::MidiEvent, clip: &Range<Duration>) -> bool {
+    use midi_file::midly::MidiMessage;
 
-    encoder(ffmpeg_encoder::Frame::Terminator);
+    match event.message {
+        MidiMessage::NoteOn { vel, .. } if vel > 0 => clip.contains(&event.timestamp),
+        MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => true,
+        _ => event.timestamp < clip.end,
+    }
 }
 
-fn file_midi_events(
+fn file_midi_events<'a>(
     synth: &mut oxisynth::Synth,
     keyboard: &mut KeyboardRenderer,
     config: &Config,
-    events: &[&midi_file::MidiEvent],
+    muted_tracks: &[usize],
+    events: impl Iterator<Item = &'a midi_file::MidiEvent>,
 ) {
     use midi_file::midly::MidiMessage;
 
     for e in events {
         let channel = e.channel;
 
-        let oxistynth_event = libmidi_to_oxisynth_event(channel, e.message);
-        synth.send_event(oxistynth_event).ok();
+        // Muted tracks still light up the keyboard, like in the app
+        if !muted_tracks.contains(&e.track_id) {
+            let oxistynth_event = libmidi_to_oxisynth_event(channel, e.message);
+            synth.send_event(oxistynth_event).ok();
+        }
 
         let (is_on, key) = match e.message {
             MidiMessage::NoteOn { key, .. } => (true, key.as_int()),
@@ -467,3 +769,22 @@ fn libmidi_to_oxisynth_event(channel: u8, message: midly::MidiMessage) -> oxisyn
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpremultiply_in_linear_space() {
+        // Linear 0.2 at half alpha is blended to linear 0.1, encoded as 89
+        let mut pixel = [89, 0, 255, 128];
+        unpremultiply_bgra(&mut pixel);
+        // sRGB of linear 0.2 is 124, up to 8-bit rounding. Dividing the encoded value gives 177.
+        assert!(pixel[0].abs_diff(124) <= 1);
+        assert_eq!(pixel[1..], [0, 255, 128]);
+
+        let mut opaque = [10, 20, 30, 255];
+        unpremultiply_bgra(&mut opaque);
+        assert_eq!(opaque, [10, 20, 30, 255]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use crate::{cli::Args, wav::WavWriter};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[cfg(feature = "ffmpeg")]
+    #[error(transparent)]
+    Ffmpeg(#[from] ffmpeg_encoder::Error),
+}
+
+#[cfg(feature = "ffmpeg")]
+type Encoder = Box<dyn FnMut(ffmpeg_encoder::Frame) -> Result<(), ffmpeg_encoder::Error>>;
+
+/// Where rendered frames and synthesized audio end up
+pub enum Output {
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg {
+        frame_size: usize,
+        encoder: Encoder,
+    },
+    Png(PngSequence),
+}
+
+impl Output {
+    pub fn new(args: &Args) -> Result<Self, Error> {
+        match args.format {
+            #[cfg(feature = "ffmpeg")]
+            crate::cli::OutputFormat::Ffmpeg => {
+                let (info, encoder) = ffmpeg_encoder::new(&args.out, &args.encoder_options())?;
+                Ok(Self::Ffmpeg {
+                    frame_size: info.frame_size,
+                    encoder: Box::new(encoder),
+                })
+            }
+            crate::cli::OutputFormat::Png => Ok(Self::Png(PngSequence::new(
+                &args.out,
+                args.width,
+                args.height,
+                args.transparent,
+                crate::SAMPLE_RATE as u32,
+            )?)),
+        }
+    }
+
+    /// Audio is expected in chunks of this many samples, except for the last one
+    pub fn audio_frame_size(&self) -> usize {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg { frame_size, .. } => *frame_size,
+            Self::Png(_) => 1024,
+        }
+    }
+
+    /// BGRA frame with straight alpha
+    pub fn video(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg { encoder, .. } => Ok(encoder(ffmpeg_encoder::Frame::Vide(data))?),
+            Self::Png(png) => Ok(png.frame(data)?),
+        }
+    }
+
+    pub fn audio(&mut self, l: &[f32], r: &[f32]) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg { encoder, .. } => Ok(encoder(ffmpeg_encoder::Frame::Audio(l, r))?),
+            Self::Png(png) => Ok(png.wav.write(l, r)?),
+        }
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg { mut encoder, .. } => Ok(encoder(ffmpeg_encoder::Frame::Terminator)?),
+            Self::Png(png) => Ok(png.wav.finish().map(|_| ())?),
+        }
+    }
+}
+
+/// Numbered PNG frames (`00001.png`, ...) and `audio.wav` in a directory, no ffmpeg needed
+pub struct PngSequence {
+    dir: PathBuf,
+    width: u32,
+    height: u32,
+    alpha: bool,
+    frame: usize,
+    pixels: Vec<u8>,
+    wav: WavWriter<BufWriter<File>>,
+}
+
+impl PngSequence {
+    pub fn new(
+        dir: &Path,
+        width: u32,
+        height: u32,
+        alpha: bool,
+        sample_rate: u32,
+    ) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let wav = File::create(dir.join("audio.wav"))?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            width,
+            height,
+            alpha,
+            frame: 0,
+            pixels: Vec::new(),
+            wav: WavWriter::new(BufWriter::new(wav), sample_rate)?,
+        })
+    }
+
+    fn frame(&mut self, bgra: &[u8]) -> io::Result<()> {
+        self.frame += 1;
+
+        self.pixels.clear();
+        for pixel in bgra.chunks_exact(4) {
+            self.pixels
+                .extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            if self.alpha {
+                self.pixels.push(pixel[3]);
+            }
+        }
+
+        let file = File::create(self.dir.join(format!("{:05}.png", self.frame)))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(if self.alpha {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        });
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Fast);
+
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer
+            .write_image_data(&self.pixels)
+            .map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use neothesia_core::{
+    config::Config,
+    render::{QuadInstance, QuadRenderer, TextRenderer},
+    utils::{Point, Rect, Size},
+};
+
+use crate::cli::OverlayArgs;
+
+/// Time the title card takes to fade out
+const TITLE_FADE: Duration = Duration::from_millis(600);
+
+/// Text shaped once and drawn every frame, until it changes
+#[derive(Default)]
+struct Label {
+    text: String,
+    buffer: Option<cosmic_text::Buffer>,
+}
+
+impl Label {
+    fn set(&mut self, text: String, size: f32) -> &cosmic_text::Buffer {
+        if self.buffer.is_none() || self.text != text {
+            self.buffer = Some(TextRenderer::gen_buffer(size, &text));
+            self.text = text;
+        }
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+/// Song name and composer, shaped once, only their alpha changes as the card fades out
+struct TitleCard {
+    title: cosmic_text::Buffer,
+    composer: Option<cosmic_text::Buffer>,
+}
+
+/// Song information and position drawn over the video
+pub struct Overlay {
+    args: OverlayArgs,
+    title_card: Option<TitleCard>,
+    caption: Option<String>,
+    caption_label: Label,
+    counter: Label,
+    beats: Vec<midi_file::Beat>,
+    accent: [f32; 4],
+
+    width: f32,
+    height: f32,
+    /// Top of the keyboard, the title card is centered above it
+    keyboard_top: f32,
+
+    quads: QuadRenderer,
+    text: TextRenderer,
+}
+
+impl Overlay {
+    pub fn new(
+        args: &OverlayArgs,
+        midi: &midi_file::MidiFile,
+        config: &Config,
+        size: (u32, u32),
+        keyboard_top: f32,
+        quads: QuadRenderer,
+        text: TextRenderer,
+    ) -> Self {
+        let title = args
+            .title
+            .clone()
+            .or_else(|| midi.meta.title.clone())
+            .unwrap_or_else(|| {
+                std::path::Path::new(&midi.name)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| midi.name.clone())
+            });
+        let composer = args
+            .composer
+            .clone()
+            .or_else(|| midi.meta.composer().map(str::to_string));
+        let caption = args.caption.as_ref().map(|caption| {
+            caption
+                .replace("{title}", &title)
+                .replace("{composer}", composer.as_deref().unwrap_or_default())
+        });
+
+        let beats = if args.bar_counter {
+            let end = midi
+                .tracks
+                .iter()
+                .flat_map(|track| track.notes.iter())
+                .map(|note| note.end)
+                .max()
+                .unwrap_or_default();
+            midi.meta.beats(&midi.tempo_track, end)
+        } else {
+            Vec::new()
+        };
+
+        let accent = config
+            .color_schema()
+            .first()
+            .map(|color| {
+                let (r, g, b) = color.base;
+                wgpu_jumpstart::Color::from_rgba8(r, g, b, 1.0).into_linear_rgba()
+            })
+            .unwrap_or([1.0; 4]);
+
+        let height = size.1 as f32;
+        let title_card = args.title_card.map(|_| TitleCard {
+            title: TextRenderer::gen_buffer_bold(title_size(height), &title),
+            composer: composer
+                .as_deref()
+                .map(|composer| TextRenderer::gen_buffer(composer_size(height), composer)),
+        });
+
+        Self {
+            args: args.clone(),
+            title_card,
+            caption,
+            caption_label: Label::default(),
+            counter: Label::default(),
+            beats,
+            accent,
+
+            width: size.0 as f32,
+            height,
+            keyboard_top,
+
+            quads,
+            text,
+        }
+    }
+
+    /// `video_time` counts from the first frame, `song_time` from the start of the song (negative
+    /// during the lead-in) and `progress` is the played part of the clip, from 0 to 1
+    pub fn update(&mut self, video_time: Duration, song_time: f32, progress: f32) {
+        self.quads.clear();
+
+        let margin = (self.height * 0.025).round();
+        let font_size = (self.height / 40.0).round();
+        let mut top = margin;
+
+        if self.args.progress_bar {
+            let bar_height = (self.height / 180.0).round().max(2.0);
+            self.quads.push(QuadInstance {
+                position: [0.0, 0.0],
+                size: [self.width, bar_height],
+                color: [0.0, 0.0, 0.0, 0.4],
+                ..Default::default()
+            });
+            self.quads.push(QuadInstance {
+                position: [0.0, 0.0],
+                size: [self.width * progress.clamp(0.0, 1.0), bar_height],
+                color: self.accent,
+                ..Default::default()
+            });
+            top += bar_height;
+        }
+
+        if let Some(caption) = &self.caption {
+            let buffer = self.caption_label.set(caption.clone(), font_size).clone();
+            let rect = Rect::new(
+                Point::new(margin, top),
+                Size::new(self.width / 2.0, font_size * 1.5),
+            );
+            self.text.queue_buffer_left(rect, buffer);
+        }
+
+        let beat = self
+            .beats
+            .partition_point(|beat| beat.timestamp.as_secs_f32() <= song_time)
+            .checked_sub(1)
+            .map(|id| self.beats[id]);
+        if let Some(beat) = beat {
+            let text = format!("Bar {}  Beat {}/{}", beat.bar, beat.beat, beat.beats_in_bar);
+            let buffer = self.counter.set(text, font_size).clone();
+            let rect = Rect::new(
+                Point::new(self.width / 2.0 - margin, top),
+                Size::new(self.width / 2.0, font_size * 1.5),
+            );
+            self.text.queue_buffer_right(rect, buffer);
+        }
+
+        if let Some(duration) = self.args.title_card {
+            self.update_title_card(video_time, duration);
+        }
+
+        self.quads.prepare();
+        self.text.update(
+            neothesia_core::dpi::PhysicalSize::new(self.width as u32, self.height as u32),
+            1.0,
+        );
+    }
+
+    fn update_title_card(&mut self, video_time: Duration, duration: Duration) {
+        let Some(card) = &self.title_card else {
+            return;
+        };
+        let Some(left) = duration
+            .checked_sub(video_time)
+            .filter(|left| !left.is_zero())
+        else {
+            return;
+        };
+        let alpha = (left.as_secs_f32() / TITLE_FADE.as_secs_f32()).min(1.0);
+
+        self.quads.push(QuadInstance {
+            position: [0.0, 0.0],
+            size: [self.width, self.keyboard_top],
+            color: [0.0, 0.0, 0.0, 0.6 * alpha],
+            ..Default::default()
+        });
+
+        let title_size = title_size(self.height);
+        let composer_size = composer_size(self.height);
+        let center = self.keyboard_top / 2.0;
+
+        let Some(composer) = &card.composer else {
+            let rect = Rect::new(
+                Point::new(0.0, center - title_size),
+                Size::new(self.width, title_size * 2.0),
+            );
+            queue_faded(&mut self.text, rect, card.title.clone(), alpha);
+            return;
+        };
+
+        let rect = Rect::new(
+            Point::new(0.0, center - title_size * 1.5),
+            Size::new(self.width, title_size * 2.0),
+        );
+        queue_faded(&mut self.text, rect, card.title.clone(), alpha);
+
+        let rect = Rect::new(
+            Point::new(0.0, center + title_size * 0.5),
+            Size::new(self.width, composer_size * 2.0),
+        );
+        queue_faded(&mut self.text, rect, composer.clone(), alpha);
+    }
+
+    pub fn render<'a>(&'a self, rpass: &mut wgpu_jumpstart::RenderPass<'a>) {
+        self.quads.render(rpass);
+        self.text.render(rpass);
+    }
+}
+
+fn title_size(height: f32) -> f32 {
+    (height / 14.0).round()
+}
+
+fn composer_size(height: f32) -> f32 {
+    (height / 28.0).round()
+}
+
+/// Queues white text centered in `rect`, with `alpha` applied without shaping it again
+fn queue_faded(text: &mut TextRenderer, rect: Rect, buffer: cosmic_text::Buffer, alpha: f32) {
+    text.queue_buffer_centered(rect, buffer);
+    if let Some(area) = text.queue_mut().last_mut() {
+        area.default_color = cosmic_text::Color::rgba(255, 255, 255, (alpha * 255.0) as u8);
+    }
+}
@@ -0,0 +1,272 @@
+//! Rendering, read back and encoding overlap: the GPU renders the next frames while the previous
+//! ones are copied out of staging buffers, and a worker thread encodes them.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use wgpu_jumpstart::wgpu;
+
+use crate::{
+    cli::Args,
+    output::{self, Output},
+};
+
+/// A GPU frame takes much less, longer than this the GPU is considered hung
+const READBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadbackError {
+    #[error("waiting for the GPU failed: {0}")]
+    Poll(#[from] wgpu::PollError),
+    #[error(transparent)]
+    Map(#[from] wgpu::BufferAsyncError),
+    #[error(transparent)]
+    MapRange(#[from] wgpu::MapRangeError),
+    #[error("the GPU dropped a frame before mapping it")]
+    NotMapped,
+}
+
+/// Ring of staging buffers, frames are copied into them on the GPU and read back a few frames
+/// later, so the CPU doesn't wait for every frame to finish rendering
+pub struct Readback {
+    buffers: Vec<wgpu::Buffer>,
+    /// Buffer ids in submission order, with the result of their mapping
+    in_flight: VecDeque<InFlight>,
+    next: usize,
+    width: u32,
+    height: u32,
+    bytes_per_row: u32,
+}
+
+struct InFlight {
+    id: usize,
+    submission: wgpu::SubmissionIndex,
+    mapped: Receiver<Result<(), wgpu::BufferAsyncError>>,
+}
+
+impl Readback {
+    pub fn new(device: &wgpu::Device, width: u32, height: u32, count: usize) -> Self {
+        // Copies need padded rows, the padding is dropped when reading back
+        let bytes_per_row = (width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffers = (0..count)
+            .map(|_| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    size: (bytes_per_row * height) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                    label: Some("readback buffer"),
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+
+        Self {
+            buffers,
+            in_flight: VecDeque::with_capacity(count),
+            next: 0,
+            width,
+            height,
+            bytes_per_row,
+        }
+    }
+
+    pub fn bytes_per_row(&self) -> u32 {
+        self.bytes_per_row
+    }
+
+    /// Buffer the next frame should be copied into, only free when [`Self::is_full`] is false
+    pub fn next_buffer(&self) -> &wgpu::Buffer {
+        &self.buffers[self.next]
+    }
+
+    /// Marks the copy into [`Self::next_buffer`] as submitted
+    pub fn submitted(&mut self, submission: wgpu::SubmissionIndex) {
+        let (mapped_tx, mapped) = mpsc::channel();
+        self.buffers[self.next]
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                mapped_tx.send(result).ok();
+            });
+        self.in_flight.push_back(InFlight {
+            id: self.next,
+            submission,
+            mapped,
+        });
+        self.next = (self.next + 1) % self.buffers.len();
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_flight.len() == self.buffers.len()
+    }
+
+    /// Waits for the oldest frame in flight and copies it into `frame`, tightly packed.
+    /// Returns false when there are no frames in flight.
+    pub fn read(
+        &mut self,
+        device: &wgpu::Device,
+        frame: &mut Vec<u8>,
+    ) -> Result<bool, ReadbackError> {
+        let Some(InFlight {
+            id,
+            submission,
+            mapped,
+        }) = self.in_flight.pop_front()
+        else {
+            return Ok(false);
+        };
+
+        device.poll(wgpu::PollType::Wait {
+            submission_index: Some(submission),
+            timeout: Some(READBACK_TIMEOUT),
+        })?;
+        // The callback runs within the poll
+        mapped.try_recv().map_err(|_| ReadbackError::NotMapped)??;
+
+        let buffer = &self.buffers[id];
+        {
+            let mapping = buffer.slice(..).get_mapped_range()?;
+            let row_len = self.width as usize * 4;
+
+            frame.clear();
+            frame.reserve(row_len * self.height as usize);
+            for row in mapping.chunks_exact(self.bytes_per_row as usize) {
+                frame.extend_from_slice(&row[..row_len]);
+            }
+        }
+        buffer.unmap();
+
+        Ok(true)
+    }
+}
+
+enum Job {
+    Video(Vec<u8>),
+    Audio(Vec<f32>, Vec<f32>),
+}
+
+/// Output running on a worker thread, fed through a bounded queue
+pub struct Encoder {
+    jobs: Option<SyncSender<Job>>,
+    /// Frame buffers the worker is done with, reused to avoid allocating every frame
+    recycled: Receiver<Vec<u8>>,
+    worker: Option<JoinHandle<Result<(), output::Error>>>,
+    /// Time the worker spent encoding, in nanoseconds
+    busy: Arc<AtomicU64>,
+    audio_frame_size: usize,
+}
+
+impl Encoder {
+    /// Opens the output on a new thread, at most `queue_len` jobs wait for it
+    pub fn spawn(args: &Args, queue_len: usize) -> Result<Self, output::Error> {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let (jobs_tx, jobs_rx) = mpsc::sync_channel(queue_len);
+        let (recycled_tx, recycled_rx) = mpsc::channel();
+        let busy = Arc::new(AtomicU64::new(0));
+
+        let args = args.clone();
+        let worker_busy = busy.clone();
+        let worker = std::thread::Builder::new()
+            .name("encoder".into())
+            .spawn(move || {
+                let mut output = match Output::new(&args) {
+                    Ok(output) => output,
+                    Err(err) => {
+                        opened_tx.send(Err(err)).ok();
+                        return Ok(());
+                    }
+                };
+                opened_tx.send(Ok(output.audio_frame_size())).ok();
+
+                for job in jobs_rx {
+                    let start = Instant::now();
+                    match job {
+                        Job::Video(mut frame) => {
+                            if args.transparent {
+                                crate::unpremultiply_bgra(&mut frame);
+                            }
+                            output.video(&frame)?;
+                            recycled_tx.send(frame).ok();
+                        }
+                        Job::Audio(l, r) => output.audio(&l, &r)?,
+                    }
+                    worker_busy.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                }
+
+                output.finish()
+            })
+            .expect("Failed to spawn the encoder thread");
+
+        let audio_frame_size = match opened_rx.recv() {
+            Ok(Ok(frame_size)) => frame_size,
+            Ok(Err(err)) => return Err(err),
+            Err(_) => {
+                // Opening the output panicked, pass the panic on
+                Self::join(worker)?;
+                unreachable!("the encoder thread quit without opening the output");
+            }
+        };
+
+        Ok(Self {
+            jobs: Some(jobs_tx),
+            recycled: recycled_rx,
+            worker: Some(worker),
+            busy,
+            audio_frame_size,
+        })
+    }
+
+    /// Audio is expected in chunks of this many samples, except for the last one
+    pub fn audio_frame_size(&self) -> usize {
+        self.audio_frame_size
+    }
+
+    /// Empty buffer for the next frame
+    pub fn frame_buffer(&self) -> Vec<u8> {
+        self.recycled.try_recv().unwrap_or_default()
+    }
+
+    /// Queues a BGRA frame, waits when the queue is full
+    pub fn video(&mut self, frame: Vec<u8>) -> Result<(), output::Error> {
+        self.send(Job::Video(frame))
+    }
+
+    pub fn audio(&mut self, l: &[f32], r: &[f32]) -> Result<(), output::Error> {
+        self.send(Job::Audio(l.to_vec(), r.to_vec()))
+    }
+
+    /// Time the worker spent encoding so far
+    pub fn busy(&self) -> Duration {
+        Duration::from_nanos(self.busy.load(Ordering::Relaxed))
+    }
+
+    /// Waits for the queued jobs and finishes the output
+    pub fn finish(mut self) -> Result<(), output::Error> {
+        self.jobs = None;
+        Self::join(self.worker.take().unwrap())
+    }
+
+    fn send(&mut self, job: Job) -> Result<(), output::Error> {
+        let jobs = self.jobs.as_ref().unwrap();
+        if jobs.send(job).is_ok() {
+            return Ok(());
+        }
+
+        // The worker stopped on an error
+        self.jobs = None;
+        Self::join(self.worker.take().unwrap())
+    }
+
+    fn join(worker: JoinHandle<Result<(), output::Error>>) -> Result<(), output::Error> {
+        worker
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const HEADER_LEN: u32 = 44;
+const FRAME_LEN: u64 = 4;
+
+/// Stereo 16-bit PCM WAV writer, sizes in the header are filled in by [`WavWriter::finish`]
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    /// Stereo frames written so far
+    frames: u64,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        let channels: u16 = 2;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&bits_per_sample.to_le_bytes())?;
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { out, frames: 0 })
+    }
+
+    /// Fails before the file outgrows the 4GiB the header can describe
+    pub fn write(&mut self, l: &[f32], r: &[f32]) -> io::Result<()> {
+        let frames = self.frames + l.len().min(r.len()) as u64;
+        if data_len(frames).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "the audio is too long for a WAV file",
+            ));
+        }
+
+        let mut buf = Vec::with_capacity(l.len() * 4);
+        for (l, r) in l.iter().zip(r) {
+            buf.extend_from_slice(&to_i16(*l).to_le_bytes());
+            buf.extend_from_slice(&to_i16(*r).to_le_bytes());
+        }
+
+        self.out.write_all(&buf)?;
+        self.frames = frames;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_len = data_len(self.frames).expect("`write` keeps the data within the limit");
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+/// Size of the sample data, `None` when the RIFF size would overflow the header
+fn data_len(frames: u64) -> Option<u32> {
+    frames
+        .checked_mul(FRAME_LEN)
+        .and_then(|len| u32::try_from(len).ok())
+        .filter(|len| len.checked_add(HEADER_LEN - 8).is_some())
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let mut wav = WavWriter::new(io::Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write(&[0.0, 1.0, -1.0], &[0.5, 2.0, 0.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let i16_at = |i: usize| i16::from_le_bytes(data[i..i + 2].try_into().unwrap());
+
+        assert_eq!(data.len(), 44 + 3 * 4);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 3 * 4);
+        assert_eq!(u32_at(24), 44100);
+        assert_eq!(u32_at(40), 3 * 4);
+        assert_eq!(i16_at(44 + 4), i16::MAX);
+        assert_eq!(i16_at(44 + 6), i16::MAX);
+        assert_eq!(i16_at(44 + 8), -i16::MAX);
+    }
+
+    #[test]
+    fn too_long() {
+        let max = (u32::MAX - 36) as u64 / FRAME_LEN;
+        assert_eq!(data_len(max), Some(max as u32 * 4));
+        assert_eq!(data_len(max + 1), None);
+        assert_eq!(data_len(u64::MAX), None);
+
+        let mut wav = WavWriter::new(io::Cursor::new(Vec::new()), 44100).unwrap();
+        wav.frames = max;
+        let err = wav.write(&[0.0], &[0.0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+    }
+}
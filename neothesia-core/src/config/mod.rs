@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 mod model;
 
@@ -6,7 +6,8 @@ pub use model::ColorSchemaV1;
 use model::{
     AppearanceConfig, AppearanceConfigV1, DevicesConfig, DevicesConfigV1, History, HistoryV1,
     LayoutConfig, LayoutConfigV1, Model, PcKeyboardConfig, PcKeyboardConfigV1, PlaybackConfig,
-    PlaybackConfigV1, SynthConfig, SynthConfigV1, WaterfallConfig, WaterfallConfigV1,
+    PlaybackConfigV1, SynthConfig, SynthConfigV1, TranscriptionConfig, TranscriptionConfigV1,
+    WaterfallConfig, WaterfallConfigV1,
 };
 
 fn ron_options() -> ron::Options {
@@ -35,6 +36,14 @@ impl Model {
         config.unwrap_or_default()
     }
 
+    fn load_from(path: &Path) -> Result<Self, String> {
+        let file =
+            std::fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        ron_options()
+            .from_str(&file)
+            .map_err(|err| format!("{}: {err}", path.display()))
+    }
+
     fn from_config(config: Config) -> Self {
         let Config {
             playback,
@@ -45,6 +54,7 @@ impl Model {
             keyboard_layout,
             appearance,
             pc_keyboard,
+            transcription,
         } = config;
 
         Self {
@@ -56,6 +66,7 @@ impl Model {
             devices: DevicesConfig::V1(devices),
             appearance: AppearanceConfig::V1(appearance),
             pc_keyboard: PcKeyboardConfig::V1(pc_keyboard),
+            transcription: TranscriptionConfig::V1(transcription),
         }
     }
 
@@ -85,6 +96,9 @@ impl Model {
             pc_keyboard: match self.pc_keyboard {
                 PcKeyboardConfig::V1(v) => v,
             },
+            transcription: match self.transcription {
+                TranscriptionConfig::V1(v) => v,
+            },
         }
     }
 }
@@ -99,6 +113,7 @@ pub struct Config {
     history: HistoryV1,
     keyboard_layout: LayoutConfigV1,
     pc_keyboard: PcKeyboardConfigV1,
+    transcription: TranscriptionConfigV1,
 }
 
 impl Default for Config {
@@ -112,6 +127,11 @@ impl Config {
         Model::load().build()
     }
 
+    /// Loads settings from `path` instead of the user's settings file, missing fields get defaults
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        Model::load_from(path).map(Model::build)
+    }
+
     pub fn piano_range(&self) -> std::ops::RangeInclusive<u8> {
         self.keyboard_layout.range.0..=self.keyboard_layout.range.1
     }
@@ -184,6 +204,23 @@ impl Config {
         self.synth.soundfont_path = soundfont_path;
     }
 
+    /// Model of the audio transcription
+    pub fn transcription_model_path(&self) -> Option<&PathBuf> {
+        self.transcription.model_path.as_ref()
+    }
+
+    pub fn set_transcription_model_path(&mut self, model_path: Option<PathBuf>) {
+        self.transcription.model_path = model_path;
+    }
+
+    pub fn backing_tracks(&self) -> bool {
+        self.transcription.backing_tracks
+    }
+
+    pub fn set_backing_tracks(&mut self, backing_tracks: bool) {
+        self.transcription.backing_tracks = backing_tracks;
+    }
+
     pub fn output(&self) -> Option<&str> {
         self.devices.output.as_deref()
     }
@@ -261,6 +298,15 @@ impl Config {
         self.playback.speed_multiplier = speed_multiplier.max(0.0);
     }
 
+    /// Input/output latency measured by the calibration wizard, in seconds
+    pub fn latency_offset(&self) -> f32 {
+        self.playback.latency_offset
+    }
+
+    pub fn set_latency_offset(&mut self, latency_offset: f32) {
+        self.playback.latency_offset = latency_offset.max(0.0);
+    }
+
     pub fn pc_keyboard_octave(&self) -> u8 {
         self.pc_keyboard.octave_shift.min(10)
     }
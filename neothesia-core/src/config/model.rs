@@ -21,6 +21,8 @@ pub struct Model {
     pub appearance: AppearanceConfig,
     #[serde(default)]
     pub pc_keyboard: PcKeyboardConfig,
+    #[serde(default)]
+    pub transcription: TranscriptionConfig,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -54,6 +56,9 @@ impl Default for WaterfallConfig {
 pub struct PlaybackConfigV1 {
     #[serde(default = "default_speed_multiplier")]
     pub speed_multiplier: f32,
+
+    #[serde(default = "default_latency_offset")]
+    pub latency_offset: f32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -65,6 +70,7 @@ impl Default for PlaybackConfig {
     fn default() -> Self {
         Self::V1(PlaybackConfigV1 {
             speed_multiplier: default_speed_multiplier(),
+            latency_offset: default_latency_offset(),
         })
     }
 }
@@ -216,6 +222,29 @@ impl Default for PcKeyboardConfig {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TranscriptionConfigV1 {
+    pub model_path: Option<PathBuf>,
+
+    /// Play a recording next to the MIDI file, with the same name, along with the song
+    #[serde(default = "default_backing_tracks")]
+    pub backing_tracks: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum TranscriptionConfig {
+    V1(TranscriptionConfigV1),
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        Self::V1(TranscriptionConfigV1 {
+            model_path: None,
+            backing_tracks: default_backing_tracks(),
+        })
+    }
+}
+
 fn default_piano_range() -> (u8, u8) {
     (21, 108)
 }
@@ -224,6 +253,10 @@ fn default_speed_multiplier() -> f32 {
     1.0
 }
 
+fn default_latency_offset() -> f32 {
+    0.0
+}
+
 fn default_animation_speed() -> f32 {
     400.0
 }
@@ -256,6 +289,10 @@ fn default_separate_channels() -> bool {
     false
 }
 
+fn default_backing_tracks() -> bool {
+    true
+}
+
 fn default_color_schema() -> Vec<ColorSchemaV1> {
     vec![
         ColorSchemaV1 {
@@ -16,4 +16,4 @@ pub use keyboard::{KeyState as KeyboardKeyState, KeyboardRenderer};
 pub use note_labels::NoteLabels;
 pub use quad::{QuadInstance, QuadRenderer, QuadRendererFactory};
 pub use text::{TextRenderer, TextRendererFactory};
-pub use waterfall::WaterfallRenderer;
+pub use waterfall::{KEYBOARD_HEIGHT_RATIO, WaterfallRenderer};
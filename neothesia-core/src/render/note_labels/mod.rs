@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::utils::Point;
 
 use super::{KeyboardRenderer, TextRenderer, waterfall::NoteList};
@@ -96,10 +98,17 @@ impl NoteLabels {
         let labels = self.labels_cache.get(keyboard);
         let animation_speed = animation_speed / scale;
 
+        // Labels sit on the start of a note, so only notes starting between the keyboard and the
+        // top of the view are of interest
+        let from = time - label_width / animation_speed.abs();
+        let to = time + self.pos.y / animation_speed.abs();
+        let from = Duration::from_secs_f32(from.max(0.0));
+        let to = Duration::from_secs_f32(to.max(0.0));
+
         let iter = self
             .notes
-            .inner
-            .iter()
+            .in_range(from, to)
+            .filter(move |note| note.start >= from)
             .filter(|note| layout.range.contains(note.note) && note.channel != 9)
             .map(|note| {
                 let buffer = &labels[(note.note % 12) as usize];
@@ -110,10 +119,7 @@ impl NoteLabels {
 
                 (buffer, x, y)
             })
-            // Stop iteration once we reach top of the screen
-            .take_while(|(_buffer, _x, y)| *y > 0.0)
-            // TODO: Cache last note idx to skip this NoOp skip iteration
-            .skip_while(|(_buffer, _x, y)| *y > keyboard.pos().y)
+            .filter(|(_buffer, _x, y)| *y > 0.0 && *y <= keyboard.pos().y)
             .map(|(buffer, left, top)| glyphon::TextArea {
                 buffer,
                 left,
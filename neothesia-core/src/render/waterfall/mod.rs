@@ -1,40 +1,119 @@
-use std::rc::Rc;
+use std::{ops::Range, rc::Rc, sync::Arc, time::Duration};
 
-use crate::{TransformUniform, Uniform, config::Config};
+use crate::{
+    TransformUniform, Uniform,
+    config::{ColorSchemaV1, Config},
+};
 use midi_file::{MidiNote, MidiTrack};
 use wgpu_jumpstart::{Color, Gpu};
 
 mod pipeline;
 use pipeline::{NoteInstance, WaterfallPipeline};
 
+/// Part of the view height taken by the keyboard at the bottom, the notes fall above it
+pub const KEYBOARD_HEIGHT_RATIO: f32 = 0.2;
+
+/// Notes held longer than this are looked up apart from the others, so a single held note doesn't
+/// make every lookup scan from the time it started
+const LONG_NOTE: Duration = Duration::from_secs(4);
+
+/// Notes of the visible tracks, shared with the tracks rather than copied
 #[derive(Clone)]
 pub struct NoteList {
-    pub(crate) inner: Rc<[MidiNote]>,
+    tracks: Rc<[TrackNotes]>,
+    /// Lowest and highest key used by the notes
+    keys: Option<(u8, u8)>,
+}
+
+struct TrackNotes {
+    /// Sorted by start
+    notes: Arc<[MidiNote]>,
+    /// Tells how long before a time window a note overlapping it can start, long notes aside
+    longest: Duration,
+    /// Indices of the notes longer than [`LONG_NOTE`], sorted by start
+    long: Box<[usize]>,
+}
+
+impl TrackNotes {
+    fn new(notes: Arc<[MidiNote]>) -> Self {
+        let (long, short): (Vec<_>, Vec<_>) = notes
+            .iter()
+            .enumerate()
+            .partition(|(_, note)| note.duration > LONG_NOTE);
+
+        Self {
+            longest: short
+                .iter()
+                .map(|(_, note)| note.duration)
+                .max()
+                .unwrap_or_default(),
+            long: long.into_iter().map(|(id, _)| id).collect(),
+            notes,
+        }
+    }
+
+    /// Indices of the notes that may sound in `start..=end`, long notes that started earlier aside
+    fn window(&self, start: Duration, end: Duration) -> Range<usize> {
+        let from = self
+            .notes
+            .partition_point(|note| note.start + self.longest < start);
+        let to = self.notes.partition_point(|note| note.start <= end);
+        from..to.max(from)
+    }
+
+    /// Notes sounding at any point of `start..=end`, sorted by start
+    fn in_range(&self, start: Duration, end: Duration) -> impl Iterator<Item = &MidiNote> {
+        let window = self.window(start, end);
+        let held = self.long[..self.long.partition_point(|id| *id < window.start)]
+            .iter()
+            .map(|id| &self.notes[*id]);
+
+        held.chain(&self.notes[window])
+            .filter(move |note| note.end >= start)
+    }
 }
 
 impl NoteList {
     fn new(tracks: &[MidiTrack], hidden_tracks: &[usize]) -> Self {
-        let mut notes: Vec<_> = tracks
+        let tracks: Vec<_> = tracks
             .iter()
             .filter(|track| !hidden_tracks.contains(&track.track_id))
-            .flat_map(|track| track.notes.iter().cloned())
+            .filter(|track| !track.notes.is_empty())
+            .map(|track| TrackNotes::new(track.notes.clone()))
             .collect();
-        // We want to render newer notes on top of old notes
-        notes.sort_unstable_by_key(|note| note.start);
+
+        let keys = tracks
+            .iter()
+            .flat_map(|track| track.notes.iter())
+            .filter(|note| note.channel != 9)
+            .fold(None, |keys, note| match keys {
+                Some((min, max)) => Some((note.note.min(min), note.note.max(max))),
+                None => Some((note.note, note.note)),
+            });
 
         Self {
-            inner: notes.into(),
+            tracks: tracks.into(),
+            keys,
         }
     }
 
-    fn len(&self) -> usize {
-        self.inner.len()
+    /// Notes sounding at any point of `start..=end`, track by track, each track sorted by start
+    pub fn in_range(&self, start: Duration, end: Duration) -> impl Iterator<Item = &MidiNote> {
+        self.tracks
+            .iter()
+            .flat_map(move |track| track.in_range(start, end))
     }
 }
 
+/// Notes uploaded to the GPU are limited to a time window around the playback position, so memory
+/// and upload cost depend on what is on screen rather than on the size of the file
 pub struct WaterfallRenderer {
     notes_pipeline: WaterfallPipeline,
     notes: NoteList,
+    layout: piano_layout::KeyboardLayout,
+    color_schema: Vec<ColorSchemaV1>,
+    /// Time window (in seconds) of the notes currently in the instance buffer
+    uploaded: Option<Range<f32>>,
     device: wgpu::Device,
     queue: wgpu::Queue,
 }
@@ -50,10 +129,14 @@ impl WaterfallRenderer {
     ) -> Self {
         let notes = NoteList::new(tracks, hidden_tracks);
 
-        let notes_pipeline = WaterfallPipeline::new(gpu, transform_uniform, notes.len());
+        // The buffer grows on demand to fit the busiest window
+        let notes_pipeline = WaterfallPipeline::new(gpu, transform_uniform, 1024);
         let mut notes = Self {
             notes_pipeline,
             notes,
+            layout: layout.clone(),
+            color_schema: Vec::new(),
+            uploaded: None,
             device: gpu.device.clone(),
             queue: gpu.queue.clone(),
         };
@@ -73,53 +156,89 @@ impl WaterfallRenderer {
     }
 
     pub fn resize(&mut self, config: &Config, layout: piano_layout::KeyboardLayout) {
+        if let Some((min, max)) = self.notes.keys
+            && !(layout.range.contains(min) && layout.range.contains(max))
+        {
+            log::warn!(
+                "Midi wider than giver range: {}-{}",
+                layout.range.start(),
+                layout.range.end()
+            );
+        }
+
+        self.layout = layout;
+        self.color_schema = config.color_schema().to_vec();
+        self.uploaded = None;
+    }
+
+    /// Seconds of the song that fit on the screen at the current animation speed
+    fn visible_span(&self) -> f32 {
+        let view_height = self.layout.height / KEYBOARD_HEIGHT_RATIO;
+        view_height / self.notes_pipeline.speed().abs().max(f32::EPSILON)
+    }
+
+    /// Uploads notes of `window`, replacing the previous ones
+    fn upload(&mut self, window: Range<f32>) {
+        let layout = &self.layout;
         let range_start = layout.range.start() as usize;
 
         self.notes_pipeline.clear();
 
-        let mut longer_than_range = false;
-        for note in self.notes.inner.iter() {
-            if layout.range.contains(note.note) && note.channel != 9 {
-                let key = &layout.keys[note.note as usize - range_start];
-
-                let color_schema = config.color_schema();
-
-                let color = &color_schema[note.track_color_id % color_schema.len()];
-                let color = if key.kind().is_sharp() {
-                    color.dark
-                } else {
-                    color.base
-                };
-                let color: Color = color.into();
-
-                let h = if note.duration.as_secs_f32() >= 0.1 {
-                    note.duration.as_secs_f32()
-                } else {
-                    0.1
-                };
-
-                self.notes_pipeline.instances().push(NoteInstance {
-                    position: [key.x(), note.start.as_secs_f32()],
-                    size: [key.width() - 1.0, h - 0.01], // h - 0.01 to make a little gap bettwen successive notes
-                    color: color.into_linear_rgb(),
-                    radius: key.width() * 0.2,
-                });
-            } else {
-                longer_than_range = true;
+        let start = Duration::from_secs_f32(window.start.max(0.0));
+        let end = Duration::from_secs_f32(window.end.max(0.0));
+
+        for note in self.notes.in_range(start, end) {
+            if !layout.range.contains(note.note) || note.channel == 9 {
+                continue;
             }
-        }
 
-        if longer_than_range {
-            log::warn!(
-                "Midi wider than giver range: {range_start}-{}",
-                layout.range.end()
-            );
+            let key = &layout.keys[note.note as usize - range_start];
+
+            let color = &self.color_schema[note.track_color_id % self.color_schema.len()];
+            let color = if key.kind().is_sharp() {
+                color.dark
+            } else {
+                color.base
+            };
+            let color: Color = color.into();
+
+            let h = if note.duration.as_secs_f32() >= 0.1 {
+                note.duration.as_secs_f32()
+            } else {
+                0.1
+            };
+
+            self.notes_pipeline.instances().push(NoteInstance {
+                position: [key.x(), note.start.as_secs_f32()],
+                size: [key.width() - 1.0, h - 0.01], // h - 0.01 to make a little gap bettwen successive notes
+                color: color.into_linear_rgb(),
+                radius: key.width() * 0.2,
+            });
         }
 
+        // We want to render newer notes on top of old notes
+        self.notes_pipeline
+            .instances()
+            .sort_by(|a, b| a.position[1].total_cmp(&b.position[1]));
+
         self.notes_pipeline.prepare(&self.device, &self.queue);
+        self.uploaded = Some(window);
     }
 
     pub fn update(&mut self, time: f32) {
+        let span = self.visible_span();
+        let visible = (time - span)..(time + span);
+
+        let is_uploaded = self
+            .uploaded
+            .as_ref()
+            .is_some_and(|w| w.start <= visible.start && visible.end <= w.end);
+
+        if !is_uploaded {
+            // Upload a bit more than is visible, so the buffer is not rebuilt every frame
+            self.upload(visible.start..visible.end + span * 2.0);
+        }
+
         self.notes_pipeline.update_time(&self.queue, time);
     }
 
@@ -128,3 +247,72 @@ impl WaterfallRenderer {
         self.notes_pipeline.render(render_pass);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_range_matches_full_scan() {
+        let midi = midi_file::MidiFile::new("../test.mid").unwrap();
+        let list = NoteList::new(&midi.tracks, &[]);
+
+        for (start, end) in [(0, 1000), (2500, 4000), (10_000, 10_050), (60_000, 70_000)] {
+            let start = Duration::from_millis(start);
+            let end = Duration::from_millis(end);
+
+            let mut found: Vec<_> = list
+                .in_range(start, end)
+                .map(|note| (note.start, note.note))
+                .collect();
+            let mut expected: Vec<_> = midi
+                .tracks
+                .iter()
+                .flat_map(|track| track.notes.iter())
+                .filter(|note| note.start <= end && note.end >= start)
+                .map(|note| (note.start, note.note))
+                .collect();
+
+            found.sort();
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn long_note_keeps_the_window_small() {
+        let note = |start: u64, duration: u64, key: u8| {
+            let start = Duration::from_millis(start);
+            let duration = Duration::from_millis(duration);
+            MidiNote {
+                start,
+                end: start + duration,
+                duration,
+                note: key,
+                velocity: 100,
+                channel: 0,
+                track_id: 0,
+                track_color_id: 0,
+            }
+        };
+        // A pedal tone held through the song under a run of short notes
+        let notes: Arc<[MidiNote]> = std::iter::once(note(0, 600_000, 36))
+            .chain((0..6000).map(|id| note(id * 100, 200, 60 + (id % 12) as u8)))
+            .collect();
+        let track = TrackNotes::new(notes.clone());
+
+        let (start, end) = (Duration::from_secs(300), Duration::from_secs(302));
+        assert!(track.window(start, end).len() < 30);
+
+        let found: Vec<_> = track
+            .in_range(start, end)
+            .map(|note| (note.start, note.note))
+            .collect();
+        let expected: Vec<_> = notes
+            .iter()
+            .filter(|note| note.start <= end && note.end >= start)
+            .map(|note| (note.start, note.note))
+            .collect();
+        assert_eq!(found, expected);
+    }
+}
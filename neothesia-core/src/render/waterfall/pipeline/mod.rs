@@ -121,6 +121,9 @@ impl WaterfallPipeline {
 struct TimeUniform {
     time: f32,
     speed: f32,
+    /// See [`super::KEYBOARD_HEIGHT_RATIO`]
+    keyboard_height_ratio: f32,
+    _pad: f32,
 }
 
 impl Default for TimeUniform {
@@ -128,6 +131,8 @@ impl Default for TimeUniform {
         Self {
             time: 0.0,
             speed: 400.0,
+            keyboard_height_ratio: super::KEYBOARD_HEIGHT_RATIO,
+            _pad: 0.0,
         }
     }
 }
@@ -7,6 +7,7 @@ struct ViewUniform {
 struct TimeUniform {
     time: f32,
     speed: f32,
+    keyboard_height_ratio: f32,
 }
 
 @group(0) @binding(0)
@@ -42,8 +43,7 @@ fn vs_main(vertex: Vertex, note: NoteInstance) -> VertexOutput {
 
     let size = vec2<f32>(note.size.x * view_uniform.scale, note.size.y * abs(speed));
 
-    // In an ideal world this should not be hard-coded
-    let keyboard_h = view_uniform.size.y / 5.0;
+    let keyboard_h = view_uniform.size.y * time_uniform.keyboard_height_ratio;
     let keyboard_y = view_uniform.size.y - keyboard_h;
 
     var pos = vec2<f32>(note.n_position.x * view_uniform.scale, keyboard_y);
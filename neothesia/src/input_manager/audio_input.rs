@@ -0,0 +1,133 @@
+//! Notes of an acoustic piano, heard through the default input device
+
+use std::{error::Error, time::Duration};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use midi_file::midly::MidiMessage;
+use neothesia_ai::live::{LiveDetector, NoteEvent};
+use winit::event_loop::EventLoopProxy;
+
+use super::InputSource;
+use crate::NeothesiaEvent;
+
+/// Seconds of audio the callback can get ahead of the detector
+const BUFFER_SECS: u32 = 1;
+/// How long the detector sleeps once it has caught up, well under a hop
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+pub struct AudioInputConnection {
+    _stream: cpal::Stream,
+}
+
+impl AudioInputConnection {
+    pub fn new(tx: EventLoopProxy<NeothesiaEvent>) -> Result<Self, Box<dyn Error>> {
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or("failed to find a default input device")?;
+
+        let config = device.default_input_config()?;
+        let sample_format = config.sample_format();
+        let stream_config: cpal::StreamConfig = config.into();
+
+        let stream = match sample_format {
+            cpal::SampleFormat::I8 => run::<i8>(&device, &stream_config, tx),
+            cpal::SampleFormat::I16 => run::<i16>(&device, &stream_config, tx),
+            cpal::SampleFormat::I32 => run::<i32>(&device, &stream_config, tx),
+            cpal::SampleFormat::I64 => run::<i64>(&device, &stream_config, tx),
+
+            cpal::SampleFormat::U8 => run::<u8>(&device, &stream_config, tx),
+            cpal::SampleFormat::U16 => run::<u16>(&device, &stream_config, tx),
+            cpal::SampleFormat::U32 => run::<u32>(&device, &stream_config, tx),
+            cpal::SampleFormat::U64 => run::<u64>(&device, &stream_config, tx),
+
+            cpal::SampleFormat::F32 => run::<f32>(&device, &stream_config, tx),
+            cpal::SampleFormat::F64 => run::<f64>(&device, &stream_config, tx),
+            sample_format => Err(format!("Unsupported sample format '{sample_format}'").into()),
+        }?;
+
+        Ok(Self { _stream: stream })
+    }
+}
+
+fn run<T: cpal::SizedSample>(
+    device: &cpal::Device,
+    stream_config: &cpal::StreamConfig,
+    tx: EventLoopProxy<NeothesiaEvent>,
+) -> Result<cpal::Stream, Box<dyn Error>>
+where
+    f32: cpal::FromSample<T>,
+{
+    let channels = stream_config.channels as usize;
+    let sample_rate = stream_config.sample_rate;
+
+    // The callback only downmixes, the detector runs on its own thread
+    let (mut producer, consumer) = rtrb::RingBuffer::new((sample_rate * BUFFER_SECS) as usize);
+    std::thread::Builder::new()
+        .name("audio-input-detector".into())
+        .spawn(move || detect(consumer, sample_rate, &tx))?;
+
+    let stream = device.build_input_stream(
+        *stream_config,
+        move |input: &[T], _: &cpal::InputCallbackInfo| {
+            let frames = input.chunks(channels).map(|frame| {
+                frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32
+            });
+
+            // Samples the detector is too late for are dropped
+            let len = producer.slots().min(input.len() / channels);
+            if let Ok(chunk) = producer.write_chunk_uninit(len) {
+                chunk.fill_from_iter(frames);
+            }
+        },
+        |err| log::error!("an error occurred on the audio input stream: {err}"),
+        None,
+    )?;
+    stream.play()?;
+
+    Ok(stream)
+}
+
+/// Feeds the detector until the input stream is closed
+fn detect(
+    mut consumer: rtrb::Consumer<f32>,
+    sample_rate: u32,
+    tx: &EventLoopProxy<NeothesiaEvent>,
+) {
+    let mut detector = LiveDetector::new(sample_rate);
+    let mut events = Vec::new();
+
+    while !consumer.is_abandoned() {
+        let Ok(chunk) = consumer.read_chunk(consumer.slots()) else {
+            continue;
+        };
+        if chunk.is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let (first, second) = chunk.as_slices();
+        detector.push(first, &mut events);
+        detector.push(second, &mut events);
+        chunk.commit_all();
+
+        for event in events.drain(..) {
+            let message = match event {
+                NoteEvent::On { key, velocity } => MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: velocity.into(),
+                },
+                NoteEvent::Off { key } => MidiMessage::NoteOff {
+                    key: key.into(),
+                    vel: 0.into(),
+                },
+            };
+            tx.send_event(NeothesiaEvent::MidiInput {
+                source: InputSource::Microphone,
+                channel: 0,
+                message,
+            })
+            .ok();
+        }
+    }
+}
@@ -1,12 +1,60 @@
+#[cfg(feature = "audio-input")]
+mod audio_input;
+
+use std::fmt::{self, Display, Formatter};
+
 use midi_file::midly::{self, MidiMessage, live::LiveEvent};
 use winit::event_loop::EventLoopProxy;
 
 use crate::NeothesiaEvent;
 
+/// Where a user note comes from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InputSource {
+    /// MIDI devices, the computer keyboard and the on-screen piano
+    Midi,
+    /// Notes heard through the microphone. They are never played back, the synth would otherwise
+    /// double them and the detector would hear its own output.
+    #[cfg(feature = "audio-input")]
+    Microphone,
+}
+
+impl InputSource {
+    /// Whether notes from this source should be echoed to the output
+    pub fn is_audible(self) -> bool {
+        self == InputSource::Midi
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InputDescriptor {
+    MidiIn(midi_io::MidiInputPort),
+    /// Acoustic piano, heard through the default input device
+    #[cfg(feature = "audio-input")]
+    Microphone,
+}
+
+impl Display for InputDescriptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InputDescriptor::MidiIn(port) => write!(f, "{port}"),
+            #[cfg(feature = "audio-input")]
+            InputDescriptor::Microphone => write!(f, "Microphone (Acoustic Piano)"),
+        }
+    }
+}
+
+#[allow(unused)]
+enum InputConnection {
+    Midi(midi_io::MidiInputConnection),
+    #[cfg(feature = "audio-input")]
+    Microphone(audio_input::AudioInputConnection),
+}
+
 pub struct InputManager {
     input: midi_io::MidiInputManager,
     tx: EventLoopProxy<NeothesiaEvent>,
-    current_connection: Option<(midi_io::MidiInputPort, midi_io::MidiInputConnection)>,
+    current_connection: Option<(InputDescriptor, InputConnection)>,
 }
 
 impl InputManager {
@@ -19,15 +67,19 @@ impl InputManager {
         }
     }
 
-    pub fn inputs(&self) -> Vec<midi_io::MidiInputPort> {
-        self.input.inputs()
-    }
+    /// MIDI ports first, the microphone last
+    pub fn inputs(&self) -> Vec<InputDescriptor> {
+        let inputs = self.input.inputs().into_iter().map(InputDescriptor::MidiIn);
 
-    pub fn connect_input(&mut self, port: midi_io::MidiInputPort) {
-        let tx = self.tx.clone();
+        #[cfg(feature = "audio-input")]
+        let inputs = inputs.chain([InputDescriptor::Microphone]);
 
+        inputs.collect()
+    }
+
+    pub fn connect_input(&mut self, input: InputDescriptor) {
         if let Some((current, _)) = self.current_connection.as_ref()
-            && current == &port
+            && current == &input
         {
             return;
         }
@@ -35,7 +87,29 @@ impl InputManager {
         // Close the connection first, as Windows does not like it when we hold 2 connections
         self.current_connection = None;
 
-        self.current_connection = midi_io::MidiInputManager::connect_input(port, move |message| {
+        let connection = match &input {
+            InputDescriptor::MidiIn(port) => {
+                self.connect_midi(port.clone()).map(InputConnection::Midi)
+            }
+            #[cfg(feature = "audio-input")]
+            InputDescriptor::Microphone => {
+                match audio_input::AudioInputConnection::new(self.tx.clone()) {
+                    Ok(connection) => Some(InputConnection::Microphone(connection)),
+                    Err(err) => {
+                        log::error!("{err}");
+                        None
+                    }
+                }
+            }
+        };
+
+        self.current_connection = connection.map(|connection| (input, connection));
+    }
+
+    fn connect_midi(&self, port: midi_io::MidiInputPort) -> Option<midi_io::MidiInputConnection> {
+        let tx = self.tx.clone();
+
+        midi_io::MidiInputManager::connect_input(port, move |message| {
             let event = LiveEvent::parse(message).unwrap();
 
             if let LiveEvent::Midi { channel, message } = event {
@@ -43,6 +117,7 @@ impl InputManager {
                     // Some keyboards send NoteOn event with vel 0 instead of NoteOff
                     midly::MidiMessage::NoteOn { key, vel } if vel == 0 => {
                         tx.send_event(NeothesiaEvent::MidiInput {
+                            source: InputSource::Midi,
                             channel: channel.as_int(),
                             message: MidiMessage::NoteOff { key, vel },
                         })
@@ -50,6 +125,7 @@ impl InputManager {
                     }
                     message => {
                         tx.send_event(NeothesiaEvent::MidiInput {
+                            source: InputSource::Midi,
                             channel: channel.as_int(),
                             message,
                         })
@@ -57,6 +133,7 @@ impl InputManager {
                     }
                 }
             }
-        });
+        })
+        .map(|(_, connection)| connection)
     }
 }
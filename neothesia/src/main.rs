@@ -35,6 +35,8 @@ pub enum NeothesiaEvent {
     /// Go to main menu scene
     MainMenu(Option<song::Song>),
     MidiInput {
+        /// Where the message comes from
+        source: input_manager::InputSource,
         /// The MIDI channel that this message is associated with.
         channel: u8,
         /// The MIDI message type and associated data.
@@ -156,9 +158,13 @@ impl Neothesia {
                 let to = menu_scene::MenuScene::new(&mut self.context, song);
                 self.game_scene = Box::new(to);
             }
-            NeothesiaEvent::MidiInput { channel, message } => {
+            NeothesiaEvent::MidiInput {
+                source,
+                channel,
+                message,
+            } => {
                 self.game_scene
-                    .midi_event(&mut self.context, channel, &message);
+                    .midi_event(&mut self.context, source, channel, &message);
             }
             NeothesiaEvent::Exit => {
                 event_loop.exit();
@@ -1,4 +1,7 @@
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
 use crate::output_manager::OutputDescriptor;
 
@@ -22,13 +25,13 @@ struct MidiOutputConnectionInner {
 
 #[derive(Clone)]
 pub struct MidiOutputConnection {
-    inner: Rc<RefCell<MidiOutputConnectionInner>>,
+    inner: Arc<Mutex<MidiOutputConnectionInner>>,
 }
 
 impl From<midi_io::MidiOutputConnection> for MidiOutputConnection {
     fn from(conn: midi_io::MidiOutputConnection) -> Self {
         Self {
-            inner: Rc::new(RefCell::new(MidiOutputConnectionInner {
+            inner: Arc::new(Mutex::new(MidiOutputConnectionInner {
                 conn,
                 active_notes: Default::default(),
                 buf: Vec::with_capacity(8),
@@ -64,7 +67,7 @@ impl MidiBackend {
 
 impl MidiOutputConnection {
     pub fn midi_event(&self, channel: u4, message: midly::MidiMessage) {
-        let inner = &mut *self.inner.borrow_mut();
+        let inner = &mut *self.inner.lock().unwrap();
         match message {
             midly::MidiMessage::NoteOff { key, .. } => {
                 inner.active_notes.remove(&ActiveNote { key, channel });
@@ -83,7 +86,7 @@ impl MidiOutputConnection {
     }
 
     pub fn stop_all(&self) {
-        let inner = &mut *self.inner.borrow_mut();
+        let inner = &mut *self.inner.lock().unwrap();
         for note in std::mem::take(&mut inner.active_notes).iter() {
             inner.buf.clear();
             let msg = LiveEvent::Midi {
@@ -10,6 +10,7 @@ use synth_backend::SynthBackend;
 use std::{
     fmt::{self, Display, Formatter},
     path::PathBuf,
+    time::Instant,
 };
 
 use midi_file::midly::{MidiMessage, num::u4};
@@ -68,6 +69,18 @@ impl OutputConnection {
             OutputConnection::DummyOutput => {}
         }
     }
+    /// Like [`Self::midi_event`], but the event should be heard at `at`.
+    ///
+    /// The built-in synth applies it at the matching sample (up to its render block size), other
+    /// outputs send it right away.
+    pub fn midi_event_at(&self, channel: u4, msg: MidiMessage, at: Instant) {
+        match self {
+            OutputConnection::Midi(b) => b.midi_event(channel, msg),
+            #[cfg(feature = "synth")]
+            OutputConnection::Synth(b) => b.midi_event_at(channel, msg, at),
+            OutputConnection::DummyOutput => {}
+        }
+    }
     pub fn set_gain(&self, gain: f32) {
         match self {
             #[cfg(feature = "synth")]
@@ -1,4 +1,10 @@
-use std::{error::Error, path::Path, rc::Rc, sync::mpsc::Receiver};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    path::Path,
+    sync::{Arc, mpsc::Receiver},
+    time::{Duration, Instant},
+};
 
 use crate::output_manager::OutputDescriptor;
 
@@ -49,19 +55,26 @@ impl SynthBackend {
         let mut next_value = fluidsynth_adapter(self, rx, path);
 
         #[cfg(all(feature = "oxi-synth", not(feature = "fluid-synth")))]
-        let mut next_value = oxisynth_adapter(self, rx, path, self.gain);
+        let mut next_value =
+            oxisynth_adapter(rx, path, self.stream_config.sample_rate as f32, self.gain);
 
         let err_fn = |err| eprintln!("an error occurred on stream: {err}");
 
         let channels = self.stream_config.channels as usize;
+        let sample_period = Duration::from_secs(1) / self.stream_config.sample_rate;
 
         let stream = self
             .device
             .build_output_stream(
                 self.stream_config,
                 move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    // The first sample of the buffer is treated as "now", scheduled events are
+                    // placed relative to it with sample precision
+                    let mut sample_time = Instant::now();
+
                     for frame in output.chunks_mut(channels) {
-                        let (l, r) = next_value();
+                        let (l, r) = next_value(sample_time);
+                        sample_time += sample_period;
 
                         let l = T::from_sample(l);
                         let r = T::from_sample(r);
@@ -101,7 +114,7 @@ impl SynthBackend {
         };
 
         SynthOutputConnection {
-            _stream: Rc::new(stream),
+            _stream: Arc::new(stream),
             tx,
         }
     }
@@ -113,19 +126,64 @@ impl SynthBackend {
 
 enum SynthEvent {
     SetGain(f32),
-    Midi(oxisynth::MidiEvent),
+    Midi {
+        event: oxisynth::MidiEvent,
+        /// Time at which the event should be heard, `None` means as soon as possible
+        at: Option<Instant>,
+    },
+    /// Drop scheduled events and silence all channels
+    StopAll,
+}
+
+/// Midi events waiting for the sample they are scheduled at
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<(Instant, oxisynth::MidiEvent)>,
+}
+
+impl EventQueue {
+    fn push(&mut self, at: Instant, event: oxisynth::MidiEvent) {
+        // Events arrive in order most of the time, so this is almost always a push to the back.
+        // Equal timestamps keep their arrival order.
+        let id = self.events.partition_point(|(t, _)| *t <= at);
+        self.events.insert(id, (at, event));
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<oxisynth::MidiEvent> {
+        if self.events.front()?.0 <= now {
+            self.events.pop_front().map(|(_, event)| event)
+        } else {
+            None
+        }
+    }
+
+    fn clear(&mut self) {
+        self.events.clear();
+    }
 }
 
 #[derive(Clone)]
 pub struct SynthOutputConnection {
-    _stream: Rc<cpal::Stream>,
+    _stream: Arc<cpal::Stream>,
     tx: std::sync::mpsc::Sender<SynthEvent>,
 }
 
 impl SynthOutputConnection {
     pub fn midi_event(&self, channel: u4, msg: midly::MidiMessage) {
         let event = libmidi_to_oxisynth_event(channel, msg);
-        self.tx.send(SynthEvent::Midi(event)).ok();
+        self.tx.send(SynthEvent::Midi { event, at: None }).ok();
+    }
+
+    /// Schedule the event to be applied at the sample that plays at `at`, rounded up to the
+    /// synth render block (64 samples for oxisynth)
+    pub fn midi_event_at(&self, channel: u4, msg: midly::MidiMessage, at: Instant) {
+        let event = libmidi_to_oxisynth_event(channel, msg);
+        self.tx
+            .send(SynthEvent::Midi {
+                event,
+                at: Some(at),
+            })
+            .ok();
     }
 
     pub fn set_gain(&self, gain: f32) {
@@ -133,18 +191,7 @@ impl SynthOutputConnection {
     }
 
     pub fn stop_all(&self) {
-        for channel in 0..16 {
-            self.tx
-                .send(SynthEvent::Midi(oxisynth::MidiEvent::AllNotesOff {
-                    channel,
-                }))
-                .ok();
-            self.tx
-                .send(SynthEvent::Midi(oxisynth::MidiEvent::AllSoundOff {
-                    channel,
-                }))
-                .ok();
-        }
+        self.tx.send(SynthEvent::StopAll).ok();
     }
 }
 
@@ -189,13 +236,11 @@ fn libmidi_to_oxisynth_event(channel: u4, message: midly::MidiMessage) -> oxisyn
 
 #[cfg(all(feature = "oxi-synth", not(feature = "fluid-synth")))]
 fn oxisynth_adapter<'a>(
-    this: &SynthBackend,
     rx: Receiver<SynthEvent>,
     path: &Path,
+    sample_rate: f32,
     gain: f32,
-) -> impl FnMut() -> (f32, f32) + 'a {
-    let sample_rate = this.stream_config.sample_rate as f32;
-
+) -> impl FnMut(Instant) -> (f32, f32) + 'a {
     let mut synth = oxisynth::Synth::new(oxisynth::SynthDescriptor {
         sample_rate,
         gain,
@@ -223,21 +268,42 @@ fn oxisynth_adapter<'a>(
         synth.add_font(font, true);
     }
 
-    move || {
-        let (l, r) = synth.read_next();
+    let mut queue = EventQueue::default();
 
-        if let Ok(event) = rx.try_recv() {
+    move |sample_time| {
+        while let Ok(event) = rx.try_recv() {
             match event {
                 SynthEvent::SetGain(gain) => {
                     synth.set_gain(gain);
                 }
-                SynthEvent::Midi(event) => {
+                SynthEvent::Midi { event, at: None } => {
                     synth.send_event(event).ok();
                 }
+                SynthEvent::Midi {
+                    event,
+                    at: Some(at),
+                } => {
+                    queue.push(at, event);
+                }
+                SynthEvent::StopAll => {
+                    queue.clear();
+                    for channel in 0..16 {
+                        synth
+                            .send_event(oxisynth::MidiEvent::AllNotesOff { channel })
+                            .ok();
+                        synth
+                            .send_event(oxisynth::MidiEvent::AllSoundOff { channel })
+                            .ok();
+                    }
+                }
             }
         }
 
-        (l, r)
+        while let Some(event) = queue.pop_due(sample_time) {
+            synth.send_event(event).ok();
+        }
+
+        synth.read_next()
     }
 }
 
@@ -246,7 +312,7 @@ fn fluidsynth_adapter<'a>(
     this: &SynthBackend,
     rx: Receiver<SynthEvent>,
     path: &Path,
-) -> impl FnMut() -> (f32, f32) + 'a {
+) -> impl FnMut(Instant) -> (f32, f32) + 'a {
     use fluidlite::{IsSettings, Settings};
 
     let synth = {
@@ -263,71 +329,180 @@ fn fluidsynth_adapter<'a>(
         synth
     };
 
+    let send_event = |synth: &fluidlite::Synth, e: oxisynth::MidiEvent| match e {
+        oxisynth::MidiEvent::NoteOn { channel, key, vel } => {
+            synth.note_on(channel as u32, key as u32, vel as u32).ok();
+        }
+        oxisynth::MidiEvent::NoteOff { channel, key } => {
+            synth.note_off(channel as u32, key as u32).ok();
+        }
+        oxisynth::MidiEvent::PitchBend { channel, value } => {
+            synth.pitch_bend(channel as u32, value as u32).ok();
+        }
+        oxisynth::MidiEvent::ProgramChange {
+            channel,
+            program_id,
+        } => {
+            synth.program_change(channel as u32, program_id as u32).ok();
+        }
+        oxisynth::MidiEvent::ChannelPressure { channel, value } => {
+            synth.channel_pressure(channel as u32, value as u32).ok();
+        }
+        oxisynth::MidiEvent::PolyphonicKeyPressure {
+            channel,
+            key,
+            value,
+        } => {
+            synth
+                .key_pressure(channel as u32, key as u32, value as u32)
+                .ok();
+        }
+        oxisynth::MidiEvent::SystemReset => {
+            synth.system_reset().ok();
+        }
+        oxisynth::MidiEvent::ControlChange {
+            channel,
+            ctrl,
+            value,
+        } => {
+            synth.cc(channel as u32, ctrl as u32, value as u32).ok();
+        }
+        // TODO: Where are those for fluidsynth?
+        oxisynth::MidiEvent::AllNotesOff { .. } => {}
+        oxisynth::MidiEvent::AllSoundOff { .. } => {}
+    };
+
     let mut sample_clock = 0;
     let mut buff: [f32; SAMPLES_SIZE] = [0.0f32; SAMPLES_SIZE];
+    let mut queue = EventQueue::default();
 
-    move || {
+    move |sample_time| {
         let l = buff[sample_clock];
         let r = buff[sample_clock + 1];
 
         sample_clock += 2;
 
+        while let Ok(e) = rx.try_recv() {
+            match e {
+                SynthEvent::SetGain(_g) => {
+                    // TODO
+                }
+                SynthEvent::Midi { event, at: None } => send_event(&synth, event),
+                SynthEvent::Midi {
+                    event,
+                    at: Some(at),
+                } => queue.push(at, event),
+                SynthEvent::StopAll => queue.clear(),
+            }
+        }
+
+        // fluidlite renders whole blocks, so scheduled events land on block boundaries
         if sample_clock == SAMPLES_SIZE {
+            while let Some(event) = queue.pop_due(sample_time) {
+                send_event(&synth, event);
+            }
+
             let buff: &mut [f32] = buff.as_mut();
             synth.write(buff).unwrap();
             sample_clock = 0;
         }
 
-        if let Ok(e) = rx.try_recv() {
-            match e {
-                SynthEvent::SetGain(_g) => {
-                    // TODO
-                }
-                SynthEvent::Midi(e) => match e {
-                    oxisynth::MidiEvent::NoteOn { channel, key, vel } => {
-                        synth.note_on(channel as u32, key as u32, vel as u32).ok();
-                    }
-                    oxisynth::MidiEvent::NoteOff { channel, key } => {
-                        synth.note_off(channel as u32, key as u32).ok();
-                    }
-                    oxisynth::MidiEvent::PitchBend { channel, value } => {
-                        synth.pitch_bend(channel as u32, value as u32).ok();
-                    }
-                    oxisynth::MidiEvent::ProgramChange {
-                        channel,
-                        program_id,
-                    } => {
-                        synth.program_change(channel as u32, program_id as u32).ok();
-                    }
-                    oxisynth::MidiEvent::ChannelPressure { channel, value } => {
-                        synth.channel_pressure(channel as u32, value as u32).ok();
-                    }
-                    oxisynth::MidiEvent::PolyphonicKeyPressure {
-                        channel,
-                        key,
-                        value,
-                    } => {
-                        synth
-                            .key_pressure(channel as u32, key as u32, value as u32)
-                            .ok();
-                    }
-                    oxisynth::MidiEvent::SystemReset => {
-                        synth.system_reset().ok();
-                    }
-                    oxisynth::MidiEvent::ControlChange {
-                        channel,
-                        ctrl,
-                        value,
-                    } => {
-                        synth.cc(channel as u32, ctrl as u32, value as u32).ok();
-                    }
-                    // TODO: Where are those for fluidsynth?
-                    oxisynth::MidiEvent::AllNotesOff { .. } => {}
-                    oxisynth::MidiEvent::AllSoundOff { .. } => {}
-                },
+        (l, r)
+    }
+}
+
+#[cfg(all(test, feature = "oxi-synth", not(feature = "fluid-synth")))]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+    /// oxisynth renders voices in blocks of 64 samples, events take effect at the next block
+    const BLOCK: usize = 64;
+
+    /// Renders the synth offline, feeding it the same timestamps the audio callback would
+    struct OfflineRender {
+        tx: std::sync::mpsc::Sender<SynthEvent>,
+        next_value: Box<dyn FnMut(Instant) -> (f32, f32)>,
+        start: Instant,
+        sample_period: Duration,
+    }
+
+    impl OfflineRender {
+        fn new() -> Self {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let next_value =
+                oxisynth_adapter(rx, Path::new("../sin_wave.sf2"), SAMPLE_RATE as f32, 1.0);
+
+            Self {
+                tx,
+                next_value: Box::new(next_value),
+                start: Instant::now(),
+                sample_period: Duration::from_secs(1) / SAMPLE_RATE,
             }
         }
 
-        (l, r)
+        fn note_on_at(&self, key: u8, offset: Duration) {
+            let event = oxisynth::MidiEvent::NoteOn {
+                channel: 0,
+                key,
+                vel: 127,
+            };
+            let at = Some(self.start + offset);
+            self.tx.send(SynthEvent::Midi { event, at }).unwrap();
+        }
+
+        fn render(&mut self, samples: u32) -> Vec<f32> {
+            (0..samples)
+                .map(|id| (self.next_value)(self.start + self.sample_period * id).0)
+                .collect()
+        }
+    }
+
+    fn first_sound(samples: &[f32]) -> Option<usize> {
+        samples.iter().position(|s| s.abs() > 1e-3)
+    }
+
+    #[test]
+    fn scheduled_event_starts_at_its_sample() {
+        let mut render = OfflineRender::new();
+        render.note_on_at(60, Duration::from_millis(10));
+
+        let samples = render.render(SAMPLE_RATE / 10);
+        let start = first_sound(&samples).unwrap();
+
+        // 10ms at 44.1kHz, plus the wait for the next render block and the attack to get audible
+        assert!(
+            (441..441 + BLOCK + 8).contains(&start),
+            "note started at {start}"
+        );
+    }
+
+    #[test]
+    fn scheduled_events_keep_spacing() {
+        let gap = Duration::from_micros(2500);
+
+        let mut first = OfflineRender::new();
+        first.note_on_at(60, Duration::from_millis(5));
+        let first = first_sound(&first.render(SAMPLE_RATE / 10)).unwrap();
+
+        let mut second = OfflineRender::new();
+        second.note_on_at(60, Duration::from_millis(5) + gap);
+        let second = first_sound(&second.render(SAMPLE_RATE / 10)).unwrap();
+
+        let expected = (gap.as_secs_f64() * SAMPLE_RATE as f64).round() as usize;
+        assert!(
+            second.abs_diff(first + expected) < BLOCK,
+            "notes started at {first} and {second}"
+        );
+    }
+
+    #[test]
+    fn stop_all_drops_scheduled_events() {
+        let mut render = OfflineRender::new();
+        render.note_on_at(60, Duration::from_millis(10));
+        render.tx.send(SynthEvent::StopAll).unwrap();
+
+        let samples = render.render(SAMPLE_RATE / 10);
+        assert_eq!(first_sound(&samples), None);
     }
 }
@@ -10,6 +10,7 @@ use winit::{
 use crate::{
     NeothesiaEvent,
     context::Context,
+    input_manager::InputSource,
     scene::{
         MouseToMidiEventState, NuonRenderer, Scene,
         freeplay::recorder::{FreeplayRecorder, Preview, RecorderStatus},
@@ -184,7 +185,7 @@ impl Scene for FreeplayScene {
         self.dispatch_futures(ctx);
 
         if let Some(preview) = self.preview.as_mut() {
-            preview.update(&mut self.keyboard, ctx, delta);
+            preview.update(&mut self.keyboard, ctx);
         }
 
         let time = 0.0;
@@ -256,12 +257,20 @@ impl Scene for FreeplayScene {
         );
     }
 
-    fn midi_event(&mut self, ctx: &mut Context, channel: u8, message: &MidiMessage) {
+    fn midi_event(
+        &mut self,
+        ctx: &mut Context,
+        source: InputSource,
+        channel: u8,
+        message: &MidiMessage,
+    ) {
         self.recorder.push_event(channel, *message);
         self.keyboard.user_midi_event(message);
-        ctx.output_manager
-            .connection()
-            .midi_event(0.into(), *message);
+        if source.is_audible() {
+            ctx.output_manager
+                .connection()
+                .midi_event(0.into(), *message);
+        }
 
         if let MidiMessage::NoteOn { .. } = message {
             let start = self.keyboard.layout().range.start();
@@ -171,9 +171,10 @@ impl Preview {
         }
     }
 
-    pub fn update(&mut self, keyboard: &mut Keyboard, ctx: &mut Context, delta: Duration) {
-        let midi_events = self.player.update(delta);
-        keyboard.file_midi_events(&ctx.config, &midi_events);
+    pub fn update(&mut self, keyboard: &mut Keyboard, ctx: &mut Context) {
+        if let Some(file_keys) = self.player.update() {
+            keyboard.set_file_keys(&ctx.config, &file_keys);
+        }
 
         if self.player.is_finished() && !self.player.is_paused() {
             self.player.pause();
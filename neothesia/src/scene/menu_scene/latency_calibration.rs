@@ -0,0 +1,253 @@
+use std::time::{Duration, Instant};
+
+use midi_file::midly::MidiMessage;
+
+use crate::{
+    context::Context,
+    scene::menu_scene::{neo_btn, state::connect_io},
+};
+
+const CLICK_INTERVAL: Duration = Duration::from_millis(600);
+/// Clicks played before taps start counting, so the user can catch the rhythm
+const WARMUP_CLICKS: usize = 4;
+const REQUIRED_TAPS: usize = 12;
+
+/// Hi Wood Block on the GM drum channel
+const CLICK_CHANNEL: u8 = 9;
+const CLICK_KEY: u8 = 76;
+
+#[derive(Debug)]
+pub struct LatencyCalibration {
+    started_at: Instant,
+    clicks_played: usize,
+    /// Delays (in seconds) between each click and the tap that answered it, negative for taps
+    /// ahead of the click
+    offsets: Vec<f32>,
+}
+
+impl LatencyCalibration {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            clicks_played: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    fn click_time(&self, id: usize) -> Instant {
+        self.started_at + CLICK_INTERVAL * id as u32
+    }
+
+    /// Returns true when a new click is due
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if self.is_finished() || now < self.click_time(self.clicks_played) {
+            return false;
+        }
+
+        self.clicks_played += 1;
+        true
+    }
+
+    pub fn tap(&mut self, now: Instant) {
+        if self.is_finished() {
+            return;
+        }
+
+        let elapsed = now.duration_since(self.started_at);
+        // Nearest click, taps can land on either side of it
+        let id = (elapsed.as_secs_f32() / CLICK_INTERVAL.as_secs_f32()).round() as usize;
+
+        if id < WARMUP_CLICKS || id >= self.clicks_played {
+            return;
+        }
+
+        let click = self.click_time(id);
+        // Early taps are kept signed, clamping them to zero would pull the result up
+        let offset = match now.checked_duration_since(click) {
+            Some(late) => late.as_secs_f32(),
+            None => -click.duration_since(now).as_secs_f32(),
+        };
+        self.offsets.push(offset);
+    }
+
+    pub fn taps(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.offsets.len() >= REQUIRED_TAPS
+    }
+
+    /// Median of the measured offsets in seconds, robust to the odd missed or doubled tap.
+    /// Negative when the user tapped ahead of the clicks.
+    pub fn result(&self) -> Option<f32> {
+        if !self.is_finished() {
+            return None;
+        }
+
+        let mut offsets = self.offsets.clone();
+        offsets.sort_unstable_by(f32::total_cmp);
+        Some(offsets[offsets.len() / 2])
+    }
+}
+
+impl super::MenuScene {
+    pub fn start_latency_calibration(&mut self) {
+        self.latency_calibration = Some(LatencyCalibration::new(Instant::now()));
+        self.state.go_to(super::Page::LatencyCalibration);
+    }
+
+    pub fn latency_calibration_tap(&mut self, ctx: &mut Context) {
+        let Some(calibration) = self.latency_calibration.as_mut() else {
+            return;
+        };
+
+        calibration.tap(Instant::now());
+
+        if let Some(latency) = calibration.result() {
+            ctx.config.set_latency_offset(latency);
+        }
+    }
+
+    fn play_click(ctx: &Context) {
+        let conn = ctx.output_manager.connection();
+        conn.midi_event(
+            CLICK_CHANNEL.into(),
+            MidiMessage::NoteOn {
+                key: CLICK_KEY.into(),
+                vel: 127.into(),
+            },
+        );
+        conn.midi_event(
+            CLICK_CHANNEL.into(),
+            MidiMessage::NoteOff {
+                key: CLICK_KEY.into(),
+                vel: 0.into(),
+            },
+        );
+    }
+
+    pub fn latency_calibration_page_ui(&mut self, ctx: &mut Context, ui: &mut nuon::Ui) {
+        connect_io(&self.state, ctx);
+
+        let Some(calibration) = self.latency_calibration.as_mut() else {
+            self.state.go_back();
+            return;
+        };
+
+        if calibration.tick(Instant::now()) {
+            Self::play_click(ctx);
+        }
+
+        let win_w = ctx.window_state.logical_size.width;
+        let win_h = ctx.window_state.logical_size.height;
+
+        let btn_w = 320.0;
+        let btn_h = 50.0;
+        let btn_gap = 5.0;
+
+        let text_h = 60.0;
+
+        let full_w = btn_w * 2.0 + btn_gap;
+        let full_h = btn_h + text_h * 2.0;
+
+        let status = if let Some(latency) = calibration.result() {
+            format!("Measured latency: {}ms", (latency * 1000.0).round())
+        } else {
+            format!("Taps: {}/{REQUIRED_TAPS}", calibration.taps())
+        };
+
+        nuon::translate()
+            .x(nuon::center_x(win_w, full_w))
+            .y(nuon::center_y(win_h, full_h))
+            .build(ui, |ui| {
+                nuon::label()
+                    .text("Tap any key along with the clicks")
+                    .font_size(30.0)
+                    .size(full_w, text_h)
+                    .build(ui);
+
+                nuon::translate().y(text_h).add_to_current(ui);
+
+                nuon::label()
+                    .text(status)
+                    .font_size(20.0)
+                    .size(full_w, text_h)
+                    .build(ui);
+
+                nuon::translate().y(text_h).add_to_current(ui);
+
+                if neo_btn().size(btn_w, btn_h).label("Restart").build(ui) {
+                    self.latency_calibration = Some(LatencyCalibration::new(Instant::now()));
+                }
+
+                nuon::translate().x(btn_w).add_to_current(ui);
+                nuon::translate().x(btn_gap).add_to_current(ui);
+
+                if neo_btn().size(btn_w, btn_h).label("Done").build(ui) {
+                    self.latency_calibration = None;
+                    self.state.go_back();
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(latency: Duration) -> LatencyCalibration {
+        let start = Instant::now();
+        let mut calibration = LatencyCalibration::new(start);
+
+        let mut id = 0;
+        while !calibration.is_finished() {
+            let click = start + CLICK_INTERVAL * id;
+            assert!(calibration.tick(click));
+            calibration.tap(click + latency);
+            id += 1;
+        }
+
+        calibration
+    }
+
+    fn assert_ms(result: Option<f32>, ms: f32) {
+        let result = result.expect("calibration is finished");
+        assert!(
+            (result * 1000.0 - ms).abs() < 0.01,
+            "{result}s, expected {ms}ms"
+        );
+    }
+
+    #[test]
+    fn measures_constant_latency() {
+        let calibration = run(Duration::from_millis(80));
+        assert_ms(calibration.result(), 80.0);
+    }
+
+    #[test]
+    fn ignores_warmup_taps() {
+        let calibration = run(Duration::from_millis(30));
+        assert_eq!(calibration.taps(), REQUIRED_TAPS);
+        assert_eq!(calibration.clicks_played, WARMUP_CLICKS + REQUIRED_TAPS);
+    }
+
+    #[test]
+    fn early_taps_stay_signed() {
+        let start = Instant::now();
+        let mut calibration = LatencyCalibration::new(start);
+
+        for id in 0..(WARMUP_CLICKS + REQUIRED_TAPS) as u32 {
+            let click = start + CLICK_INTERVAL * id;
+            calibration.tick(click);
+            // Mostly ahead of the clicks
+            let tap = match id % 3 {
+                0 => click + Duration::from_millis(10),
+                _ => click - Duration::from_millis(20),
+            };
+            calibration.tap(tap);
+        }
+
+        assert_ms(calibration.result(), -20.0);
+    }
+}
@@ -1,41 +1,118 @@
+#[cfg(feature = "transcription")]
+use std::path::Path;
 use std::path::PathBuf;
 
 use crate::{
+    context::Context,
     scene::menu_scene::{MsgFn, on_async},
     song::Song,
     utils::BoxFuture,
 };
 
 use super::UiState;
+#[cfg(feature = "transcription")]
+use super::state::ImportProgress;
 
-pub fn open_midi_file_picker(data: &mut UiState) -> BoxFuture<MsgFn> {
-    data.is_loading = true;
-    on_async(open_midi_file_picker_fut(), |res, data, ctx| {
-        if let Some((midi, path)) = res {
-            ctx.config.set_last_opened_song(Some(path));
-            data.song = Some(Song::new(midi));
+const MIDI_EXTENSIONS: [&str; 2] = ["mid", "midi"];
+
+/// What opening a recording takes, the transcription model and where to report progress
+#[cfg(feature = "transcription")]
+struct AudioImport {
+    model: Option<PathBuf>,
+    progress: ImportProgress,
+}
+
+#[cfg(feature = "transcription")]
+impl AudioImport {
+    fn new(data: &mut UiState, ctx: &Context) -> Self {
+        let progress = ImportProgress::default();
+        data.import_progress = Some(progress.clone());
+        Self {
+            model: ctx.config.transcription_model_path().cloned(),
+            progress,
         }
-        data.is_loading = false;
-    })
+    }
 }
 
-async fn open_midi_file_picker_fut() -> Option<(midi_file::MidiFile, PathBuf)> {
-    let file = rfd::AsyncFileDialog::new()
-        .add_filter("midi", &["mid", "midi"])
-        .pick_file()
-        .await;
+/// Only MIDI files can be opened without transcription support
+#[cfg(not(feature = "transcription"))]
+struct AudioImport;
+
+#[cfg(not(feature = "transcription"))]
+impl AudioImport {
+    fn new(_data: &mut UiState, _ctx: &Context) -> Self {
+        Self
+    }
+}
+
+pub fn open_midi_file_picker(data: &mut UiState, ctx: &Context) -> BoxFuture<MsgFn> {
+    data.is_loading = true;
+
+    let import = AudioImport::new(data, ctx);
+    let backing_track = ctx.config.backing_tracks();
+
+    on_async(
+        open_midi_file_picker_fut(import, backing_track),
+        |res, data, ctx| {
+            if let Some((song, path)) = res {
+                if let Some(path) = path {
+                    ctx.config.set_last_opened_song(Some(path));
+                }
+                data.song = Some(song);
+            }
+            data.is_loading = false;
+            #[cfg(feature = "transcription")]
+            {
+                data.import_progress = None;
+            }
+        },
+    )
+}
+
+/// The song, and the MIDI file to reopen it from next time. Transcriptions are not saved.
+#[cfg_attr(not(feature = "transcription"), allow(unused_variables))]
+async fn open_midi_file_picker_fut(
+    import: AudioImport,
+    backing_track: bool,
+) -> Option<(Song, Option<PathBuf>)> {
+    let dialog = rfd::AsyncFileDialog::new();
+
+    #[cfg(feature = "transcription")]
+    let dialog = {
+        let all: Vec<&str> = MIDI_EXTENSIONS
+            .iter()
+            .chain(&neothesia_ai::AUDIO_EXTENSIONS)
+            .copied()
+            .collect();
+        dialog.add_filter("midi, audio", &all)
+    };
+
+    let dialog = dialog.add_filter("midi", &MIDI_EXTENSIONS);
+
+    #[cfg(feature = "transcription")]
+    let dialog = dialog.add_filter("audio", &neothesia_ai::AUDIO_EXTENSIONS);
+
+    let file = dialog.pick_file().await;
 
     if let Some(file) = file {
         log::info!("File path = {:?}", file.path());
 
+        #[cfg(feature = "transcription")]
+        if is_audio(file.path()) {
+            return transcribe(file.path().to_path_buf(), import.model, import.progress)
+                .await
+                .map(|song| (song, None));
+        }
+
         let thread = crate::utils::task::thread::spawn("midi-loader".into(), move || {
-            let midi = midi_file::MidiFile::new(file.path());
+            let song = Song::open(file.path(), backing_track);
 
-            if let Err(e) = &midi {
+            if let Err(e) = &song {
                 log::error!("{e}");
             }
 
-            midi.map(|midi| (midi, file.path().to_path_buf())).ok()
+            song.map(|song| (song, Some(file.path().to_path_buf())))
+                .ok()
         });
 
         thread.join().await.ok().flatten()
@@ -44,3 +121,81 @@ async fn open_midi_file_picker_fut() -> Option<(midi_file::MidiFile, PathBuf)> {
         None
     }
 }
+
+#[cfg(feature = "transcription")]
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            neothesia_ai::AUDIO_EXTENSIONS
+                .iter()
+                .any(|audio| ext.eq_ignore_ascii_case(audio))
+        })
+}
+
+/// Transcribes a recording into a song, the recording becomes its backing track
+#[cfg(feature = "transcription")]
+async fn transcribe(
+    path: PathBuf,
+    model: Option<PathBuf>,
+    progress: ImportProgress,
+) -> Option<Song> {
+    let Some(model) = model else {
+        log::error!("No transcription model selected");
+        show_error("Select a transcription model in the settings to open audio files".into()).await;
+        return None;
+    };
+
+    let thread = crate::utils::task::thread::spawn("transcription".into(), move || {
+        let model = neothesia_ai::load_model(&model).map_err(|err| format!("{err:#}"))?;
+
+        let options = neothesia_ai::Options {
+            track_beats: true,
+            ..Default::default()
+        };
+        let transcription = neothesia_ai::transcribe(&model, &path, &options, |current| {
+            progress.set(current);
+        })
+        .map_err(|err| format!("{err:#}"))?;
+
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let midi = midi_file::MidiFile::from_smf(name, &transcription.to_smf())?;
+
+        let song = Song::new(midi).with_backing_track(path);
+        // Without a synth the backing track can't play, the notes stay audible instead
+        #[cfg(feature = "synth")]
+        let song = song.mute_tracks();
+        Ok::<_, String>(song)
+    });
+
+    let result = thread.join().await.unwrap_or_else(|panic| {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .map(|reason| reason.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown error".into());
+        Err(format!("The transcription crashed: {reason}"))
+    });
+
+    match result {
+        Ok(song) => Some(song),
+        Err(err) => {
+            log::error!("{err}");
+            show_error(err).await;
+            None
+        }
+    }
+}
+
+#[cfg(feature = "transcription")]
+async fn show_error(description: String) {
+    rfd::AsyncMessageDialog::new()
+        .set_level(rfd::MessageLevel::Error)
+        .set_title("Could not transcribe the recording")
+        .set_description(description)
+        .show()
+        .await;
+}
@@ -8,6 +8,7 @@ use midi_picker::open_midi_file_picker;
 mod neo_btn;
 use neo_btn::{neo_btn, neo_btn_icon};
 
+mod latency_calibration;
 mod settings;
 mod tracks;
 
@@ -21,7 +22,9 @@ use winit::{
     keyboard::{Key, NamedKey},
 };
 
-use crate::{NeothesiaEvent, context::Context, icons, scene::Scene, song::Song};
+use crate::{
+    NeothesiaEvent, context::Context, icons, input_manager::InputSource, scene::Scene, song::Song,
+};
 use midi_file::midly::MidiMessage;
 
 use super::NuonRenderer;
@@ -97,6 +100,7 @@ pub struct MenuScene {
     settings_scroll: nuon::ScrollState,
     midi_input_state: MidiInputState,
     popup: Popup,
+    latency_calibration: Option<latency_calibration::LatencyCalibration>,
 }
 
 impl MenuScene {
@@ -132,6 +136,7 @@ impl MenuScene {
             settings_scroll: nuon::ScrollState::new(),
             midi_input_state: MidiInputState::default(),
             popup: Popup::None,
+            latency_calibration: None,
         }
     }
 
@@ -140,10 +145,12 @@ impl MenuScene {
             let width = ctx.window_state.logical_size.width;
             let height = ctx.window_state.logical_size.height;
 
+            let text = self.state.loading_status();
+
             nuon::label()
                 .size(width, height)
                 .font_size(30.0)
-                .text("Loading...")
+                .text(text)
                 .text_justify(nuon::TextJustify::Center)
                 .build(&mut self.nuon);
             return;
@@ -156,6 +163,7 @@ impl MenuScene {
             Page::Main => self.main_page_ui(ctx, &mut nuon),
             Page::Settings => self.settings_page_ui(ctx, &mut nuon),
             Page::TrackSelection => self.tracks_page_ui(ctx, &mut nuon),
+            Page::LatencyCalibration => self.latency_calibration_page_ui(ctx, &mut nuon),
         }
 
         self.nuon = nuon;
@@ -225,7 +233,8 @@ impl MenuScene {
                     .y(logo_h + post_logo_gap)
                     .build(ui, |ui| {
                         if neo_btn().size(w, h).label("Select File").build(ui) {
-                            self.futures.push(open_midi_file_picker(&mut self.state));
+                            self.futures
+                                .push(open_midi_file_picker(&mut self.state, ctx));
                         }
 
                         nuon::translate().y(h + gap).add_to_current(ui);
@@ -380,7 +389,8 @@ impl Scene for MenuScene {
             }
             Page::Main => {
                 if event.key_pressed(Key::Named(NamedKey::Tab)) {
-                    self.futures.push(open_midi_file_picker(&mut self.state));
+                    self.futures
+                        .push(open_midi_file_picker(&mut self.state, ctx));
                 }
 
                 if event.key_pressed(Key::Named(NamedKey::Enter)) {
@@ -417,22 +427,44 @@ impl Scene for MenuScene {
                     self.state.go_back();
                 }
             }
+            Page::LatencyCalibration => {
+                if event.key_pressed(Key::Named(NamedKey::Escape)) {
+                    self.latency_calibration = None;
+                    self.state.go_back();
+                } else if event.any_key_pressed() {
+                    self.latency_calibration_tap(ctx);
+                }
+            }
         }
     }
 
-    fn midi_event(&mut self, ctx: &mut Context, channel: u8, message: &MidiMessage) {
+    fn midi_event(
+        &mut self,
+        ctx: &mut Context,
+        source: InputSource,
+        channel: u8,
+        message: &MidiMessage,
+    ) {
         match message {
             MidiMessage::NoteOn { key, .. } => {
+                if *self.state.current() == Page::LatencyCalibration {
+                    self.latency_calibration_tap(ctx);
+                }
+
                 self.midi_input_state.note_on(key.as_int());
-                ctx.output_manager
-                    .connection()
-                    .midi_event(channel.into(), *message);
+                if source.is_audible() {
+                    ctx.output_manager
+                        .connection()
+                        .midi_event(channel.into(), *message);
+                }
             }
             MidiMessage::NoteOff { key, .. } => {
                 self.midi_input_state.note_off(key.as_int());
-                ctx.output_manager
-                    .connection()
-                    .midi_event(channel.into(), *message);
+                if source.is_audible() {
+                    ctx.output_manager
+                        .connection()
+                        .midi_event(channel.into(), *message);
+                }
             }
             _ => {}
         }
@@ -72,6 +72,50 @@ impl super::MenuScene {
                         self.settings_input_section(ctx, ui, rows, spacer);
                     });
 
+                #[cfg(feature = "transcription")]
+                nuon::settings_section("Transcription").width(body_w).build(
+                    ui,
+                    |ui, rows, spacer| {
+                        self.settings_transcription_section(ctx, ui, rows, spacer);
+                    },
+                );
+
+                nuon::settings_section("Latency")
+                    .width(body_w)
+                    .build(ui, |ui, rows, spacer| {
+                        self::update_latency_offset(
+                            ctx,
+                            nuon::settings_row_spin()
+                                .title("Latency Offset")
+                                .subtitle(format!(
+                                    "{}ms",
+                                    (ctx.config.latency_offset() * 1000.0).round()
+                                ))
+                                .id("latency-offset")
+                                .build(ui, rows),
+                        );
+
+                        spacer(ui);
+
+                        nuon::settings_row()
+                            .title("Calibrate")
+                            .subtitle("Tap along to clicks to measure the latency")
+                            .body(|ui, row_w, row_h| {
+                                let w = 93.0;
+                                let h = 31.0;
+                                if button()
+                                    .x(row_w - w)
+                                    .y(nuon::center_y(row_h, h))
+                                    .size(w, h)
+                                    .label("Start")
+                                    .build(ui)
+                                {
+                                    self.start_latency_calibration();
+                                }
+                            })
+                            .build(ui, rows);
+                    });
+
                 nuon::settings_section("Note Range")
                     .width(body_w)
                     .build(ui, |ui, rows, spacer| {
@@ -350,11 +394,68 @@ impl super::MenuScene {
         ui: &mut nuon::Ui,
         rows: &dyn Fn(&mut nuon::Ui, nuon::SettingsRow<'_>),
         _spacer: &dyn Fn(&mut nuon::Ui),
+    ) {
+        let row = nuon::settings_row().title("Input");
+
+        // The microphone would hear the synth playing the other tracks
+        #[cfg(feature = "audio-input")]
+        let row = if self.state.selected_input
+            == Some(crate::input_manager::InputDescriptor::Microphone)
+        {
+            row.subtitle("Use headphones while synth tracks play")
+        } else {
+            row
+        };
+
+        row.body(|ui, row_w, row_h| self.settings_input_picker(ui, ctx, row_w, row_h))
+            .build(ui, rows);
+    }
+}
+
+#[cfg(feature = "transcription")]
+impl super::MenuScene {
+    fn settings_transcription_section(
+        &mut self,
+        ctx: &mut Context,
+        ui: &mut nuon::Ui,
+        rows: &dyn Fn(&mut nuon::Ui, nuon::SettingsRow<'_>),
+        spacer: &dyn Fn(&mut nuon::Ui),
     ) {
         nuon::settings_row()
-            .title("Input")
-            .body(|ui, row_w, row_h| self.settings_input_picker(ui, ctx, row_w, row_h))
+            .title("Model")
+            .subtitle(
+                ctx.config
+                    .transcription_model_path()
+                    .and_then(|path| path.file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "Needed to open audio files".to_string()),
+            )
+            .body(|ui, row_w, row_h| {
+                let w = 93.0;
+                let h = 31.0;
+                if button()
+                    .x(row_w - w)
+                    .y(nuon::center_y(row_h, h))
+                    .size(w, h)
+                    .label("Select File")
+                    .build(ui)
+                {
+                    self.futures
+                        .push(self::open_transcription_model_picker(&mut self.state));
+                }
+            })
             .build(ui, rows);
+
+        spacer(ui);
+
+        if nuon::settings_row_toggler()
+            .title("Backing Tracks")
+            .subtitle("Play the song's recording instead of its tracks")
+            .value(ctx.config.backing_tracks())
+            .build(ui, rows)
+        {
+            ctx.config.set_backing_tracks(!ctx.config.backing_tracks());
+        }
     }
 }
 
@@ -474,6 +575,23 @@ pub fn update_audio_gain(ctx: &mut Context, kind: nuon::SettingsRowSpinResult) {
         .set_audio_gain((ctx.config.audio_gain() * 10.0).round() / 10.0);
 }
 
+pub fn update_latency_offset(ctx: &mut Context, kind: nuon::SettingsRowSpinResult) {
+    match kind {
+        nuon::SettingsRowSpinResult::Plus => {
+            ctx.config
+                .set_latency_offset(ctx.config.latency_offset() + 0.005);
+        }
+        nuon::SettingsRowSpinResult::Minus => {
+            ctx.config
+                .set_latency_offset(ctx.config.latency_offset() - 0.005);
+        }
+        nuon::SettingsRowSpinResult::Idle => {}
+    }
+
+    ctx.config
+        .set_latency_offset((ctx.config.latency_offset() * 1000.0).round() / 1000.0);
+}
+
 pub fn update_range_start(ctx: &mut Context, kind: nuon::SettingsRowSpinResult) {
     match kind {
         nuon::SettingsRowSpinResult::Plus => {
@@ -530,3 +648,30 @@ async fn open_sondfont_picker_fut() -> Option<PathBuf> {
 
     file.map(|f| f.path().to_owned())
 }
+
+#[cfg(feature = "transcription")]
+pub fn open_transcription_model_picker(data: &mut UiState) -> BoxFuture<MsgFn> {
+    data.is_loading = true;
+    on_async(open_transcription_model_picker_fut(), |res, data, ctx| {
+        if let Some(model) = res {
+            ctx.config.set_transcription_model_path(Some(model));
+        }
+        data.is_loading = false;
+    })
+}
+
+#[cfg(feature = "transcription")]
+async fn open_transcription_model_picker_fut() -> Option<PathBuf> {
+    let file = rfd::AsyncFileDialog::new()
+        .add_filter("RTen model", &["rten"])
+        .pick_file()
+        .await;
+
+    if let Some(file) = file.as_ref() {
+        log::info!("Transcription model path = {:?}", file.path());
+    } else {
+        log::info!("User canceled dialog");
+    }
+
+    file.map(|f| f.path().to_owned())
+}
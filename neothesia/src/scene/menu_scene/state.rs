@@ -1,8 +1,11 @@
 use std::collections::VecDeque;
+#[cfg(feature = "transcription")]
+use std::sync::{Arc, Mutex};
 
-use crate::{NeothesiaEvent, context::Context, output_manager::OutputDescriptor, song::Song};
-
-type InputDescriptor = midi_io::MidiInputPort;
+use crate::{
+    NeothesiaEvent, context::Context, input_manager::InputDescriptor,
+    output_manager::OutputDescriptor, song::Song,
+};
 
 pub struct UiState {
     pub outputs: Vec<OutputDescriptor>,
@@ -12,6 +15,9 @@ pub struct UiState {
     pub selected_input: Option<InputDescriptor>,
 
     pub is_loading: bool,
+    /// Set while a recording may be transcribed
+    #[cfg(feature = "transcription")]
+    pub import_progress: Option<ImportProgress>,
 
     pub song: Option<Song>,
 
@@ -29,6 +35,8 @@ impl UiState {
             inputs: Vec::new(),
             selected_input: None,
             is_loading: false,
+            #[cfg(feature = "transcription")]
+            import_progress: None,
             song,
 
             page_stack,
@@ -43,6 +51,19 @@ impl UiState {
         self.is_loading
     }
 
+    /// What the loading screen says
+    pub fn loading_status(&self) -> String {
+        #[cfg(feature = "transcription")]
+        if let Some(progress) = self.import_progress.as_ref().and_then(|p| p.get()) {
+            return match progress.ratio() {
+                Some(ratio) => format!("Transcribing... {}%", (ratio * 100.0).round()),
+                None => format!("Transcribing... {}s", progress.transcribed.as_secs()),
+            };
+        }
+
+        "Loading...".to_string()
+    }
+
     pub fn current(&self) -> &Page {
         self.page_stack.front().unwrap()
     }
@@ -93,18 +114,41 @@ impl UiState {
             {
                 self.selected_input = Some(input.clone());
             } else {
-                self.selected_input = self.inputs.first().cloned();
+                // The microphone picks up any sound in the room, it has to be selected
+                self.selected_input = self
+                    .inputs
+                    .iter()
+                    .find(|input| matches!(input, InputDescriptor::MidiIn(_)))
+                    .cloned();
             }
         }
     }
 }
 
+/// Progress of a transcription, shared with the thread running it
+#[cfg(feature = "transcription")]
+#[derive(Debug, Default, Clone)]
+pub struct ImportProgress(Arc<Mutex<Option<neothesia_ai::Progress<'static>>>>);
+
+#[cfg(feature = "transcription")]
+impl ImportProgress {
+    pub fn set(&self, progress: &neothesia_ai::Progress) {
+        *self.0.lock().unwrap() = Some(progress.without_notes());
+    }
+
+    /// `None` until the first part of the recording is transcribed
+    pub fn get(&self) -> Option<neothesia_ai::Progress<'static>> {
+        *self.0.lock().unwrap()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Page {
     Exit,
     Main,
     Settings,
     TrackSelection,
+    LatencyCalibration,
 }
 
 pub fn connect_io(data: &UiState, ctx: &mut Context) {
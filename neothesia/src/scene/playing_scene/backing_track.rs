@@ -0,0 +1,375 @@
+//! The recording of a song, played along with it.
+//!
+//! The sequencer calls [`BackingTrack::sync`] on every tick. The recording keeps playing on the
+//! audio clock as long as it stays close to the song time, pauses, seeks and loops make it jump
+//! to the song time. Speed changes resample the recording, so its pitch follows the speed.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use neothesia_ai::audio::{AudioStream, Channels};
+
+/// Further apart than this, in seconds, the recording jumps to the song time. More than an audio
+/// buffer, the position only moves once per buffer.
+const MAX_DRIFT: f64 = 0.1;
+
+/// Seconds of audio decoded ahead of the playback position
+const BUFFER_AHEAD: f64 = 2.0;
+
+/// Further ahead of the decoded audio than this, in seconds, the decoder seeks instead of
+/// catching up
+const MAX_GAP: f64 = 0.5;
+
+/// Frames the decoder adds to the ring at once
+const CHUNK_FRAMES: usize = 1024;
+
+/// Decoded stereo frames from the playback position on, filled by the decoder thread and
+/// drained by the audio callback
+struct Ring {
+    /// Recording frame of the front of `frames`
+    start: u64,
+    frames: VecDeque<[f32; 2]>,
+    /// The decoder waits while this many frames are buffered
+    capacity: usize,
+    max_gap: u64,
+    /// Frame the decoder has to move to
+    seek: Option<u64>,
+    /// The decoder reached the end of the recording
+    end: bool,
+    closed: bool,
+}
+
+impl Ring {
+    fn new(capacity: usize, max_gap: u64) -> Self {
+        Self {
+            start: 0,
+            // Never grows, the decoder stops short of one more chunk
+            frames: VecDeque::with_capacity(capacity + CHUNK_FRAMES),
+            capacity,
+            max_gap,
+            seek: None,
+            end: false,
+            closed: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.end || self.frames.len() >= self.capacity
+    }
+
+    /// Stereo sample at recording frame `position`, frames before it are dropped. Silence
+    /// outside of the recording and until the decoder catches up.
+    fn sample_at(&mut self, position: f64) -> (f32, f32) {
+        let id = position.max(0.0) as u64;
+        let buffered_end = self.start + self.frames.len() as u64;
+
+        if self.seek.is_none() && (id < self.start || id > buffered_end + self.max_gap) {
+            self.frames.clear();
+            self.start = id;
+            self.seek = Some(id);
+            self.end = false;
+        }
+        if self.seek.is_some() || position < 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let drop = (id - self.start).min(self.frames.len() as u64);
+        self.frames.drain(..drop as usize);
+        self.start += drop;
+
+        let Some([l, r]) = self.frames.front().copied() else {
+            return (0.0, 0.0);
+        };
+        let [next_l, next_r] = self.frames.get(1).copied().unwrap_or([l, r]);
+        let fract = (position - id as f64) as f32;
+        (l + (next_l - l) * fract, r + (next_r - r) * fract)
+    }
+}
+
+type SharedRing = Arc<(Mutex<Ring>, Condvar)>;
+
+/// Keeps the ring filled, runs on its own thread until the ring is closed
+fn decode(path: &Path, sample_rate: u32, ring: &SharedRing) -> anyhow::Result<()> {
+    let (ring, wake) = &**ring;
+    let mut stream = AudioStream::open_with(path, sample_rate, Channels::Stereo)?;
+    let mut buf = vec![0.0; CHUNK_FRAMES * 2];
+
+    loop {
+        let seek = {
+            let ring = ring.lock().unwrap();
+            let mut ring = wake
+                .wait_while(ring, |ring| {
+                    !ring.closed && ring.seek.is_none() && ring.is_full()
+                })
+                .unwrap();
+            if ring.closed {
+                return Ok(());
+            }
+            ring.seek.take()
+        };
+
+        if let Some(frame) = seek {
+            stream.seek(Duration::from_secs_f64(frame as f64 / sample_rate as f64))?;
+        }
+        let len = stream.read(&mut buf)?;
+
+        let mut ring = ring.lock().unwrap();
+        // A newer seek came in while decoding
+        if ring.seek.is_some() {
+            continue;
+        }
+        ring.frames
+            .extend(buf[..len].chunks_exact(2).map(|frame| [frame[0], frame[1]]));
+        ring.end = len < buf.len();
+    }
+}
+
+/// Shared by the sequencer and the audio callback
+#[derive(Debug)]
+struct Transport {
+    /// Seconds into the recording, negative during the lead-in
+    position: f64,
+    speed: f64,
+    playing: bool,
+}
+
+impl Transport {
+    fn sync(&mut self, time: f64, speed: f32, playing: bool) {
+        self.speed = speed as f64;
+        self.playing = playing;
+
+        if !playing || (self.position - time).abs() > MAX_DRIFT {
+            self.position = time;
+        }
+    }
+
+    /// Moves on by one output sample of `period` seconds
+    fn advance(&mut self, period: f64) {
+        if self.playing {
+            self.position += period * self.speed;
+        }
+    }
+}
+
+pub struct BackingTrack {
+    _stream: cpal::Stream,
+    transport: Arc<Mutex<Transport>>,
+    ring: SharedRing,
+}
+
+impl BackingTrack {
+    /// Opens an output stream right away, the recording is decoded on a separate thread and
+    /// stays silent until the first frames are in
+    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or("failed to find a default output device")?;
+
+        let config = device.default_output_config()?;
+        let sample_format = config.sample_format();
+        let stream_config: cpal::StreamConfig = config.into();
+        let sample_rate = stream_config.sample_rate;
+
+        let ring: SharedRing = Arc::new((
+            Mutex::new(Ring::new(
+                (BUFFER_AHEAD * sample_rate as f64) as usize,
+                (MAX_GAP * sample_rate as f64) as u64,
+            )),
+            Condvar::new(),
+        ));
+        std::thread::Builder::new()
+            .name("backing-track-decoder".into())
+            .spawn({
+                let ring = ring.clone();
+                let path: PathBuf = path.to_owned();
+                move || {
+                    if let Err(err) = decode(&path, sample_rate, &ring) {
+                        log::error!("Could not play {}: {err:#}", path.display());
+                    }
+                }
+            })?;
+
+        let transport = Arc::new(Mutex::new(Transport {
+            position: 0.0,
+            speed: 1.0,
+            playing: false,
+        }));
+
+        let stream = match sample_format {
+            cpal::SampleFormat::I8 => run::<i8>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::I16 => run::<i16>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::I32 => run::<i32>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::I64 => run::<i64>(&device, &stream_config, &transport, &ring),
+
+            cpal::SampleFormat::U8 => run::<u8>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::U16 => run::<u16>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::U32 => run::<u32>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::U64 => run::<u64>(&device, &stream_config, &transport, &ring),
+
+            cpal::SampleFormat::F32 => run::<f32>(&device, &stream_config, &transport, &ring),
+            cpal::SampleFormat::F64 => run::<f64>(&device, &stream_config, &transport, &ring),
+            sample_format => Err(format!("Unsupported sample format '{sample_format}'").into()),
+        }?;
+
+        Ok(Self {
+            _stream: stream,
+            transport,
+            ring,
+        })
+    }
+
+    /// Follows the song, `time` is the song time in seconds, without the lead-in
+    pub fn sync(&self, time: f64, speed: f32, playing: bool) {
+        self.transport.lock().unwrap().sync(time, speed, playing);
+    }
+}
+
+impl Drop for BackingTrack {
+    fn drop(&mut self) {
+        let (ring, wake) = &*self.ring;
+        ring.lock().unwrap().closed = true;
+        wake.notify_one();
+    }
+}
+
+fn run<T: cpal::SizedSample + cpal::FromSample<f32>>(
+    device: &cpal::Device,
+    stream_config: &cpal::StreamConfig,
+    transport: &Arc<Mutex<Transport>>,
+    ring: &SharedRing,
+) -> Result<cpal::Stream, Box<dyn Error>> {
+    let transport = transport.clone();
+    let ring = ring.clone();
+    let channels = stream_config.channels as usize;
+    let sample_rate = stream_config.sample_rate as f64;
+
+    let stream = device.build_output_stream(
+        *stream_config,
+        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut transport = transport.lock().unwrap();
+            let (ring, wake) = &*ring;
+            let mut ring = ring.lock().unwrap();
+
+            for frame in output.chunks_mut(channels) {
+                let (l, r) = if transport.playing {
+                    ring.sample_at(transport.position * sample_rate)
+                } else {
+                    (0.0, 0.0)
+                };
+                transport.advance(1.0 / sample_rate);
+
+                let channels = [T::from_sample(l), T::from_sample(r)];
+                for (id, sample) in frame.iter_mut().enumerate() {
+                    *sample = channels[id % 2];
+                }
+            }
+
+            // Frames were taken, or a seek requested
+            if !ring.is_full() || ring.seek.is_some() {
+                wake.notify_one();
+            }
+        },
+        |err| log::error!("an error occurred on the backing track stream: {err}"),
+        None,
+    )?;
+    stream.play()?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport() -> Transport {
+        Transport {
+            position: 0.0,
+            speed: 1.0,
+            playing: false,
+        }
+    }
+
+    /// Plays `secs` of audio at 1kHz
+    fn play(transport: &mut Transport, secs: f64) {
+        for _ in 0..(secs * 1000.0).round() as usize {
+            transport.advance(0.001);
+        }
+    }
+
+    #[test]
+    fn follows_playback() {
+        let mut transport = transport();
+        transport.sync(-3.0, 1.0, true);
+        play(&mut transport, 3.5);
+        // The audio clock runs a bit slower than the song
+        transport.sync(0.52, 1.0, true);
+        assert!((transport.position - 0.5).abs() < 1e-9);
+
+        transport.sync(0.52, 2.0, true);
+        play(&mut transport, 0.01);
+        assert!((transport.position - 0.52).abs() < 1e-9);
+    }
+
+    #[test]
+    fn jumps_on_seek_and_pause() {
+        let mut transport = transport();
+        transport.sync(0.0, 1.0, true);
+        play(&mut transport, 1.0);
+
+        // Seek backwards, eg. a loop
+        transport.sync(0.2, 1.0, true);
+        assert_eq!(transport.position, 0.2);
+
+        transport.sync(0.25, 1.0, false);
+        assert_eq!(transport.position, 0.25);
+        play(&mut transport, 1.0);
+        assert_eq!(transport.position, 0.25);
+    }
+
+    fn ring(frames: impl IntoIterator<Item = f32>) -> Ring {
+        let mut ring = Ring::new(4, 2);
+        ring.frames.extend(frames.into_iter().map(|s| [s, -s]));
+        ring
+    }
+
+    #[test]
+    fn interpolates_samples() {
+        let mut ring = ring([0.0, 1.0, 0.0]);
+
+        assert_eq!(ring.sample_at(-1.0), (0.0, 0.0));
+        assert_eq!(ring.sample_at(0.5), (0.5, -0.5));
+        // Frames behind the position are dropped
+        assert_eq!(ring.sample_at(2.0), (0.0, 0.0));
+        assert_eq!((ring.start, ring.frames.len()), (2, 1));
+        assert_eq!(ring.seek, None);
+    }
+
+    #[test]
+    fn seeks_on_jumps() {
+        let mut ring = ring([0.5; 4]);
+        ring.start = 10;
+
+        // Backwards
+        assert_eq!(ring.sample_at(2.0), (0.0, 0.0));
+        assert_eq!(ring.seek, Some(2));
+        assert!(ring.frames.is_empty());
+
+        // The decoder is still behind, but close
+        ring.seek = None;
+        ring.frames.extend([[0.5, 0.5]; 2]);
+        assert_eq!(ring.sample_at(5.0), (0.0, 0.0));
+        assert_eq!(ring.seek, None);
+
+        // Too far ahead of the decoder
+        assert_eq!(ring.sample_at(9.0), (0.0, 0.0));
+        assert_eq!(ring.seek, Some(9));
+        assert_eq!(ring.start, 9);
+    }
+}
@@ -2,7 +2,7 @@ use midi_file::midly::MidiMessage;
 use neothesia_core::{
     config::ColorSchemaV1,
     piano_layout,
-    render::{KeyboardKeyState, QuadRenderer, TextRenderer},
+    render::{KEYBOARD_HEIGHT_RATIO, KeyboardKeyState, QuadRenderer, TextRenderer},
     utils::Point,
 };
 use piano_layout::KeyboardRange;
@@ -22,7 +22,7 @@ fn get_layout(
 ) -> piano_layout::KeyboardLayout {
     let white_count = range.white_count();
     let neutral_width = width / white_count as f32;
-    let neutral_height = height * 0.2;
+    let neutral_height = height * KEYBOARD_HEIGHT_RATIO;
 
     piano_layout::KeyboardLayout::from_range(
         piano_layout::Sizing::new(neutral_width, neutral_height),
@@ -128,35 +128,18 @@ impl Keyboard {
         }
     }
 
-    pub fn file_midi_events(&mut self, config: &Config, events: &[&midi_file::MidiEvent]) {
+    /// Applies the keys held down by the file, see [`super::MidiPlayer::update`]
+    pub fn set_file_keys(&mut self, config: &Config, keys: &[Option<usize>; 128]) {
         let range_start = self.range().start() as usize;
+        let schema = config.color_schema();
 
-        for e in events {
-            let track = &self.song_config.tracks[e.track_id];
-            if !track.visible {
-                continue;
-            }
-
-            let (is_on, key) = match e.message {
-                MidiMessage::NoteOn { key, .. } => (true, key.as_int()),
-                MidiMessage::NoteOff { key, .. } => (false, key.as_int()),
-                _ => continue,
-            };
-
-            if self.range().contains(key) && e.channel != 9 {
-                let id = key as usize - range_start;
-                let key = &mut self.renderer.key_states_mut()[id];
-
-                if is_on {
-                    let color =
-                        &config.color_schema()[e.track_color_id % config.color_schema().len()];
-                    key.pressed_by_file_on(color);
-                } else {
-                    key.pressed_by_file_off();
-                }
-
-                self.renderer.invalidate_cache();
+        for (id, state) in self.renderer.key_states_mut().iter_mut().enumerate() {
+            match keys[range_start + id] {
+                Some(color_id) => state.pressed_by_file_on(&schema[color_id % schema.len()]),
+                None => state.pressed_by_file_off(),
             }
         }
+
+        self.renderer.invalidate_cache();
     }
 }
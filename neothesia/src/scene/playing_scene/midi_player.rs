@@ -1,21 +1,48 @@
 use midi_file::midly::{MidiMessage, num::u4};
 
+#[cfg(all(feature = "synth", feature = "transcription"))]
+use super::backing_track::BackingTrack;
 use crate::{
+    input_manager::InputSource,
     output_manager::OutputConnection,
     song::{PlayerConfig, Song},
 };
 use neothesia_core::piano_layout;
 use std::{
     collections::{HashMap, HashSet},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
+/// How often the sequencer thread advances playback. It sleeps until woken up while nothing
+/// plays, see [`Sequencer::is_idle`].
+const TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How far ahead of the tick that dispatched them file events are scheduled.
+///
+/// Events due within a tick are spread over the wall-clock time that tick covered, delaying them
+/// by a bit more than a tick (and its scheduling jitter) lets the synth play them with their
+/// original spacing.
+const SCHEDULE_AHEAD: Duration = Duration::from_millis(5);
+
+/// Plays the song on a dedicated sequencer thread.
+///
+/// Events reach the outputs on the sequencer clock, independently of the frame rate, the render
+/// loop only reads the current position and which keys the file holds down.
 pub struct MidiPlayer {
-    playback: midi_file::PlaybackState,
+    sequencer: Arc<Mutex<Sequencer>>,
+    /// Wakes the sequencer thread up when playback may go on
+    wake: Arc<Condvar>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+
     output: OutputConnection,
     song: Song,
-    play_along: PlayAlong,
-    separate_channels: bool,
+    leed_in: Duration,
 }
 
 impl MidiPlayer {
@@ -41,79 +68,113 @@ impl MidiPlayer {
         separate_channels: bool,
         lead_in: Duration,
     ) -> Self {
-        let mut player = Self {
+        let sequencer = Sequencer {
             playback: midi_file::PlaybackState::new(lead_in, song.file.tracks.clone()),
-            output,
+            last_tick: Instant::now(),
+            speed: 1.0,
+            output: output.clone(),
             play_along: PlayAlong::new(user_keyboard_range),
-            song,
+            song: song.clone(),
             separate_channels,
+            file_keys: [None; 128],
+            file_keys_changed: false,
+            #[cfg(all(feature = "synth", feature = "transcription"))]
+            backing_track: song.backing_track.as_deref().and_then(|path| {
+                BackingTrack::new(path)
+                    .inspect_err(|err| log::error!("{err}"))
+                    .ok()
+            }),
         };
         // Let's reset programs,
         // for timestamp 0 most likely all programs will be 0, so this should clean any leftovers
         // from previous songs
-        player.send_midi_programs_for_timestamp(&player.playback.time());
-        player.update(Duration::ZERO);
+        sequencer.send_midi_programs_for_timestamp(&sequencer.playback.time());
+
+        let sequencer = Arc::new(Mutex::new(sequencer));
+        let wake = Arc::new(Condvar::new());
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = std::thread::Builder::new()
+            .name("sequencer".into())
+            .spawn({
+                let sequencer = sequencer.clone();
+                let wake = wake.clone();
+                let running = running.clone();
+                move || {
+                    while running.load(Ordering::Relaxed) {
+                        let mut guard = sequencer.lock().unwrap();
+                        guard.tick(Instant::now());
+
+                        if guard.is_idle() {
+                            guard = wake
+                                .wait_while(guard, |sequencer| {
+                                    sequencer.is_idle() && running.load(Ordering::Relaxed)
+                                })
+                                .unwrap();
+                            // No time went by for the song while idle
+                            guard.last_tick = Instant::now();
+                        } else {
+                            drop(guard);
+                            std::thread::sleep(TICK_INTERVAL);
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the sequencer thread");
 
-        player
+        Self {
+            sequencer,
+            wake,
+            running,
+            thread: Some(thread),
+            output,
+            song,
+            leed_in: lead_in,
+        }
     }
 
-    pub fn song(&self) -> &Song {
-        &self.song
+    fn sequencer(&self) -> MutexGuard<'_, Sequencer> {
+        self.sequencer.lock().unwrap()
     }
 
-    /// When playing: returns midi events
-    ///
-    /// When paused: returns None
-    pub fn update(&mut self, delta: Duration) -> Vec<&midi_file::MidiEvent> {
-        self.play_along.update();
-
-        let events = self.playback.update(delta);
-
-        events.iter().for_each(|event| {
-            let config = &self.song.config.tracks[event.track_id];
-
-            let channel = if self.separate_channels {
-                event.track_color_id as u8
-            } else {
-                event.channel
-            };
-            match config.player {
-                PlayerConfig::Auto => {
-                    self.output // TODO: Send to multiple outputs
-                        .midi_event(u4::new(channel), event.message);
-                }
-                PlayerConfig::Human => {
-                    self.play_along
-                        .midi_event(MidiEventSource::File, &event.message);
+    /// Lets the sequencer thread check whether playback goes on
+    fn wake(&self) {
+        self.wake.notify_one();
+    }
 
-                    // In Human mode note events from the file are targets for the player,
-                    // not notes to be played by the synthesizer. Keep forwarding controller
-                    // and other non-note events so the track still sounds as intended.
-                    if should_forward_human_event(&event.message) {
-                        self.output.midi_event(u4::new(channel), event.message);
-                    }
-                }
-                PlayerConfig::Mute => {}
-            }
-        });
+    pub fn song(&self) -> &Song {
+        &self.song
+    }
 
-        events
+    /// Returns the keys held down by visible tracks, indexed by key number and holding the
+    /// track color id, or `None` when nothing changed since the last call
+    pub fn update(&mut self) -> Option<[Option<usize>; 128]> {
+        let mut sequencer = self.sequencer();
+        std::mem::take(&mut sequencer.file_keys_changed).then_some(sequencer.file_keys)
     }
 
-    fn clear(&mut self) {
-        self.output.stop_all();
+    /// Playback speed relative to the file tempo
+    pub fn set_speed(&mut self, speed: f32) {
+        self.sequencer().speed = speed.max(0.0);
     }
 }
 
 impl Drop for MidiPlayer {
     fn drop(&mut self) {
-        self.clear();
+        self.running.store(false, Ordering::Relaxed);
+        // Taking the lock, the thread can't miss the wake up between its check and its wait
+        drop(self.sequencer());
+        self.wake();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        self.output.stop_all();
     }
 }
 
 impl MidiPlayer {
     pub fn pause_resume(&mut self) {
-        if self.playback.is_paused() {
+        if self.is_paused() {
             self.resume();
         } else {
             self.pause();
@@ -121,39 +182,27 @@ impl MidiPlayer {
     }
 
     pub fn pause(&mut self) {
-        self.clear();
-        self.playback.pause();
+        let mut sequencer = self.sequencer();
+        sequencer.output.stop_all();
+        sequencer.playback.pause();
     }
 
     pub fn resume(&mut self) {
-        self.playback.resume();
-        self.play_along.clear();
-    }
-
-    fn send_midi_programs_for_timestamp(&self, time: &Duration) {
-        for (&channel, &p) in self.song.file.program_track.program_for_timestamp(time) {
-            self.output.midi_event(
-                u4::new(channel),
-                midi_file::midly::MidiMessage::ProgramChange {
-                    program: midi_file::midly::num::u7::new(p),
-                },
-            );
-        }
+        let mut sequencer = self.sequencer();
+        sequencer.playback.resume();
+        sequencer.play_along.clear();
+        drop(sequencer);
+        self.wake();
     }
 
     pub fn set_time(&mut self, time: Duration) {
-        self.playback.set_time(time);
-
-        // Discard all of the events till that point
-        let events = self.playback.update(Duration::ZERO);
-        std::mem::drop(events);
-
-        self.clear();
-        self.send_midi_programs_for_timestamp(&time);
+        self.sequencer().set_time(time);
+        self.wake();
     }
 
     pub fn rewind(&mut self, delta: i64) {
-        let mut time = self.playback.time();
+        let mut sequencer = self.sequencer();
+        let mut time = sequencer.playback.time();
 
         if delta < 0 {
             let delta = Duration::from_millis((-delta) as u64);
@@ -163,15 +212,17 @@ impl MidiPlayer {
             time = time.saturating_add(delta);
         }
 
-        self.set_time(time);
+        sequencer.set_time(time);
+        drop(sequencer);
+        self.wake();
     }
 
     pub fn percentage_to_time(&self, p: f32) -> Duration {
-        Duration::from_secs_f32((p * self.playback.length().as_secs_f32()).max(0.0))
+        Duration::from_secs_f32((p * self.length().as_secs_f32()).max(0.0))
     }
 
     pub fn time_to_percentage(&self, time: &Duration) -> f32 {
-        time.as_secs_f32() / self.playback.length().as_secs_f32()
+        time.as_secs_f32() / self.length().as_secs_f32()
     }
 
     pub fn set_percentage_time(&mut self, p: f32) {
@@ -179,42 +230,209 @@ impl MidiPlayer {
     }
 
     pub fn leed_in(&self) -> &Duration {
-        self.playback.leed_in()
+        &self.leed_in
     }
 
     pub fn length(&self) -> Duration {
-        self.playback.length()
+        self.sequencer().playback.length()
     }
 
     pub fn percentage(&self) -> f32 {
-        self.playback.percentage()
+        self.sequencer().playback.percentage()
     }
 
     pub fn is_finished(&self) -> bool {
-        self.playback.is_finished()
+        self.sequencer().playback.is_finished()
     }
 
     pub fn time(&self) -> Duration {
-        self.playback.time()
+        self.sequencer().playback.time()
     }
 
     pub fn time_without_lead_in(&self) -> f32 {
-        self.playback.time().as_secs_f32() - self.playback.leed_in().as_secs_f32()
+        self.time().as_secs_f32() - self.leed_in.as_secs_f32()
     }
 
     pub fn is_paused(&self) -> bool {
-        self.playback.is_paused()
+        self.sequencer().playback.is_paused()
     }
 }
 
 impl MidiPlayer {
-    pub fn play_along(&self) -> &PlayAlong {
-        &self.play_along
+    pub fn set_latency_offset(&mut self, latency: Duration) {
+        self.sequencer().play_along.set_latency_offset(latency);
     }
 
-    pub fn user_midi_event(&mut self, channel: u8, message: &MidiMessage) {
-        self.output.midi_event(u4::new(channel), *message);
-        self.play_along.midi_event(MidiEventSource::User, message);
+    pub fn user_midi_event(&mut self, source: InputSource, channel: u8, message: &MidiMessage) {
+        if source.is_audible() {
+            self.output.midi_event(u4::new(channel), *message);
+        }
+        self.sequencer()
+            .play_along
+            .midi_event(MidiEventSource::User, message);
+        self.wake();
+    }
+}
+
+/// Playback state owned by the sequencer thread
+struct Sequencer {
+    playback: midi_file::PlaybackState,
+    /// Wall-clock time of the last `tick`
+    last_tick: Instant,
+    speed: f32,
+    output: OutputConnection,
+    play_along: PlayAlong,
+    song: Song,
+    separate_channels: bool,
+    /// Track color id of the visible track holding each key down
+    file_keys: [Option<usize>; 128],
+    /// Whether `file_keys` changed since the render loop last read it
+    file_keys_changed: bool,
+    #[cfg(all(feature = "synth", feature = "transcription"))]
+    backing_track: Option<BackingTrack>,
+}
+
+impl Sequencer {
+    /// Nothing plays until the user resumes, seeks or presses the keys the song waits for
+    fn is_idle(&self) -> bool {
+        self.playback.is_paused()
+            || self.playback.is_finished()
+            || !self.play_along.are_required_keys_pressed()
+    }
+
+    fn tick(&mut self, now: Instant) {
+        self.play_along.update();
+
+        let wall = now.saturating_duration_since(self.last_tick);
+        self.last_tick = now;
+
+        // In play along mode the song waits for the user to press the required keys
+        let waiting = !self.play_along.are_required_keys_pressed();
+
+        #[cfg(all(feature = "synth", feature = "transcription"))]
+        if let Some(backing_track) = &self.backing_track {
+            let time = self.playback.time().as_secs_f64() - self.playback.leed_in().as_secs_f64();
+            let playing = !waiting && !self.playback.is_paused();
+            backing_track.sync(time, self.speed, playing);
+        }
+
+        if waiting {
+            return;
+        }
+
+        let delta = wall.mul_f32(self.speed);
+        let start = self.playback.time();
+        let end = if self.playback.is_paused() {
+            start
+        } else {
+            start + delta
+        };
+        let clock = FrameClock::new(now, wall, start, end);
+
+        let leed_in = *self.playback.leed_in();
+
+        for event in self.playback.update(delta) {
+            let at = clock.wall_time(event.timestamp + leed_in) + SCHEDULE_AHEAD;
+
+            let config = &self.song.config.tracks[event.track_id];
+
+            let channel = if self.separate_channels {
+                event.track_color_id as u8
+            } else {
+                event.channel
+            };
+            match config.player {
+                PlayerConfig::Auto => {
+                    self.output // TODO: Send to multiple outputs
+                        .midi_event_at(u4::new(channel), event.message, at);
+                }
+                PlayerConfig::Human => {
+                    self.play_along
+                        .midi_event(MidiEventSource::File, &event.message);
+
+                    // In Human mode note events from the file are targets for the player,
+                    // not notes to be played by the synthesizer. Keep forwarding controller
+                    // and other non-note events so the track still sounds as intended.
+                    if should_forward_human_event(&event.message) {
+                        self.output
+                            .midi_event_at(u4::new(channel), event.message, at);
+                    }
+                }
+                PlayerConfig::Mute => {}
+            }
+
+            if config.visible
+                && event.channel != 9
+                && let Some((key, state)) = file_key_state(event)
+            {
+                self.file_keys[key as usize] = state;
+                self.file_keys_changed = true;
+            }
+        }
+    }
+
+    fn send_midi_programs_for_timestamp(&self, time: &Duration) {
+        for (&channel, &p) in self.song.file.program_track.program_for_timestamp(time) {
+            self.output.midi_event(
+                u4::new(channel),
+                midi_file::midly::MidiMessage::ProgramChange {
+                    program: midi_file::midly::num::u7::new(p),
+                },
+            );
+        }
+    }
+
+    fn set_time(&mut self, time: Duration) {
+        self.playback.set_time(time);
+
+        self.file_keys = [None; 128];
+        self.file_keys_changed = true;
+        self.output.stop_all();
+        self.send_midi_programs_for_timestamp(&time);
+    }
+}
+
+/// Returns the key a note event changes and the track color id holding it down afterwards
+fn file_key_state(event: &midi_file::MidiEvent) -> Option<(u8, Option<usize>)> {
+    match event.message {
+        midi_file::midly::MidiMessage::NoteOn { key, .. } => {
+            Some((key.as_int(), Some(event.track_color_id)))
+        }
+        midi_file::midly::MidiMessage::NoteOff { key, .. } => Some((key.as_int(), None)),
+        _ => None,
+    }
+}
+
+/// Maps playback time covered by a single tick to the wall-clock time of that tick
+struct FrameClock {
+    wall_start: Instant,
+    wall_end: Instant,
+    start: Duration,
+    end: Duration,
+}
+
+impl FrameClock {
+    /// `elapsed` is the real time since the last tick, not scaled by the playback speed, the
+    /// playback moved from `start` to `end` meanwhile
+    fn new(now: Instant, elapsed: Duration, start: Duration, end: Duration) -> Self {
+        Self {
+            wall_start: now - elapsed,
+            wall_end: now,
+            start,
+            end,
+        }
+    }
+
+    fn wall_time(&self, time: Duration) -> Instant {
+        if self.end <= self.start {
+            return self.wall_end;
+        }
+
+        let progress =
+            time.saturating_sub(self.start).as_secs_f64() / (self.end - self.start).as_secs_f64();
+        let wall = self.wall_end.duration_since(self.wall_start);
+
+        self.wall_start + wall.mul_f64(progress.min(1.0))
     }
 }
 
@@ -278,6 +496,8 @@ struct NotePress {
 #[derive(Debug)]
 pub struct PlayAlong {
     user_keyboard_range: piano_layout::KeyboardRange,
+    /// Calibrated delay between the user pressing a key and the event reaching us
+    latency_offset: Duration,
 
     /// Notes required to proggres further in the song
     required_notes: HashMap<NoteId, NotePress>,
@@ -294,6 +514,7 @@ impl PlayAlong {
     fn new(user_keyboard_range: piano_layout::KeyboardRange) -> Self {
         Self {
             user_keyboard_range,
+            latency_offset: Duration::ZERO,
             required_notes: Default::default(),
             user_pressed_recently: Default::default(),
             in_proggres_file_notes: Default::default(),
@@ -316,8 +537,14 @@ impl PlayAlong {
         self.stats.wrong_notes += count_before - self.user_pressed_recently.len();
     }
 
+    fn set_latency_offset(&mut self, latency: Duration) {
+        self.latency_offset = latency;
+    }
+
     fn user_press_key(&mut self, note_id: u8, active: bool) {
-        let timestamp = Instant::now();
+        // Move the press back to the moment the key was actually hit
+        let now = Instant::now();
+        let timestamp = now.checked_sub(self.latency_offset).unwrap_or(now);
 
         if active {
             // Check if note has already been played by a file
@@ -393,3 +620,30 @@ impl PlayAlong {
         self.required_notes.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_clock_spans_wall_time() {
+        let now = Instant::now();
+        // At double speed 10ms of wall time cover 20ms of the song
+        let clock = FrameClock::new(
+            now,
+            Duration::from_millis(10),
+            Duration::ZERO,
+            Duration::from_millis(20),
+        );
+
+        assert_eq!(
+            clock.wall_time(Duration::ZERO),
+            now - Duration::from_millis(10)
+        );
+        assert_eq!(
+            clock.wall_time(Duration::from_millis(10)),
+            now - Duration::from_millis(5)
+        );
+        assert_eq!(clock.wall_time(Duration::from_millis(20)), now);
+    }
+}
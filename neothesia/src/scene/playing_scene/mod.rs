@@ -12,8 +12,8 @@ use self::top_bar::TopBar;
 
 use super::{NuonRenderer, Scene};
 use crate::{
-    NeothesiaEvent, context::Context, render::WaterfallRenderer, scene::MouseToMidiEventState,
-    song::Song, utils::window::WinitEvent,
+    NeothesiaEvent, context::Context, input_manager::InputSource, render::WaterfallRenderer,
+    scene::MouseToMidiEventState, song::Song, utils::window::WinitEvent,
 };
 
 mod keyboard;
@@ -29,6 +29,8 @@ mod toast_manager;
 use toast_manager::ToastManager;
 
 mod animation;
+#[cfg(all(feature = "synth", feature = "transcription"))]
+mod backing_track;
 mod top_bar;
 
 pub struct PlayingScene {
@@ -94,12 +96,13 @@ impl PlayingScene {
             ctx.text_renderer_factory.new_renderer(),
         ));
 
-        let player = MidiPlayer::new(
+        let mut player = MidiPlayer::new(
             ctx.output_manager.connection().clone(),
             song,
             keyboard_layout.range.clone(),
             ctx.config.separate_channels(),
         );
+        player.set_latency_offset(Duration::from_secs_f32(ctx.config.latency_offset()));
         waterfall.update(player.time_without_lead_in());
 
         let quad_renderer_bg = ctx.quad_renderer_factory.new_renderer();
@@ -179,20 +182,21 @@ impl PlayingScene {
     }
 
     #[profiling::function]
-    fn update_midi_player(&mut self, ctx: &Context, delta: Duration) -> f32 {
+    fn update_midi_player(&mut self, ctx: &Context) -> f32 {
         if self.top_bar.is_looper_active() && self.player.time() > self.top_bar.loop_end_timestamp()
         {
             self.player.set_time(self.top_bar.loop_start_timestamp());
             self.keyboard.reset_notes();
         }
 
-        if self.player.play_along().are_required_keys_pressed() {
-            let delta = (delta / 10) * (ctx.config.speed_multiplier() * 10.0) as u32;
-            let midi_events = self.player.update(delta);
-            self.keyboard.file_midi_events(&ctx.config, &midi_events);
+        self.player.set_speed(ctx.config.speed_multiplier());
+        if let Some(file_keys) = self.player.update() {
+            self.keyboard.set_file_keys(&ctx.config, &file_keys);
         }
 
+        // Delay the visuals by the calibrated latency, so notes hit the keyboard when they are heard
         self.player.time_without_lead_in() + ctx.config.animation_offset()
+            - ctx.config.latency_offset()
     }
 
     #[profiling::function]
@@ -219,7 +223,7 @@ impl Scene for PlayingScene {
         self.rewind_controller.update(&mut self.player, ctx, delta);
         self.toast_manager.update(&mut self.text_renderer);
 
-        let time = self.update_midi_player(ctx, delta);
+        let time = self.update_midi_player(ctx);
         self.waterfall.update(time);
         self.guidelines.update(
             &mut self.quad_renderer_bg,
@@ -333,8 +337,14 @@ impl Scene for PlayingScene {
         super::handle_nuon_window_event(&mut self.nuon, event, ctx);
     }
 
-    fn midi_event(&mut self, _ctx: &mut Context, channel: u8, message: &MidiMessage) {
-        self.player.user_midi_event(channel, message);
+    fn midi_event(
+        &mut self,
+        _ctx: &mut Context,
+        source: InputSource,
+        channel: u8,
+        message: &MidiMessage,
+    ) {
+        self.player.user_midi_event(source, channel, message);
         self.keyboard.user_midi_event(message);
     }
 }
@@ -1,3 +1,5 @@
+use std::path::{Path, PathBuf};
+
 use midi_file::MidiTrack;
 
 use crate::context::Context;
@@ -44,24 +46,72 @@ impl SongConfig {
 pub struct Song {
     pub file: midi_file::MidiFile,
     pub config: SongConfig,
+    /// Recording of the song, eg. the one it was transcribed from
+    pub backing_track: Option<PathBuf>,
 }
 
 impl Song {
     pub fn new(file: midi_file::MidiFile) -> Self {
         let config = SongConfig::new(&file.tracks);
-        Self { file, config }
+        Self {
+            file,
+            config,
+            backing_track: None,
+        }
+    }
+
+    /// Loads a MIDI file. With `backing_track`, a recording next to it with the same name plays
+    /// along with the song instead of its tracks.
+    pub fn open(path: &Path, backing_track: bool) -> Result<Self, String> {
+        let song = Self::new(midi_file::MidiFile::new(path)?);
+
+        let Some(recording) = backing_track.then(|| Self::find_recording(path)).flatten() else {
+            return Ok(song);
+        };
+
+        let song = song.with_backing_track(recording);
+        // Without a synth the backing track can't play, the notes stay audible instead
+        #[cfg(feature = "synth")]
+        let song = song.mute_tracks();
+        Ok(song)
+    }
+
+    #[cfg(feature = "transcription")]
+    fn find_recording(path: &Path) -> Option<PathBuf> {
+        neothesia_ai::AUDIO_EXTENSIONS
+            .iter()
+            .map(|ext| path.with_extension(ext))
+            .find(|recording| recording.is_file())
+    }
+
+    /// Recordings can't be decoded without transcription support
+    #[cfg(not(feature = "transcription"))]
+    fn find_recording(_path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn with_backing_track(mut self, path: PathBuf) -> Self {
+        self.backing_track = Some(path);
+        self
+    }
+
+    /// Only the backing track is heard, the notes are still shown and can be played along
+    #[cfg(feature = "synth")]
+    pub fn mute_tracks(mut self) -> Self {
+        for track in self.config.tracks.iter_mut() {
+            track.player = PlayerConfig::Mute;
+        }
+        self
     }
 
     pub fn from_env(ctx: &Context) -> Option<Self> {
         let args: Vec<String> = std::env::args().collect();
-        let midi_file = if args.len() > 1 {
-            midi_file::MidiFile::new(&args[1]).ok()
+        if args.len() > 1 {
+            Self::open(Path::new(&args[1]), ctx.config.backing_tracks()).ok()
         } else if let Some(last) = ctx.config.last_opened_song() {
-            midi_file::MidiFile::new(last).ok()
+            Self::open(last, ctx.config.backing_tracks()).ok()
         } else {
             None
-        };
-
-        Some(Self::new(midi_file?))
+        }
     }
 }
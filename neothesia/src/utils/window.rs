@@ -131,6 +131,8 @@ pub trait WinitEvent {
     }
 
     fn key_pressed(&self, key: Key<&str>) -> bool;
+    /// Any key pressed, repeats left out
+    fn any_key_pressed(&self) -> bool;
     fn key_released(&self, key: Key<&str>) -> bool;
 
     fn character_released(&self) -> Option<&str>;
@@ -191,6 +193,20 @@ impl WinitEvent for WindowEvent {
         }
     }
 
+    fn any_key_pressed(&self) -> bool {
+        matches!(
+            self,
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    repeat: false,
+                    ..
+                },
+                ..
+            }
+        )
+    }
+
     fn key_released(&self, key: Key<&str>) -> bool {
         match self {
             WindowEvent::KeyboardInput {
@@ -1,6 +1,6 @@
 use std::{
     ops::{Range, RangeBounds},
-    rc::Rc,
+    sync::Arc,
 };
 
 const KEY_CIS: u8 = 1;
@@ -24,9 +24,9 @@ impl KeyId {
 pub struct KeyboardRange {
     range: Range<u8>,
 
-    keys: Rc<[KeyId]>,
-    white_keys: Rc<[KeyId]>,
-    black_keys: Rc<[KeyId]>,
+    keys: Arc<[KeyId]>,
+    white_keys: Arc<[KeyId]>,
+    black_keys: Arc<[KeyId]>,
 }
 
 impl KeyboardRange {
@@ -199,9 +199,9 @@ impl Gpu {
         std::mem::replace(&mut self.encoder, new_encoder)
     }
 
-    pub fn submit(&mut self) {
+    pub fn submit(&mut self) -> wgpu::SubmissionIndex {
         let encoder = self.take_encoder();
-        self.queue.submit(Some(encoder.finish()));
+        self.queue.submit(Some(encoder.finish()))
     }
 }
 